@@ -0,0 +1,22 @@
+//! Times the README's `fib(30)` example, which executes about 29 million
+//! instructions. Run it with `cargo bench`.
+
+use rvemu::{Machine, StopReason};
+use std::time::Instant;
+
+fn main() {
+    let mut machine = Machine::builder()
+        .stack(true)
+        .load("./benches/fib")
+        .unwrap();
+    let start = Instant::now();
+    assert_eq!(machine.run(), StopReason::Exited(0));
+    let elapsed = start.elapsed();
+    let steps = machine.steps();
+    println!(
+        "fib(30): {} instructions in {:.3} s ({:.1} MIPS)",
+        steps,
+        elapsed.as_secs_f64(),
+        steps as f64 / elapsed.as_secs_f64() / 1e6
+    );
+}
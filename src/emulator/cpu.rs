@@ -1,80 +1,798 @@
+use super::csr::{self, *};
+use super::error::LoadError;
+use super::float::{Env, Format, Rounding, F32, F64};
+use super::icache::ICache;
 use super::instruction::*;
-use crate::Args;
-use elf_rs::{Elf, ElfFile};
-use std::io::{Read, Write};
+use super::lines::Lines;
+use super::mmu::*;
+use super::pmp::{self, *};
+use super::symbols::Symbols;
+use super::trap::Exception;
+use elf_rs::{Elf, ElfFile, ProgramHeaderWrapper, ProgramType};
+use std::path::Path;
 
-const ALIASES: [&str; 32] = [
+pub(crate) const ALIASES: [&str; 32] = [
     "zero", "ra", "sp", "gp", "tp", "t0", "t1", "t2", "s0", "s1", "a0",
     "a1", "a2", "a3", "a4", "a5", "a6", "a7", "s2", "s3", "s4", "s5",
     "s6", "s7", "s8", "s9", "s10", "s11", "t3", "t4", "t5", "t6",
 ];
 
+pub(crate) const FP_ALIASES: [&str; 32] = [
+    "ft0", "ft1", "ft2", "ft3", "ft4", "ft5", "ft6", "ft7", "fs0", "fs1",
+    "fa0", "fa1", "fa2", "fa3", "fa4", "fa5", "fa6", "fa7", "fs2", "fs3",
+    "fs4", "fs5", "fs6", "fs7", "fs8", "fs9", "fs10", "fs11", "ft8",
+    "ft9", "ft10", "ft11",
+];
+
+/// The width of the integer registers and addresses.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Xlen {
+    #[default]
+    X32,
+    X64,
+}
+
+impl Xlen {
+    /// The number of bits, 32 or 64.
+    pub fn bits(self) -> u32 {
+        match self {
+            Xlen::X32 => 32,
+            Xlen::X64 => 64,
+        }
+    }
+}
+
+/// Which data accesses trigger a [`Watchpoint`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WatchKind {
+    Read,
+    Write,
+    Access,
+}
+
+/// Stops the program after it accesses `len` bytes at `addr`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Watchpoint {
+    pub addr: u64,
+    pub len: u64,
+    pub kind: WatchKind,
+}
+
+/// A load or store, as recorded in a [`Commit`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MemAccess {
+    pub addr: u64,
+    /// 1, 2, 4 or 8 bytes.
+    pub size: u32,
+    /// The value loaded or stored.
+    pub value: u64,
+    pub write: bool,
+}
+
+/// What a retired instruction changed, recorded while commit logging is
+/// on.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Commit {
+    /// The privilege level the instruction ran at.
+    pub privilege: u32,
+    /// The XLEN it ran at, which sets the width of the values below.
+    pub xlen: Xlen,
+    /// The integer register it wrote, unless that was x0, and the new
+    /// value.
+    pub reg: Option<(usize, u64)>,
+    /// The floating-point register it wrote and the new value, NaN-boxed
+    /// for singles.
+    pub freg: Option<(usize, u64)>,
+    /// The CSRs it wrote and their new values.
+    pub csrs: Vec<(u16, u64)>,
+    /// Its loads and stores, in order.
+    pub mem: Vec<MemAccess>,
+}
+
+/// What the loader learned about the program, for setting up its
+/// environment.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct Image {
+    /// The ELF entry point, even if execution starts elsewhere.
+    pub(crate) entry: u64,
+    /// The first address past the loaded segments, where the heap starts.
+    pub(crate) end: u64,
+    /// Where the program headers are in memory, if a segment loads them.
+    pub(crate) phdr: Option<u64>,
+    /// The program header table itself.
+    pub(crate) phdrs: Vec<u8>,
+    pub(crate) phent: u32,
+}
+
+/// A single RV32IMAFDC or RV64IMAFDC hart together with its memory.
 pub struct Cpu {
     memory: Vec<u8>,
-    registers: [u32; 32],
-    pc: u32,
+    mem_base: u64,
+    image: Image,
+    xlen: Xlen,
+    /// Whether loading a program sets `xlen` from its ELF class.
+    xlen_from_elf: bool,
+    /// `x0` to `x31`. On RV32 they hold their values zero-extended.
+    registers: [u64; 32],
+    /// `f0` to `f31`, wide enough for doubles.
+    fregs: [u64; 32],
+    pc: u64,
+    /// Address and size reserved by the last `lr.w` or `lr.d`, if any.
+    reservation: Option<(u64, usize)>,
+    icache: ICache,
+    tlb: Tlb,
+    pmp: Pmp,
+    csrs: [u64; 4096],
+    cycle: u64,
+    instret: u64,
+    /// Current privilege level, `PRV_M`, `PRV_S` or `PRV_U`.
+    privilege: u32,
+    /// Address of the `tohost` symbol, for programs that report their
+    /// exit status through HTIF like riscv-tests do.
+    tohost: Option<u64>,
+    symbols: Symbols,
+    lines: Lines,
+    exit_code: Option<i32>,
+    pub(crate) watchpoints: Vec<Watchpoint>,
+    /// The watchpoint the last instruction triggered, if any, with the
+    /// contents of the memory it accessed before and after.
+    pub(crate) watch_hit: Option<(Watchpoint, u64, u64)>,
+    pub(crate) log_commits: bool,
+    /// What the instruction being executed has changed so far, while
+    /// commit logging is on.
+    pub(crate) commit: Option<Commit>,
+}
+
+/// Configures a [`Cpu`] before it is created.
+pub struct CpuBuilder {
+    mem_size: usize,
+    xlen: Option<Xlen>,
+}
+
+impl CpuBuilder {
+    /// Sets the memory size in KiB (default = 16).
+    pub fn mem_size(mut self, mem_size: usize) -> Self {
+        self.mem_size = mem_size;
+        self
+    }
+
+    /// Fixes the XLEN, instead of taking it from the ELF class of the
+    /// program loaded.
+    pub fn xlen(mut self, xlen: Xlen) -> Self {
+        self.xlen = Some(xlen);
+        self
+    }
+
+    pub fn build(self) -> Cpu {
+        let mut cpu = Cpu::new(self.mem_size);
+        if let Some(xlen) = self.xlen {
+            cpu.set_xlen(xlen);
+            cpu.xlen_from_elf = false;
+        }
+        cpu
+    }
 }
 
 impl Cpu {
+    pub fn builder() -> CpuBuilder {
+        CpuBuilder {
+            mem_size: 16,
+            xlen: None,
+        }
+    }
+
+    /// Creates an RV32 hart in machine mode with `mem_size` KiB of
+    /// memory. Loading an RV64 program makes it an RV64 one.
     pub fn new(mem_size: usize) -> Self {
         Cpu {
             memory: vec![0; mem_size * 1024],
+            mem_base: 0,
+            image: Image::default(),
+            xlen: Xlen::X32,
+            xlen_from_elf: true,
             registers: [0; 32],
+            fregs: [0; 32],
             pc: 0,
+            reservation: None,
+            icache: ICache::new(),
+            tlb: Tlb::new(),
+            pmp: Pmp::new(),
+            csrs: Cpu::initial_csrs(Xlen::X32),
+            cycle: 0,
+            instret: 0,
+            privilege: PRV_M,
+            tohost: None,
+            symbols: Symbols::default(),
+            lines: Lines::default(),
+            exit_code: None,
+            watchpoints: Vec::new(),
+            watch_hit: None,
+            log_commits: false,
+            commit: None,
         }
     }
 
-    pub fn load(&mut self, path: &str) {
-        let mut elf_file =
-            std::fs::File::open(path).expect("open file failed");
-        let mut elf_buf = Vec::<u8>::new();
-        elf_file
-            .read_to_end(&mut elf_buf)
-            .expect("read file failed");
-        let elf = Elf::from_bytes(&elf_buf)
-            .expect("Are you sure this is an ELF file?");
-        match elf.elf_header().machine() {
-            elf_rs::ElfMachine::RISC_V => {
-                for phdr in elf.program_header_iter() {
-                    let e_entry = elf.entry_point();
-                    if phdr.vaddr() <= e_entry
-                        && e_entry < phdr.vaddr() + phdr.memsz()
-                    {
-                        let p_vaddr = phdr.vaddr();
-                        let p_offset = phdr.offset();
-                        self.pc = (e_entry - p_vaddr + p_offset)
-                            .try_into()
-                            .expect(
-                                "couldn't convert u64 entry addr to u32",
-                            );
-                    }
+    fn initial_csrs(xlen: Xlen) -> [u64; 4096] {
+        let mut csrs = [0; 4096];
+        csrs[MISA as usize] = csr::misa(xlen);
+        // programs run without a kernel to turn the FPU on
+        csrs[MSTATUS as usize] = MSTATUS_MPP | MSTATUS_FS_INITIAL;
+        if xlen == Xlen::X64 {
+            csrs[MSTATUS as usize] |= MSTATUS_UXL_64;
+        }
+        csrs
+    }
+
+    /// Makes the hart an RV32 or RV64 one, before it runs anything.
+    fn set_xlen(&mut self, xlen: Xlen) {
+        self.xlen = xlen;
+        self.csrs = Cpu::initial_csrs(xlen);
+        self.pmp = Pmp::new();
+    }
+
+    pub fn xlen(&self) -> Xlen {
+        self.xlen
+    }
+
+    /// Loads the ELF file at `path` into memory and points the pc at its
+    /// entry point.
+    pub fn load(
+        &mut self,
+        path: impl AsRef<Path>,
+    ) -> Result<(), LoadError> {
+        let elf_buf = std::fs::read(path)?;
+        let elf = Cpu::parse_elf(&elf_buf)?;
+        if self.xlen_from_elf {
+            self.set_xlen(Cpu::elf_xlen(&elf));
+        }
+
+        let segments: Vec<_> = elf
+            .program_header_iter()
+            .filter(|phdr| {
+                phdr.ph_type() == ProgramType::LOAD && phdr.memsz() > 0
+            })
+            .collect();
+
+        // Memory starts at the page containing the lowest segment, so
+        // binaries linked at e.g. 0x80000000 don't need gigabytes of RAM.
+        self.mem_base = segments
+            .iter()
+            .map(|phdr| phdr.vaddr() & !0xfff)
+            .min()
+            .unwrap_or(0);
+        self.image.end = segments
+            .iter()
+            .map(|phdr| phdr.vaddr() + phdr.memsz())
+            .max()
+            .unwrap_or(0);
+
+        for phdr in segments.iter() {
+            let start = self.mem_index(phdr.vaddr());
+            let filesz = phdr.filesz() as usize;
+            let memsz = phdr.memsz() as usize;
+            if start + memsz > self.memory.len() {
+                return Err(LoadError::SegmentOutOfMemory {
+                    vaddr: phdr.vaddr(),
+                    memsz: phdr.memsz(),
+                    mem_size: self.memory.len(),
+                });
+            }
+            self.memory[start..start + filesz]
+                .copy_from_slice(phdr.content());
+            // zero-fill whatever isn't backed by the file (.bss)
+            self.memory[start + filesz..start + memsz].fill(0);
+        }
+
+        self.pc = elf.entry_point();
+        if self.wrap(self.pc) != self.pc {
+            return Err(LoadError::EntryOutOfRange(self.pc));
+        }
+        self.image.entry = self.pc;
+        self.load_phdrs(&elf, &segments);
+        self.icache.flush();
+        self.symbols = Symbols::from_elf(&elf);
+        self.tohost = self.symbols.find("tohost");
+        self.lines = Lines::from_elf(&elf);
+        Ok(())
+    }
+
+    /// Parses `buf` as an ELF file, checking that it's a RISC-V one.
+    pub(crate) fn parse_elf(buf: &[u8]) -> Result<Elf<'_>, LoadError> {
+        let elf = Elf::from_bytes(buf).map_err(|_| LoadError::NotElf)?;
+        let machine = elf.elf_header().machine();
+        if machine != elf_rs::ElfMachine::RISC_V {
+            return Err(LoadError::UnsupportedArchitecture(format!(
+                "{:?}",
+                machine
+            )));
+        }
+        Ok(elf)
+    }
+
+    /// The XLEN an ELF file's class calls for: RV64 for ELF64.
+    pub(crate) fn elf_xlen(elf: &Elf) -> Xlen {
+        match elf {
+            Elf::Elf64(_) => Xlen::X64,
+            Elf::Elf32(_) => Xlen::X32,
+        }
+    }
+
+    /// Keeps a copy of the program header table, and notes where it is in
+    /// memory if one of the loaded `segments` covers it.
+    fn load_phdrs(
+        &mut self,
+        elf: &Elf,
+        segments: &[ProgramHeaderWrapper],
+    ) {
+        let header = elf.elf_header();
+        let offset = header.program_header_offset();
+        let phent = header.program_header_entry_size() as u64;
+        let len = phent * header.program_header_entry_num() as u64;
+        self.image.phent = phent as u32;
+        self.image.phdrs = elf
+            .content()
+            .get(offset as usize..(offset + len) as usize)
+            .unwrap_or_default()
+            .to_vec();
+        self.image.phdr = segments
+            .iter()
+            .find(|phdr| {
+                phdr.offset() <= offset
+                    && offset + len <= phdr.offset() + phdr.filesz()
+            })
+            .map(|phdr| phdr.vaddr() + offset - phdr.offset());
+    }
+
+    /// Translates a guest address into an index into `memory`.
+    fn mem_index(&self, addr: u64) -> usize {
+        addr.wrapping_sub(self.mem_base) as usize
+    }
+
+    /// Reads `size` bytes (1, 2, 4 or 8) of little-endian memory at
+    /// `addr`, or `None` if they aren't all backed by memory.
+    fn read_mem(&self, addr: u64, size: usize) -> Option<u64> {
+        let bytes = self.read_memory(addr, size)?;
+        Some(
+            bytes
+                .iter()
+                .rev()
+                .fold(0, |acc, byte| acc << 8 | *byte as u64),
+        )
+    }
+
+    /// Writes the low `size` bytes (1, 2, 4 or 8) of `value` to `addr`.
+    /// Returns `None` if they aren't all backed by memory.
+    fn write_mem(
+        &mut self,
+        addr: u64,
+        value: u64,
+        size: usize,
+    ) -> Option<()> {
+        let index = self.mem_index(addr);
+        self.memory
+            .get_mut(index..index.checked_add(size)?)?
+            .copy_from_slice(&value.to_le_bytes()[..size]);
+        self.icache.invalidate(addr, size);
+        if Some(addr) == self.tohost && value & 1 == 1 {
+            // HTIF convention: the guest writes (exit code << 1) | 1
+            self.exit_code = Some((value >> 1) as i32);
+        }
+        // any store overlapping the reserved bytes breaks the LR/SC pair
+        if let Some((reserved, len)) = self.reservation {
+            if addr < reserved.wrapping_add(len as u64)
+                && reserved < addr.wrapping_add(size as u64)
+            {
+                self.reservation = None;
+            }
+        }
+        Some(())
+    }
+
+    /// Translates the virtual address `addr` into a physical one for an
+    /// `access` at the effective privilege level. Addresses are physical
+    /// in machine mode and while `satp` is bare.
+    fn translate(
+        &mut self,
+        addr: u64,
+        access: Access,
+    ) -> Result<u64, Exception> {
+        if self.csrs[SATP as usize] & SATP_SV32 == 0 {
+            return Ok(addr);
+        }
+        let privilege = self.access_privilege(access);
+        if privilege == PRV_M {
+            return Ok(addr);
+        }
+        let vpn = addr >> 12;
+        let entry = match self.tlb.get(vpn) {
+            // a store through a clean page walks again to set D
+            Some(entry)
+                if access != Access::Store || entry.pte & PTE_D != 0 =>
+            {
+                if !self.permits(entry.pte, privilege, access) {
+                    return Err(access.page_fault(addr));
                 }
+                entry
             }
             _ => {
-                panic!(
-                    "unsupported architecture: {:#?}",
-                    elf.elf_header().machine()
-                );
+                let entry = self.walk(addr, privilege, access)?;
+                self.tlb.insert(entry);
+                entry
+            }
+        };
+        Ok(entry.ppn << 12 | addr & 0xfff)
+    }
+
+    /// The privilege level an `access` is made at: machine-mode loads
+    /// and stores use the one in MPP while MPRV is set.
+    fn access_privilege(&self, access: Access) -> u32 {
+        let mstatus = self.csrs[MSTATUS as usize];
+        match access {
+            Access::Load | Access::Store
+                if self.privilege == PRV_M
+                    && mstatus & MSTATUS_MPRV != 0 =>
+            {
+                ((mstatus & MSTATUS_MPP) >> 11) as u32
+            }
+            _ => self.privilege,
+        }
+    }
+
+    /// Walks the two-level Sv32 page table for `addr`, setting the A and,
+    /// for stores, D bits of the leaf PTE.
+    fn walk(
+        &mut self,
+        addr: u64,
+        privilege: u32,
+        access: Access,
+    ) -> Result<TlbEntry, Exception> {
+        let fault = access.page_fault(addr);
+        let mut table = (self.csrs[SATP as usize] & SATP_PPN) << 12;
+        let mut level = 1;
+        loop {
+            let vpn = addr >> (12 + 10 * level) & 0x3ff;
+            let pte_addr = table + vpn * 4;
+            // PMP checks page table accesses as supervisor-mode ones
+            if !self.pmp.allows(pte_addr, 4, Access::Load, PRV_S) {
+                return Err(access.access_fault(addr));
+            }
+            let pte = self
+                .read_mem(pte_addr, 4)
+                .ok_or(access.access_fault(addr))?;
+            // writable pages must be readable
+            if pte & PTE_V == 0 || (pte & PTE_R == 0 && pte & PTE_W != 0) {
+                return Err(fault);
+            }
+            let ppn = pte >> 10;
+            if pte & (PTE_R | PTE_X) == 0 {
+                if level == 0 {
+                    return Err(fault);
+                }
+                level -= 1;
+                table = ppn << 12;
+                continue;
+            }
+            // megapages must be aligned to 4 MiB
+            if !self.permits(pte, privilege, access)
+                || (level == 1 && ppn & 0x3ff != 0)
+            {
+                return Err(fault);
+            }
+            let mut new = pte | PTE_A;
+            if access == Access::Store {
+                new |= PTE_D;
+            }
+            if new != pte {
+                if !self.pmp.allows(pte_addr, 4, Access::Store, PRV_S) {
+                    return Err(access.access_fault(addr));
+                }
+                self.write_mem(pte_addr, new, 4)
+                    .ok_or(access.access_fault(addr))?;
+            }
+            return Ok(TlbEntry {
+                vpn: addr >> 12,
+                ppn: match level {
+                    1 => ppn | addr >> 12 & 0x3ff,
+                    _ => ppn,
+                },
+                pte: new,
+            });
+        }
+    }
+
+    /// Whether the leaf PTE `pte` allows an `access` at `privilege`.
+    fn permits(&self, pte: u64, privilege: u32, access: Access) -> bool {
+        let mstatus = self.csrs[MSTATUS as usize];
+        let allowed = match access {
+            Access::Fetch => pte & PTE_X != 0,
+            Access::Load => {
+                pte & PTE_R != 0
+                    || (pte & PTE_X != 0 && mstatus & MSTATUS_MXR != 0)
+            }
+            Access::Store => pte & PTE_W != 0,
+        };
+        // supervisor mode only reaches user pages' data, with SUM set
+        let user = pte & PTE_U != 0;
+        allowed
+            && match privilege {
+                PRV_U => user,
+                _ => {
+                    !user
+                        || (access != Access::Fetch
+                            && mstatus & MSTATUS_SUM != 0)
+                }
+            }
+    }
+
+    /// A data load: naturally aligned, mapped and backed by memory.
+    fn read_data(
+        &mut self,
+        addr: u64,
+        size: usize,
+    ) -> Result<u64, Exception> {
+        if addr & (size as u64 - 1) != 0 {
+            return Err(Exception::LoadAddressMisaligned(addr));
+        }
+        let paddr = self.translate(addr, Access::Load)?;
+        self.load_physical(addr, paddr, size)
+    }
+
+    /// A data store: naturally aligned, mapped and backed by memory.
+    fn write_data(
+        &mut self,
+        addr: u64,
+        value: u64,
+        size: usize,
+    ) -> Result<(), Exception> {
+        if addr & (size as u64 - 1) != 0 {
+            return Err(Exception::StoreAddressMisaligned(addr));
+        }
+        let paddr = self.translate(addr, Access::Store)?;
+        self.store_physical(addr, paddr, value, size)
+    }
+
+    /// Loads from the physical address `paddr` that the virtual address
+    /// `addr` translated to, if PMP allows it.
+    fn load_physical(
+        &mut self,
+        addr: u64,
+        paddr: u64,
+        size: usize,
+    ) -> Result<u64, Exception> {
+        let privilege = self.access_privilege(Access::Load);
+        if !self.pmp.allows(paddr, size, Access::Load, privilege) {
+            return Err(Exception::LoadAccessFault(addr));
+        }
+        let value = self
+            .read_mem(paddr, size)
+            .ok_or(Exception::LoadAccessFault(addr))?;
+        self.watch(addr, paddr, size, WatchKind::Read, value);
+        self.log_access(addr, size, value, false);
+        Ok(value)
+    }
+
+    /// Stores to the physical address `paddr` that the virtual address
+    /// `addr` translated to, if PMP allows it.
+    fn store_physical(
+        &mut self,
+        addr: u64,
+        paddr: u64,
+        value: u64,
+        size: usize,
+    ) -> Result<(), Exception> {
+        let privilege = self.access_privilege(Access::Store);
+        if !self.pmp.allows(paddr, size, Access::Store, privilege) {
+            return Err(Exception::StoreAccessFault(addr));
+        }
+        // the old contents are only needed to report a watchpoint
+        let old = match self.watchpoints.is_empty() {
+            true => None,
+            false => self.read_mem(paddr, size),
+        };
+        self.write_mem(paddr, value, size)
+            .ok_or(Exception::StoreAccessFault(addr))?;
+        if let Some(old) = old {
+            self.watch(addr, paddr, size, WatchKind::Write, old);
+        }
+        self.log_access(addr, size, value, true);
+        Ok(())
+    }
+
+    fn log_access(
+        &mut self,
+        addr: u64,
+        size: usize,
+        value: u64,
+        write: bool,
+    ) {
+        if let Some(commit) = &mut self.commit {
+            commit.mem.push(MemAccess {
+                addr,
+                size: size as u32,
+                value,
+                write,
+            });
+        }
+    }
+
+    /// Records the first watchpoint a data access to `addr`, at the
+    /// physical address `paddr`, triggers, along with what the accessed
+    /// memory held before it.
+    fn watch(
+        &mut self,
+        addr: u64,
+        paddr: u64,
+        size: usize,
+        access: WatchKind,
+        old: u64,
+    ) {
+        if self.watch_hit.is_some() {
+            return;
+        }
+        let hit = self.watchpoints.iter().copied().find(|w| {
+            (w.kind == access || w.kind == WatchKind::Access)
+                && addr < w.addr.wrapping_add(w.len)
+                && w.addr < addr.wrapping_add(size as u64)
+        });
+        if let Some(w) = hit {
+            let new = self.read_mem(paddr, size).unwrap_or(old);
+            self.watch_hit = Some((w, old, new));
+        }
+    }
+
+    pub fn pc(&self) -> u64 {
+        self.pc
+    }
+
+    /// Sets the pc, truncated to XLEN bits.
+    pub fn set_pc(&mut self, pc: u64) {
+        self.pc = self.wrap(pc);
+    }
+
+    /// The number of the register called `name`: `x0` to `x31`, or an
+    /// ABI name such as `sp`, `a0` or `fp`.
+    pub fn register_number(name: &str) -> Option<usize> {
+        if name == "fp" {
+            return Some(8);
+        }
+        match name.strip_prefix('x').map(str::parse::<usize>) {
+            Some(Ok(n)) => (n < 32).then_some(n),
+            _ => ALIASES.iter().position(|&alias| alias == name),
+        }
+    }
+
+    /// Returns the value of register `x<n>`, zero-extended on RV32.
+    pub fn register(&self, n: usize) -> u64 {
+        self.registers[n]
+    }
+
+    /// Sets register `x<n>` to `value` truncated to XLEN bits. Writes to
+    /// `x0` are ignored.
+    pub fn set_register(&mut self, n: usize, value: u64) {
+        if n != 0 {
+            self.registers[n] = self.wrap(value);
+        }
+    }
+
+    /// Truncates `value` to XLEN bits, the way RV32 registers hold it.
+    fn wrap(&self, value: u64) -> u64 {
+        match self.xlen {
+            Xlen::X32 => value as u32 as u64,
+            Xlen::X64 => value,
+        }
+    }
+
+    /// Rotates the XLEN-bit `value` right by `shamt` bits.
+    fn rotate_right(&self, value: u64, shamt: u32) -> u64 {
+        match self.xlen {
+            Xlen::X32 => (value as u32).rotate_right(shamt) as u64,
+            Xlen::X64 => value.rotate_right(shamt),
+        }
+    }
+
+    /// `value` as a signed XLEN-bit integer.
+    pub(crate) fn signed(&self, value: u64) -> i64 {
+        match self.xlen {
+            Xlen::X32 => value as i32 as i64,
+            Xlen::X64 => value as i64,
+        }
+    }
+
+    /// The number of the floating-point register called `name`: `f0` to
+    /// `f31`, or an ABI name such as `fa0` or `ft11`.
+    pub fn fp_register_number(name: &str) -> Option<usize> {
+        match name.strip_prefix('f').map(str::parse::<usize>) {
+            Some(Ok(n)) => (n < 32).then_some(n),
+            _ => FP_ALIASES.iter().position(|&alias| alias == name),
+        }
+    }
+
+    /// Returns the raw 64 bits of register `f<n>`, with singles NaN-boxed.
+    pub fn fp_register(&self, n: usize) -> u64 {
+        self.fregs[n]
+    }
+
+    /// The lowest address backed by memory.
+    pub fn mem_base(&self) -> u64 {
+        self.mem_base
+    }
+
+    /// The amount of memory in bytes.
+    pub fn mem_size(&self) -> usize {
+        self.memory.len()
+    }
+
+    /// Returns `len` bytes of memory starting at `addr`, or `None` if
+    /// they aren't all backed by memory.
+    pub fn read_memory(&self, addr: u64, len: usize) -> Option<&[u8]> {
+        let index = self.mem_index(addr);
+        self.memory.get(index..index.checked_add(len)?)
+    }
+
+    /// Copies `bytes` into memory at `addr`. Returns `None`, leaving
+    /// memory untouched, if they don't all fit.
+    pub fn write_memory(&mut self, addr: u64, bytes: &[u8]) -> Option<()> {
+        let index = self.mem_index(addr);
+        self.memory
+            .get_mut(index..index.checked_add(bytes.len())?)?
+            .copy_from_slice(bytes);
+        self.icache.invalidate(addr, bytes.len());
+        self.reservation = None;
+        Some(())
+    }
+
+    /// The symbols of the loaded program, empty if it was stripped.
+    pub fn symbols(&self) -> &Symbols {
+        &self.symbols
+    }
+
+    /// The source lines of the loaded program, empty if it was built
+    /// without debug information.
+    pub fn lines(&self) -> &Lines {
+        &self.lines
+    }
+
+    /// Where `addr` is in the loaded program, as `<function+offset>` and
+    /// `file:line` when those are known, or an empty string.
+    pub fn location(&self, addr: u64) -> String {
+        let mut location = match self.symbols.lookup(addr) {
+            Some((sym, 0)) => format!("<{}>", sym.name),
+            Some((sym, off)) => format!("<{}+{:#x}>", sym.name, off),
+            None => String::new(),
+        };
+        if let Some((file, line)) = self.lines.lookup(addr) {
+            if !location.is_empty() {
+                location.push(' ');
             }
+            location += &format!("{file}:{line}");
         }
-        let raw_data: Vec<u8> = elf_buf.into_iter().collect();
-        self.memory[..raw_data.len()].copy_from_slice(&raw_data);
+        location
+    }
+
+    pub(crate) fn image(&self) -> &Image {
+        &self.image
+    }
+
+    /// The exit code the program wrote to `tohost`, if it did.
+    pub(crate) fn exit_code(&self) -> Option<i32> {
+        self.exit_code
     }
 
     pub fn print_registers(&self, aliases: bool) {
         let mut reg_name;
-        println!(" pc: 0x{:0>8x}", self.pc);
+        let width = self.xlen.bits() as usize / 4;
+        println!(" pc: 0x{:0>width$x}", self.pc);
         let mut strbuilder = String::new();
         for (i, alias) in ALIASES.iter().enumerate() {
             if aliases {
                 strbuilder += &*format!(
-                    "{:>4}: 0x{:0>8x}  ",
+                    "{:>4}: 0x{:0>width$x}  ",
                     *alias, self.registers[i]
                 );
             } else {
                 reg_name = String::from("x") + &i.to_string();
                 strbuilder += &*format!(
-                    "{:>3}: 0x{:0>8x}    ",
+                    "{:>3}: 0x{:0>width$x}    ",
                     reg_name, self.registers[i]
                 );
             }
@@ -82,24 +800,76 @@ impl Cpu {
                 strbuilder += "\n";
             }
         }
+        // only programs that have used the FPU get to see it
+        if self.csrs[MSTATUS as usize] & MSTATUS_FS == MSTATUS_FS {
+            for (i, alias) in FP_ALIASES.iter().enumerate() {
+                if aliases {
+                    strbuilder += &*format!(
+                        "{:>4}: 0x{:0>16x}  ",
+                        *alias, self.fregs[i]
+                    );
+                } else {
+                    reg_name = String::from("f") + &i.to_string();
+                    strbuilder += &*format!(
+                        "{:>3}: 0x{:0>16x}    ",
+                        reg_name, self.fregs[i]
+                    );
+                }
+                if (i + 1) % 4 == 0 {
+                    strbuilder += "\n";
+                }
+            }
+        }
         println!("{}", strbuilder);
     }
 
-    fn fetch(&self) -> u32 {
-        let index = self.pc as usize;
-        self.memory[index] as u32
-            | ((self.memory[index + 1]) as u32) << 8
-            | ((self.memory[index + 2]) as u32) << 16
-            | ((self.memory[index + 3]) as u32) << 24
+    /// Fetches the instruction at the pc, which translated to the
+    /// physical address `addr`.
+    fn fetch(&mut self, addr: u64) -> Result<u32, Exception> {
+        if self.pc & 1 != 0 {
+            return Err(Exception::InstructionAddressMisaligned(self.pc));
+        }
+        let half_word = self.fetch_half(self.pc, addr)?;
+        if half_word & 0b11 != 0b11 {
+            // compressed instructions are only 16 bits long
+            return Ok(half_word as u32);
+        }
+        // the upper half may be on the next page
+        let next = self.wrap(self.pc.wrapping_add(2));
+        let next_addr = match next & 0xfff {
+            0 => self.translate(next, Access::Fetch)?,
+            _ => addr.wrapping_add(2),
+        };
+        let upper = self.fetch_half(next, next_addr)?;
+        Ok((upper << 16 | half_word) as u32)
+    }
+
+    /// Reads half of an instruction from the physical address `addr`,
+    /// which `vaddr` translated to, if PMP allows it.
+    fn fetch_half(&self, vaddr: u64, addr: u64) -> Result<u64, Exception> {
+        let fault = Exception::InstructionAccessFault(vaddr);
+        if !self.pmp.allows(addr, 2, Access::Fetch, self.privilege) {
+            return Err(fault);
+        }
+        self.read_mem(addr, 2).ok_or(fault)
     }
 
-    fn decode(&self, inst: u32) -> Instruction {
+    /// Decodes the instruction whose encoding is `inst` for an `xlen`
+    /// hart. Encodings that only exist on RV64 decode as `unimp` on RV32,
+    /// and the other way round.
+    pub fn decode(inst: u32, xlen: Xlen) -> Instruction {
+        if inst & 0b11 != 0b11 {
+            return Cpu::decode_compressed(inst, xlen);
+        }
         let mut instruction = Instruction::new();
+        instruction.raw = inst;
         let opcode = inst & 0b1111111;
         instruction.opcode = opcode;
         match opcode {
-            // R Type
-            0b0110011 => {
+            // R Type (the A extension, OP-32 and OP-FP share its layout,
+            // and the fused multiply-adds keep rs3 in the top of funct7)
+            0b0110011 | 0b0111011 | 0b0101111 | 0b1010011 | 0b1000011
+            | 0b1000111 | 0b1001011 | 0b1001111 => {
                 let rd = ((inst >> 7) & 0b11111) as usize;
                 let funct3 = (inst >> 12) & 0b111;
                 let rs1 = ((inst >> 15) & 0b11111) as usize;
@@ -115,8 +885,9 @@ impl Cpu {
                 instruction.type_name = InstTypeName::R;
             }
 
-            // I Type
-            0b0010011 | 0b0000011 | 0b1100111 | 0b1110011 => {
+            // I Type (with OP-IMM-32 and the floating-point loads)
+            0b0010011 | 0b0011011 | 0b0000011 | 0b0000111 | 0b1100111
+            | 0b1110011 => {
                 let rd = ((inst >> 7) & 0b11111) as usize;
                 let funct3 = (inst >> 12) & 0b111;
                 let rs1 = ((inst >> 15) & 0b11111) as usize;
@@ -131,8 +902,8 @@ impl Cpu {
                 instruction.type_name = InstTypeName::I;
             }
 
-            // S Type
-            0b0100011 => {
+            // S Type (with the floating-point stores)
+            0b0100011 | 0b0100111 => {
                 let imm4_0 = (inst >> 7) & 0b11111;
                 let imm11_5 = (inst >> 25) & 0b1111111;
                 let imm = ((imm11_5 << 5) | imm4_0) as i32 as u32;
@@ -160,7 +931,7 @@ impl Cpu {
                     | (imm11 << 11)
                     | (imm10_5 << 5)
                     | (imm4_1 << 1);
-                let imm = Cpu::sign_extend(imm, 12);
+                let imm = Cpu::sign_extend(imm, 13);
 
                 let funct3 = (inst >> 12) & 0b111;
                 let rs1 = ((inst >> 15) & 0b11111) as usize;
@@ -186,7 +957,7 @@ impl Cpu {
                     | (imm19_12 << 12)
                     | (imm11 << 11)
                     | (imm10_1 << 1);
-                let imm = Cpu::sign_extend(imm, 12);
+                let imm = Cpu::sign_extend(imm, 21);
                 instruction.type_data = InstTypeData::J { rd, imm };
                 instruction.type_name = InstTypeName::J;
             }
@@ -210,14 +981,346 @@ impl Cpu {
                 instruction.type_name = InstTypeName::Unimp;
             }
         }
-        if inst == 0 || inst == 0xc0001073 {
+        if inst == 0
+            || inst == 0xc0001073
+            || xlen == Xlen::X32 && Cpu::rv64_only(inst)
+            || xlen == Xlen::X64 && Cpu::rv32_only(inst)
+        {
             instruction.type_data = InstTypeData::Unimp;
             instruction.type_name = InstTypeName::Unimp;
         }
         instruction
     }
 
-    fn execute(&mut self, inst: &mut Instruction) {
+    /// Whether `inst` is one of the encodings RV64 adds: the word
+    /// instructions, `ld`, `lwu` and `sd`, shift amounts of 32 and up,
+    /// the doubleword atomics, and conversions and moves between
+    /// floating-point and 64-bit integer registers.
+    fn rv64_only(inst: u32) -> bool {
+        let funct3 = (inst >> 12) & 0b111;
+        let funct7 = inst >> 25;
+        let rs2 = (inst >> 20) & 0b11111;
+        match inst & 0b1111111 {
+            0b0011011 | 0b0111011 => true,
+            0b0000011 => matches!(funct3, 0b011 | 0b110),
+            0b0100011 => funct3 == 0b011,
+            // shamt[5]
+            0b0010011 => funct3 & 0b011 == 0b001 && funct7 & 1 != 0,
+            0b0101111 => funct3 == 0b011,
+            0b1010011 => match funct7 {
+                0b1100000 | 0b1100001 | 0b1101000 | 0b1101001 => rs2 >= 2,
+                0b1110001 | 0b1111001 => funct3 == 0b000,
+                _ => false,
+            },
+            _ => false,
+        }
+    }
+
+    /// Whether `inst` is one of the encodings only RV32 has: `zext.h` and
+    /// `rev8`, which RV64 encodes differently.
+    fn rv32_only(inst: u32) -> bool {
+        match inst & 0b1111111 {
+            0b0110011 => inst & 0xfff0_7000 == 0x0800_4000,
+            0b0010011 => inst & 0xfff0_7000 == 0x6980_5000,
+            _ => false,
+        }
+    }
+
+    /// Expands a 16-bit RVC instruction into its 32-bit equivalent.
+    fn decode_compressed(inst: u32, xlen: Xlen) -> Instruction {
+        let mut instruction = Instruction::new();
+        instruction.raw = inst;
+        instruction.size = 2;
+        let bit = |n: u32| (inst >> n) & 1;
+        let bits =
+            |hi: u32, lo: u32| (inst >> lo) & ((1 << (hi - lo + 1)) - 1);
+        // full and 3-bit (x8-x15) register fields
+        let rd = bits(11, 7) as usize;
+        let rs2 = bits(6, 2) as usize;
+        let rd_ = 8 + bits(4, 2) as usize;
+        let rs1_ = 8 + bits(9, 7) as usize;
+        let imm6 = Cpu::sign_extend(bit(12) << 5 | bits(6, 2), 6);
+        let funct3 = bits(15, 13);
+        let rv64 = xlen == Xlen::X64;
+        // shift amounts of 32 and up only exist on RV64
+        let shamt = bit(12) << 5 | bits(6, 2);
+        let shamt_ok = rv64 || bit(12) == 0;
+
+        let i_type = |opcode, rd, funct3, rs1, imm| {
+            (
+                opcode,
+                InstTypeName::I,
+                InstTypeData::I {
+                    rd,
+                    funct3,
+                    rs1,
+                    imm,
+                },
+            )
+        };
+        let r_type = |opcode, rd, funct3, rs1, rs2, funct7| {
+            (
+                opcode,
+                InstTypeName::R,
+                InstTypeData::R {
+                    rd,
+                    funct3,
+                    rs1,
+                    rs2,
+                    funct7,
+                },
+            )
+        };
+        let s_type = |opcode, funct3, imm, rs1, rs2| {
+            (
+                opcode,
+                InstTypeName::S,
+                InstTypeData::S {
+                    imm,
+                    funct3,
+                    rs1,
+                    rs2,
+                },
+            )
+        };
+        let b_type = |funct3, rs1| {
+            let imm = bit(12) << 8
+                | bits(6, 5) << 6
+                | bit(2) << 5
+                | bits(11, 10) << 3
+                | bits(4, 3) << 1;
+            (
+                0b1100011,
+                InstTypeName::B,
+                InstTypeData::B {
+                    imm: Cpu::sign_extend(imm, 9),
+                    funct3,
+                    rs1,
+                    rs2: 0,
+                },
+            )
+        };
+        let j_type = |rd| {
+            let imm = bit(12) << 11
+                | bit(8) << 10
+                | bits(10, 9) << 8
+                | bit(6) << 7
+                | bit(7) << 6
+                | bit(2) << 5
+                | bit(11) << 4
+                | bits(5, 3) << 1;
+            (
+                0b1101111,
+                InstTypeName::J,
+                InstTypeData::J {
+                    rd,
+                    imm: Cpu::sign_extend(imm, 12),
+                },
+            )
+        };
+
+        let expanded = match (inst & 0b11, funct3) {
+            // C.ADDI4SPN
+            (0b00, 0b000) if bits(12, 5) != 0 => {
+                let imm = bits(12, 11) << 4
+                    | bits(10, 7) << 6
+                    | bit(6) << 2
+                    | bit(5) << 3;
+                Some(i_type(0b0010011, rd_, 0b000, 2, imm))
+            }
+            // C.FLD
+            (0b00, 0b001) => {
+                let imm = bits(12, 10) << 3 | bits(6, 5) << 6;
+                Some(i_type(0b0000111, rd_, 0b011, rs1_, imm))
+            }
+            // C.LD
+            (0b00, 0b011) if rv64 => {
+                let imm = bits(12, 10) << 3 | bits(6, 5) << 6;
+                Some(i_type(0b0000011, rd_, 0b011, rs1_, imm))
+            }
+            // C.LW, C.FLW
+            (0b00, 0b010 | 0b011) => {
+                let imm = bits(12, 10) << 3 | bit(6) << 2 | bit(5) << 6;
+                let opcode = match funct3 {
+                    0b010 => 0b0000011,
+                    _ => 0b0000111,
+                };
+                Some(i_type(opcode, rd_, 0b010, rs1_, imm))
+            }
+            // C.FSD
+            (0b00, 0b101) => {
+                let imm = bits(12, 10) << 3 | bits(6, 5) << 6;
+                Some(s_type(0b0100111, 0b011, imm, rs1_, rd_))
+            }
+            // C.SD
+            (0b00, 0b111) if rv64 => {
+                let imm = bits(12, 10) << 3 | bits(6, 5) << 6;
+                Some(s_type(0b0100011, 0b011, imm, rs1_, rd_))
+            }
+            // C.SW, C.FSW
+            (0b00, 0b110 | 0b111) => {
+                let imm = bits(12, 10) << 3 | bit(6) << 2 | bit(5) << 6;
+                let opcode = match funct3 {
+                    0b110 => 0b0100011,
+                    _ => 0b0100111,
+                };
+                Some(s_type(opcode, 0b010, imm, rs1_, rd_))
+            }
+            // C.NOP, C.ADDI
+            (0b01, 0b000) => Some(i_type(0b0010011, rd, 0b000, rd, imm6)),
+            // C.ADDIW
+            (0b01, 0b001) if rv64 && rd != 0 => {
+                Some(i_type(0b0011011, rd, 0b000, rd, imm6))
+            }
+            (0b01, 0b001) if rv64 => None,
+            // C.JAL
+            (0b01, 0b001) => Some(j_type(1)),
+            // C.LI
+            (0b01, 0b010) => Some(i_type(0b0010011, rd, 0b000, 0, imm6)),
+            // C.ADDI16SP
+            (0b01, 0b011) if rd == 2 && imm6 != 0 => {
+                let imm = bit(12) << 9
+                    | bits(4, 3) << 7
+                    | bit(5) << 6
+                    | bit(2) << 5
+                    | bit(6) << 4;
+                Some(i_type(
+                    0b0010011,
+                    2,
+                    0b000,
+                    2,
+                    Cpu::sign_extend(imm, 10),
+                ))
+            }
+            // C.LUI
+            (0b01, 0b011) if imm6 != 0 => Some((
+                0b0110111,
+                InstTypeName::U,
+                InstTypeData::U {
+                    rd,
+                    imm: imm6 & 0xfffff,
+                },
+            )),
+            (0b01, 0b100) => match bits(11, 10) {
+                // C.SRLI, C.SRAI
+                0b00 if shamt_ok => {
+                    Some(i_type(0b0010011, rs1_, 0b101, rs1_, shamt))
+                }
+                0b01 if shamt_ok => Some(i_type(
+                    0b0010011,
+                    rs1_,
+                    0b101,
+                    rs1_,
+                    0b0100000 << 5 | shamt,
+                )),
+                // C.ANDI
+                0b10 => Some(i_type(0b0010011, rs1_, 0b111, rs1_, imm6)),
+                // C.SUB, C.XOR, C.OR, C.AND
+                0b11 if bit(12) == 0 => {
+                    let (op, rs2_) = (0b0110011, rd_);
+                    Some(match bits(6, 5) {
+                        0b00 => {
+                            r_type(op, rs1_, 0b000, rs1_, rs2_, 0b0100000)
+                        }
+                        0b01 => r_type(op, rs1_, 0b100, rs1_, rs2_, 0),
+                        0b10 => r_type(op, rs1_, 0b110, rs1_, rs2_, 0),
+                        _ => r_type(op, rs1_, 0b111, rs1_, rs2_, 0),
+                    })
+                }
+                // C.SUBW, C.ADDW
+                0b11 if rv64 => {
+                    let (op, rs2_) = (0b0111011, rd_);
+                    match bits(6, 5) {
+                        0b00 => Some(r_type(
+                            op, rs1_, 0b000, rs1_, rs2_, 0b0100000,
+                        )),
+                        0b01 => {
+                            Some(r_type(op, rs1_, 0b000, rs1_, rs2_, 0))
+                        }
+                        _ => None,
+                    }
+                }
+                _ => None,
+            },
+            // C.J
+            (0b01, 0b101) => Some(j_type(0)),
+            // C.BEQZ, C.BNEZ
+            (0b01, 0b110) => Some(b_type(0b000, rs1_)),
+            (0b01, 0b111) => Some(b_type(0b001, rs1_)),
+            // C.SLLI
+            (0b10, 0b000) if shamt_ok => {
+                Some(i_type(0b0010011, rd, 0b001, rd, shamt))
+            }
+            // C.FLDSP
+            (0b10, 0b001) => {
+                let imm = bit(12) << 5 | bits(6, 5) << 3 | bits(4, 2) << 6;
+                Some(i_type(0b0000111, rd, 0b011, 2, imm))
+            }
+            // C.LWSP
+            (0b10, 0b010) if rd != 0 => {
+                let imm = bit(12) << 5 | bits(6, 4) << 2 | bits(3, 2) << 6;
+                Some(i_type(0b0000011, rd, 0b010, 2, imm))
+            }
+            // C.LDSP
+            (0b10, 0b011) if rv64 && rd != 0 => {
+                let imm = bit(12) << 5 | bits(6, 5) << 3 | bits(4, 2) << 6;
+                Some(i_type(0b0000011, rd, 0b011, 2, imm))
+            }
+            (0b10, 0b011) if rv64 => None,
+            // C.FLWSP
+            (0b10, 0b011) => {
+                let imm = bit(12) << 5 | bits(6, 4) << 2 | bits(3, 2) << 6;
+                Some(i_type(0b0000111, rd, 0b010, 2, imm))
+            }
+            (0b10, 0b100) => match (bit(12), rd, rs2) {
+                // reserved
+                (0, 0, 0) => None,
+                // C.JR
+                (0, 1.., 0) => Some(i_type(0b1100111, 0, 0b000, rd, 0)),
+                // C.MV
+                (0, _, 1..) => {
+                    Some(r_type(0b0110011, rd, 0b000, 0, rs2, 0))
+                }
+                // C.EBREAK
+                (1, 0, 0) => Some(i_type(0b1110011, 0, 0b000, 0, 1)),
+                // C.JALR
+                (1, _, 0) => Some(i_type(0b1100111, 1, 0b000, rd, 0)),
+                // C.ADD
+                (_, _, _) => {
+                    Some(r_type(0b0110011, rd, 0b000, rd, rs2, 0))
+                }
+            },
+            // C.FSDSP
+            (0b10, 0b101) => {
+                let imm = bits(12, 10) << 3 | bits(9, 7) << 6;
+                Some(s_type(0b0100111, 0b011, imm, 2, rs2))
+            }
+            // C.SDSP
+            (0b10, 0b111) if rv64 => {
+                let imm = bits(12, 10) << 3 | bits(9, 7) << 6;
+                Some(s_type(0b0100011, 0b011, imm, 2, rs2))
+            }
+            // C.SWSP, C.FSWSP
+            (0b10, 0b110 | 0b111) => {
+                let imm = bits(12, 9) << 2 | bits(8, 7) << 6;
+                let opcode = match funct3 {
+                    0b110 => 0b0100011,
+                    _ => 0b0100111,
+                };
+                Some(s_type(opcode, 0b010, imm, 2, rs2))
+            }
+            _ => None,
+        };
+
+        if let Some((opcode, type_name, type_data)) = expanded {
+            instruction.opcode = opcode;
+            instruction.type_name = type_name;
+            instruction.type_data = type_data;
+        }
+        instruction
+    }
+
+    fn execute(&mut self, inst: &Instruction) -> Result<(), Exception> {
         match inst.type_name {
             InstTypeName::R => {
                 if let InstTypeData::R {
@@ -228,121 +1331,106 @@ impl Cpu {
                     rs2,
                 } = inst.type_data
                 {
+                    let lhs = self.registers[rs1];
+                    let rhs = self.registers[rs2];
+                    // register shifts only use the low log2(XLEN) bits
+                    let shamt = rhs as u32 & (self.xlen.bits() - 1);
                     match funct3 {
+                        _ if inst.opcode == 0b0101111 => {
+                            self.execute_atomic(
+                                inst, funct3, funct7, rd, rs1, rs2,
+                            )?;
+                        }
+                        _ if inst.opcode == 0b1010011 => {
+                            self.execute_fp(
+                                inst, funct3, funct7, rd, rs1, rs2,
+                            )?;
+                        }
+                        _ if inst.opcode & 0b1110011 == 0b1000011 => {
+                            self.execute_fma(
+                                inst, funct3, funct7, rd, rs1, rs2,
+                            )?;
+                        }
+                        _ if inst.opcode == 0b0111011 => {
+                            self.execute_word(
+                                inst, funct3, funct7, rd, rs1, rs2,
+                            )?;
+                        }
+                        _ if funct7 == 0x01 => {
+                            self.execute_muldiv(funct3, rd, rs1, rs2);
+                        }
+                        // everything else but sub and sra is one of the
+                        // bit-manipulation instructions
+                        _ if funct7 != 0x00
+                            && !(funct7 == 0x20
+                                && matches!(funct3, 0x0 | 0x5)) =>
+                        {
+                            self.execute_bitmanip(
+                                inst, funct3, funct7, rd, rs1, rs2,
+                            )?;
+                        }
                         0x0 => match funct7 {
                             0x0 => {
-                                inst.name = format!(
-                                    "add     x{},x{},x{}",
-                                    rd, rs1, rs2
-                                );
-                                self.registers[rd] = self.registers[rs1]
-                                    .wrapping_add(self.registers[rs2]);
+                                self.registers[rd] =
+                                    self.wrap(lhs.wrapping_add(rhs));
                             }
                             0x20 => {
-                                inst.name = format!(
-                                    "sub     x{},x{},x{}",
-                                    rd, rs1, rs2
-                                );
-                                self.registers[rd] = self.registers[rs1]
-                                    .wrapping_sub(self.registers[rs2]);
+                                self.registers[rd] =
+                                    self.wrap(lhs.wrapping_sub(rhs));
                             }
                             _ => {
-                                panic!(
-                                    "unknown R funct7: {:#09b}",
-                                    funct7
+                                return Err(
+                                    Exception::IllegalInstruction(
+                                        inst.raw,
+                                    ),
                                 );
                             }
                         },
                         0x4 => {
-                            inst.name = format!(
-                                "xor     x{},x{},x{}",
-                                rd, rs1, rs2
-                            );
-                            self.registers[rd] =
-                                self.registers[rs1] ^ self.registers[rs2];
+                            self.registers[rd] = lhs ^ rhs;
                         }
                         0x6 => {
-                            inst.name = format!(
-                                "or      x{},x{},x{}",
-                                rd, rs1, rs2
-                            );
-                            self.registers[rd] =
-                                self.registers[rs1] | self.registers[rs2];
+                            self.registers[rd] = lhs | rhs;
                         }
                         0x7 => {
-                            inst.name = format!(
-                                "and     x{},x{},x{}",
-                                rd, rs1, rs2
-                            );
-                            self.registers[rd] =
-                                self.registers[rs1] & self.registers[rs2];
+                            self.registers[rd] = lhs & rhs;
                         }
                         0x1 => {
-                            inst.name = format!(
-                                "sll     x{},x{},x{}",
-                                rd, rs1, rs2
-                            );
-                            self.registers[rd] =
-                                self.registers[rs1] << self.registers[rs2];
+                            self.registers[rd] = self.wrap(lhs << shamt);
                         }
                         0x5 => match funct7 {
                             0x0 => {
-                                inst.name = format!(
-                                    "srl     x{},x{},x{}",
-                                    rd, rs1, rs2
-                                );
-                                self.registers[rd] = self.registers[rs1]
-                                    >> self.registers[rs2];
+                                self.registers[rd] = lhs >> shamt;
                             }
                             0x20 => {
-                                inst.name = format!(
-                                    "sra     x{},x{},x{}",
-                                    rd, rs1, rs2
+                                self.registers[rd] = self.wrap(
+                                    (self.signed(lhs) >> shamt) as u64,
                                 );
-                                self.registers[rd] = ((self.registers[rs1]
-                                    as i32)
-                                    >> self.registers[rs2])
-                                    as u32;
                             }
                             _ => {
-                                panic!(
-                                    "unknown R funct7: {:#09b}",
-                                    funct7
+                                return Err(
+                                    Exception::IllegalInstruction(
+                                        inst.raw,
+                                    ),
                                 );
                             }
                         },
                         0x2 => {
-                            inst.name = format!(
-                                "slt     x{},x{},x{}",
-                                rd, rs1, rs2
-                            );
-                            self.registers[rd] = if (self.registers[rs1]
-                                as i32)
-                                < (self.registers[rs2] as i32)
-                            {
-                                1
-                            } else {
-                                0
-                            }
+                            self.registers[rd] =
+                                if self.signed(lhs) < self.signed(rhs) {
+                                    1
+                                } else {
+                                    0
+                                }
                         }
                         0x3 => {
-                            inst.name = format!(
-                                "sltu    x{},x{},x{}",
-                                rd, rs1, rs2
-                            );
-                            self.registers[rd] = if self.registers[rs1]
-                                < self.registers[rs2]
-                            {
-                                1
-                            } else {
-                                0
-                            }
+                            self.registers[rd] =
+                                if lhs < rhs { 1 } else { 0 }
                         }
                         _ => {
-                            panic!(
-                                "execute: unimplemented R funct3: {:#05b}",
-                                funct3
-                            );
+                            return Err(Exception::IllegalInstruction(
+                                inst.raw,
+                            ));
                         }
                     };
                 }
@@ -355,122 +1443,44 @@ impl Cpu {
                     rs2,
                 } = inst.type_data
                 {
-                    match funct3 {
-                        0x0 => {
-                            inst.name = format!(
-                                "beq     x{},x{},{:08x}",
-                                rs1,
-                                rs2,
-                                (self.pc) as i32 + imm as i32
-                            );
-                            let lhs = self.registers[rs1];
-                            let rhs = self.registers[rs2];
-                            if lhs == rhs {
-                                self.pc =
-                                    (self.pc as i32 + imm as i32) as u32;
-                                return;
-                            };
-                        }
-                        0x1 => {
-                            inst.name = format!(
-                                "bne     x{},x{},{:08x}",
-                                rs1,
-                                rs2,
-                                (self.pc) as i32 + imm as i32
-                            );
-                            let lhs = self.registers[rs1];
-                            let rhs = self.registers[rs2];
-                            if lhs != rhs {
-                                self.pc =
-                                    (self.pc as i32 + imm as i32) as u32;
-                                return;
-                            };
-                        }
-                        0x4 => {
-                            inst.name = format!(
-                                "blt     x{},x{},{:08x}",
-                                rs1,
-                                rs2,
-                                (self.pc) as i32 + imm as i32
-                            );
-                            let lhs = self.registers[rs1] as i32;
-                            let rhs = self.registers[rs2] as i32;
-                            if lhs < rhs {
-                                self.pc =
-                                    (self.pc as i32 + imm as i32) as u32;
-                                return;
-                            };
-                        }
-                        0x5 => {
-                            inst.name = format!(
-                                "bge     x{},x{},{:08x}",
-                                rs1,
-                                rs2,
-                                (self.pc) as i32 + imm as i32
-                            );
-                            let lhs = self.registers[rs1] as i32;
-                            let rhs = self.registers[rs2] as i32;
-                            if lhs >= rhs {
-                                self.pc =
-                                    (self.pc as i32 + imm as i32) as u32;
-                                return;
-                            };
-                        }
-                        0x6 => {
-                            inst.name = format!(
-                                "bltu    x{},x{},{:08x}",
-                                rs1,
-                                rs2,
-                                (self.pc) as i32 + imm as i32
-                            );
-                            let lhs = self.registers[rs1];
-                            let rhs = self.registers[rs2];
-                            if lhs < rhs {
-                                self.pc =
-                                    (self.pc as i32 + imm as i32) as u32;
-                                return;
-                            };
-                        }
-                        0x7 => {
-                            inst.name = format!(
-                                "bgeu    x{},x{},{:08x}",
-                                rs1,
-                                rs2,
-                                (self.pc) as i32 + imm as i32
-                            );
-                            let lhs = self.registers[rs1];
-                            let rhs = self.registers[rs2];
-                            if lhs >= rhs {
-                                self.pc =
-                                    (self.pc as i32 + imm as i32) as u32;
-                                return;
-                            };
-                        }
+                    let lhs = self.registers[rs1];
+                    let rhs = self.registers[rs2];
+                    let taken = match funct3 {
+                        0x0 => lhs == rhs,
+                        0x1 => lhs != rhs,
+                        0x4 => self.signed(lhs) < self.signed(rhs),
+                        0x5 => self.signed(lhs) >= self.signed(rhs),
+                        0x6 => lhs < rhs,
+                        0x7 => lhs >= rhs,
                         _ => {
-                            panic!(
-                                "execute: unimplemented B funct3: {:#05b}",
-                                funct3
-                            );
+                            return Err(Exception::IllegalInstruction(
+                                inst.raw,
+                            ));
                         }
                     };
+                    if taken {
+                        self.pc = self
+                            .wrap(self.pc.wrapping_add(imm as i32 as u64));
+                        return Ok(());
+                    }
                 }
             }
             InstTypeName::J => {
                 if let InstTypeData::J { rd, imm } = inst.type_data {
                     match inst.opcode {
                         0b1101111 => {
-                            inst.name =
-                                format!("jal     x{},{:08x}", rd, imm);
-                            self.registers[rd] = self.pc + 4;
-                            self.pc = (self.pc as i32 + imm as i32) as u32;
+                            self.registers[rd] =
+                                self.wrap(self.pc + inst.size as u64);
+                            self.pc = self.wrap(
+                                self.pc.wrapping_add(imm as i32 as u64),
+                            );
                             self.registers[0] = 0;
-                            return;
+                            return Ok(());
                         }
                         _ => {
-                            panic!(
-                                "execute: unimplemented J opcode: {:#09b}",
-                                inst.opcode
-                            );
+                            return Err(Exception::IllegalInstruction(
+                                inst.raw,
+                            ));
                         }
                     };
                 }
@@ -483,280 +1493,194 @@ impl Cpu {
                     imm,
                 } = inst.type_data
                 {
+                    let src = self.registers[rs1];
+                    let simm = imm as i32 as u64;
                     match inst.opcode {
                         0b0010011 => match funct3 {
                             0x0 => {
-                                inst.name = format!(
-                                    "addi    x{},x{},{}",
-                                    rd, rs1, imm as i32
-                                );
-                                self.registers[rd] = (self.registers[rs1]
-                                    as i32)
-                                    .wrapping_add(imm as i32)
-                                    as u32;
-
-                                if rd == 0 && rs1 == 0 && imm == 0 {
-                                    inst.name = String::from("nop");
-                                }
+                                self.registers[rd] =
+                                    self.wrap(src.wrapping_add(simm));
                             }
                             0x4 => {
-                                inst.name = format!(
-                                    "xori    x{},x{},{}",
-                                    rd, rs1, imm as i32
-                                );
-                                self.registers[rd] = ((self.registers[rs1]
-                                    as i32)
-                                    ^ (imm as i32))
-                                    as u32;
+                                self.registers[rd] = self.wrap(src ^ simm);
                             }
                             0x6 => {
-                                inst.name = format!(
-                                    "ori     x{},x{},{}",
-                                    rd, rs1, imm as i32
-                                );
-                                self.registers[rd] = ((self.registers[rs1]
-                                    as i32)
-                                    | (imm as i32))
-                                    as u32;
+                                self.registers[rd] = self.wrap(src | simm);
                             }
                             0x7 => {
-                                inst.name = format!(
-                                    "andi    x{},x{},{}",
-                                    rd, rs1, imm as i32
-                                );
-                                self.registers[rd] = ((self.registers[rs1]
-                                    as i32)
-                                    & (imm as i32))
-                                    as u32;
+                                self.registers[rd] = src & simm;
                             }
                             0x2 => {
-                                inst.name = format!(
-                                    "slti    x{},x{},{}",
-                                    rd, rs1, imm as i32
-                                );
                                 self.registers[rd] =
-                                    if (self.registers[rs1] as i32)
-                                        < (imm as i32)
-                                    {
+                                    if self.signed(src) < simm as i64 {
                                         1
                                     } else {
                                         0
                                     }
                             }
                             0x3 => {
-                                inst.name = format!(
-                                    "sltiu   x{},x{},{}",
-                                    rd, rs1, imm
-                                );
                                 self.registers[rd] =
-                                    if self.registers[rs1] < imm {
+                                    if src < self.wrap(simm) {
                                         1
                                     } else {
                                         0
                                     }
                             }
-                            0x1 => {
-                                let shamt = imm & 0b11111;
-                                inst.name = format!(
-                                    "slli    x{},x{},{:#x}",
-                                    rd, rs1, shamt
-                                );
+                            // the shifts split the immediate into funct6
+                            // and a 6-bit shamt
+                            0x1 if (imm >> 6) & 0b111111 == 0 => {
+                                let shamt = imm & 0b111111;
                                 self.registers[rd] =
-                                    self.registers[rs1] << shamt;
+                                    self.wrap(src << shamt);
                             }
-                            0x5 => match (imm >> 5) & 0b1111111 {
+                            0x5 => match (imm >> 6) & 0b111111 {
                                 0 => {
-                                    let shamt = imm & 0b11111;
-                                    inst.name = format!(
-                                        "srli    x{},x{},{:#x}",
-                                        rd, rs1, shamt
-                                    );
-                                    self.registers[rd] =
-                                        self.registers[rs1] >> shamt;
+                                    let shamt = imm & 0b111111;
+                                    self.registers[rd] = src >> shamt;
                                 }
-                                0b0100000 => {
-                                    let shamt = imm & 0b11111;
-                                    inst.name = format!(
-                                        "srai    x{},x{},{:#x}",
-                                        rd, rs1, shamt
-                                    );
-                                    self.registers[rd] = Cpu::sign_extend(
-                                        self.registers[rs1] >> shamt,
-                                        32 - shamt,
+                                0b010000 => {
+                                    let shamt = imm & 0b111111;
+                                    self.registers[rd] = self.wrap(
+                                        (self.signed(src) >> shamt) as u64,
                                     );
                                 }
                                 _ => {
-                                    panic!("should never be here.")
+                                    self.execute_bitmanip_imm(
+                                        inst, funct3, rd, rs1, imm,
+                                    )?;
                                 }
                             },
-                            _ => {
-                                panic!(
-                                    "unknown I funct3: {:#05b}",
-                                    funct3,
-                                );
-                            }
-                        },
-                        0b0000011 => match funct3 {
-                            0x0 => {
-                                inst.name = format!(
-                                    "lb      x{},{}(x{})",
-                                    rd, imm as i32, rs1
-                                );
-                                let index = (self.registers[rs1]
-                                    + Cpu::sign_extend(imm, 12))
-                                    as usize;
-                                self.registers[rd] = Cpu::sign_extend(
-                                    self.memory[index] as u32,
-                                    8,
-                                );
-                            }
                             0x1 => {
-                                inst.name = format!(
-                                    "lh      x{},{}(x{})",
-                                    rd, imm as i32, rs1
-                                );
-                                let index = (self.registers[rs1]
-                                    + Cpu::sign_extend(imm, 12))
-                                    as usize;
-                                let half_word = self.memory[index] as u32
-                                    | (self.memory[index + 1] as u32) << 8;
-                                self.registers[rd] =
-                                    Cpu::sign_extend(half_word as u32, 16);
-                            }
-                            0x2 => {
-                                inst.name = format!(
-                                    "lw      x{},{}(x{})",
-                                    rd, imm as i32, rs1
-                                );
-                                let index = (self.registers[rs1]
-                                    + Cpu::sign_extend(imm, 12))
-                                    as usize;
-
-                                self.registers[rd] = self.memory[index]
-                                    as u32
-                                    | ((self.memory[index + 1]) as u32)
-                                        << 8
-                                    | ((self.memory[index + 2]) as u32)
-                                        << 16
-                                    | ((self.memory[index + 3]) as u32)
-                                        << 24;
-                            }
-                            0x4 => {
-                                inst.name = format!(
-                                    "lbu     x{},{}(x{})",
-                                    rd, imm, rs1
-                                );
-                                let index = (self.registers[rs1]
-                                    + Cpu::sign_extend(imm, 12))
-                                    as usize;
-                                self.registers[rd] =
-                                    self.memory[index] as u32;
-                            }
-                            0x5 => {
-                                inst.name = format!(
-                                    "lhu     x{},{}(x{})",
-                                    rd, imm, rs1
-                                );
-                                let index = (self.registers[rs1]
-                                    + Cpu::sign_extend(imm, 12))
-                                    as usize;
-
-                                self.registers[rd] = self.memory[index]
-                                    as u32
-                                    | (self.memory[index + 1] as u32) << 8;
+                                self.execute_bitmanip_imm(
+                                    inst, funct3, rd, rs1, imm,
+                                )?;
                             }
                             _ => {
-                                panic!(
-                                    "unknown I funct3: {:#05b}",
-                                    funct3
+                                return Err(
+                                    Exception::IllegalInstruction(
+                                        inst.raw,
+                                    ),
                                 );
                             }
                         },
+                        0b0011011 => {
+                            self.execute_word_imm(
+                                inst, funct3, rd, rs1, imm,
+                            )?;
+                        }
+                        0b0000111 => {
+                            self.load_fp(inst, funct3, rd, rs1, imm)?;
+                        }
+                        0b0000011 => {
+                            let addr = self.wrap(src.wrapping_add(simm));
+                            // loads sign-extend to XLEN, except the
+                            // unsigned ones
+                            let value =
+                                match funct3 {
+                                    0x0 => self.read_data(addr, 1)? as i8
+                                        as u64,
+                                    0x1 => self.read_data(addr, 2)? as i16
+                                        as u64,
+                                    0x2 => self.read_data(addr, 4)? as i32
+                                        as u64,
+                                    0x3 => self.read_data(addr, 8)?,
+                                    0x4 => self.read_data(addr, 1)?,
+                                    0x5 => self.read_data(addr, 2)?,
+                                    0x6 => self.read_data(addr, 4)?,
+                                    _ => {
+                                        return Err(
+                                            Exception::IllegalInstruction(
+                                                inst.raw,
+                                            ),
+                                        );
+                                    }
+                                };
+                            self.registers[rd] = self.wrap(value);
+                        }
                         0b1100111 => match funct3 {
                             0x0 => {
-                                inst.name = format!(
-                                    "jalr    x{},x{},{:#x}",
-                                    rd, rs1, imm
-                                );
                                 let pc_copy = self.pc;
-                                self.pc = self.registers[rs1]
-                                    + Cpu::sign_extend(imm, 12);
+                                self.pc =
+                                    self.wrap(src.wrapping_add(simm));
                                 self.pc &= !1; // set lsb to 0
-                                self.registers[rd] = pc_copy + 4;
+                                self.registers[rd] =
+                                    self.wrap(pc_copy + inst.size as u64);
 
                                 self.registers[0] = 0;
-                                return;
+                                return Ok(());
                             }
                             _ => {
-                                panic!(
-                                    "unknown I funct3: {:#05b}",
-                                    funct3
+                                return Err(
+                                    Exception::IllegalInstruction(
+                                        inst.raw,
+                                    ),
                                 );
                             }
                         },
-                        0b1110011 => match funct3 {
-                            0b000 => match imm {
-                                0x0 => {
-                                    inst.name = String::from("ecall");
+                        0b1110011 => {
+                            match funct3 {
+                                // only sfence.vma has register operands
+                                0b000
+                                    if rd != 0
+                                        || (rs1 != 0
+                                            && imm >> 5 != 0b0001001) =>
+                                {
+                                    return Err(
+                                        Exception::IllegalInstruction(
+                                            inst.raw,
+                                        ),
+                                    );
                                 }
-                                0x1 => {
-                                    inst.name = String::from("ebreak");
+                                0b000 => {
+                                    match imm {
+                                        0x0 => {
+                                            return Err(self.ecall());
+                                        }
+                                        0x1 => {
+                                            return Err(
+                                                Exception::Breakpoint(
+                                                    self.pc,
+                                                ),
+                                            );
+                                        }
+                                        0b0001_0000_0010 => {
+                                            return self.sret(inst.raw);
+                                        }
+                                        0b1100000010 => {
+                                            return self.mret(inst.raw);
+                                        }
+                                        0b0001_0000_0101 => {
+                                            // wfi: no interrupts to wait for
+                                        }
+                                        _ if imm >> 5 == 0b0001001 => {
+                                            self.sfence_vma(inst.raw)?;
+                                        }
+                                        _ => return Err(
+                                            Exception::IllegalInstruction(
+                                                inst.raw,
+                                            ),
+                                        ),
+                                    }
                                 }
-                                0b1100000010 => {
-                                    inst.name = String::from("mret");
+                                0b001 | 0b010 | 0b011 | 0b101 | 0b110
+                                | 0b111 => {
+                                    self.execute_csr(
+                                        inst, funct3, rd, rs1, imm,
+                                    )?;
                                 }
                                 _ => {
-                                    panic!("unknown I imm: {:#014b}", imm)
+                                    return Err(
+                                        Exception::IllegalInstruction(
+                                            inst.raw,
+                                        ),
+                                    );
                                 }
-                            },
-                            0b001 => {
-                                inst.name = format!(
-                                    "csrrw   x{},{:#x},x{}",
-                                    rd, imm, rs1
-                                );
-                            }
-                            0b010 => {
-                                inst.name = format!(
-                                    "csrrs   x{},{:#x},x{}",
-                                    rd, imm, rs1
-                                );
-                            }
-                            0b011 => {
-                                inst.name = format!(
-                                    "csrrc   x{},{:#x},x{}",
-                                    rd, imm, rs1
-                                );
-                            }
-                            0b101 => {
-                                inst.name = format!(
-                                    "csrrwi  x{},{:#x},{}",
-                                    rd, imm, rs1
-                                );
-                            }
-                            0b110 => {
-                                inst.name = format!(
-                                    "csrrsi  x{},{:#x},{}",
-                                    rd, imm, rs1
-                                );
                             }
-                            0b111 => {
-                                inst.name = format!(
-                                    "csrrci  x{},{:#x},{}",
-                                    rd, imm, rs1
-                                );
-                            }
-                            _ => {
-                                panic!(
-                                    "unknown I funct3: {:#05b}",
-                                    funct3
-                                );
-                            }
-                        },
+                        }
                         _ => {
-                            panic!(
-                                "unknown I opcode: {:#09b}",
-                                inst.opcode
-                            );
+                            return Err(Exception::IllegalInstruction(
+                                inst.raw,
+                            ));
                         }
                     };
                 }
@@ -769,261 +1693,1040 @@ impl Cpu {
                     rs2,
                 } = inst.type_data
                 {
-                    match funct3 {
-                        0x0 => {
-                            inst.name = format!(
-                                "sb      x{},{}(x{})",
-                                rs2, imm as i32, rs1
-                            );
-                            let index = (self.registers[rs1]
-                                + Cpu::sign_extend(imm, 12))
-                                as usize;
-                            self.memory[index] =
-                                (self.registers[rs2] & 0xff) as u8;
-                        }
-                        0x1 => {
-                            inst.name = format!(
-                                "sh      x{},{}(x{})",
-                                rs2, imm as i32, rs1
-                            );
-                            let index = (self.registers[rs1]
-                                + Cpu::sign_extend(imm, 12))
-                                as usize;
-                            self.memory[index] =
-                                (self.registers[rs2] & 0xff) as u8;
-                            self.memory[index + 1] =
-                                (self.registers[rs2] >> 8 & 0xff) as u8;
-                        }
-                        0x2 => {
-                            inst.name = format!(
-                                "sw      x{},{}(x{})",
-                                rs2, imm as i32, rs1
-                            );
-                            let index = (self.registers[rs1]
-                                + Cpu::sign_extend(imm, 12))
-                                as usize;
-                            self.memory[index] =
-                                (self.registers[rs2] & 0xff) as u8;
-                            self.memory[index + 1] =
-                                (self.registers[rs2] >> 8 & 0xff) as u8;
-                            self.memory[index + 2] =
-                                (self.registers[rs2] >> 16 & 0xff) as u8;
-                            self.memory[index + 3] =
-                                (self.registers[rs2] >> 24 & 0xff) as u8;
+                    let addr = self.wrap(
+                        self.registers[rs1]
+                            .wrapping_add(imm as i32 as u64),
+                    );
+                    let size = match funct3 {
+                        _ if inst.opcode == 0b0100111 => {
+                            self.store_fp(inst, funct3, addr, rs2)?;
+                            0
                         }
+                        0x0 => 1,
+                        0x1 => 2,
+                        0x2 => 4,
+                        0x3 => 8,
                         _ => {
-                            panic!("unknown S funct3: {:#05b}", funct3);
+                            return Err(Exception::IllegalInstruction(
+                                inst.raw,
+                            ));
                         }
                     };
+                    if size > 0 {
+                        self.write_data(addr, self.registers[rs2], size)?;
+                    }
                 }
             }
             InstTypeName::U => {
                 if let InstTypeData::U { rd, imm } = inst.type_data {
+                    // the upper immediate is sign-extended on RV64
+                    let imm = (imm << 12) as i32 as u64;
                     match inst.opcode {
                         0b0110111 => {
-                            inst.name =
-                                format!("lui     x{},{:#x}", rd, imm);
-                            self.registers[rd] = imm << 12;
+                            self.registers[rd] = self.wrap(imm);
                         }
                         0b0010111 => {
-                            inst.name =
-                                format!("auipc   x{},{:#x}", rd, imm);
-                            self.registers[rd] = self.pc + (imm << 12);
+                            self.registers[rd] =
+                                self.wrap(self.pc.wrapping_add(imm));
                         }
                         _ => {
-                            panic!(
-                                "unknown U opcode: {:#09b}",
-                                inst.opcode
-                            );
+                            return Err(Exception::IllegalInstruction(
+                                inst.raw,
+                            ));
                         }
                     };
                 }
             }
-            InstTypeName::Fence => inst.name = String::from("fence"),
-            InstTypeName::Unimp => inst.name = String::from("unimp"),
+            InstTypeName::Fence => {
+                // fence.i: make earlier stores visible to instruction
+                // fetches, i.e. forget decoded instructions
+                if (inst.raw >> 12) & 0b111 == 0b001 {
+                    self.icache.flush();
+                }
+            }
+            InstTypeName::Unimp => {
+                return Err(Exception::IllegalInstruction(inst.raw));
+            }
         }
         self.registers[0] = 0;
-        self.pc += 4;
+        self.pc = self.wrap(self.pc + inst.size as u64);
+        Ok(())
     }
 
-    fn command_handler(&mut self, com: &str) {
-        if com.is_empty() {
-            return;
+    /// Returns from a machine-mode trap handler.
+    fn mret(&mut self, raw_inst: u32) -> Result<(), Exception> {
+        if self.privilege != PRV_M {
+            return Err(Exception::IllegalInstruction(raw_inst));
         }
-        let tokens: Vec<&str> = com.split(' ').collect();
-        match tokens[0] {
-            "mem" => {
-                let addr = usize::from_str_radix(tokens[1], 16);
-                match addr {
-                    Ok(addr) => {
-                        if addr + 3 > self.memory.len() - 1 {
-                            println!("bad argument: memory out of bounds");
-                            return;
-                        }
-                        let chunk = self.memory[addr] as u32
-                            | (self.memory[addr + 1] as u32) << 8
-                            | (self.memory[addr + 2] as u32) << 16
-                            | (self.memory[addr + 3] as u32) << 24;
-                        println!("{:#010x}", chunk)
+        let mstatus = self.csrs[MSTATUS as usize];
+        self.privilege = ((mstatus & MSTATUS_MPP) >> 11) as u32;
+        // MIE = MPIE, MPIE = 1, MPP = U
+        let mut new = mstatus & !(MSTATUS_MIE | MSTATUS_MPP);
+        if mstatus & MSTATUS_MPIE != 0 {
+            new |= MSTATUS_MIE;
+        }
+        // MPRV only applies to machine mode, which this may be leaving
+        if self.privilege != PRV_M {
+            new &= !MSTATUS_MPRV;
+        }
+        self.csrs[MSTATUS as usize] = new | MSTATUS_MPIE;
+        self.pc = self.csrs[MEPC as usize];
+        Ok(())
+    }
+
+    /// The exception an `ecall` raises, which depends on the privilege
+    /// level it's made from.
+    fn ecall(&self) -> Exception {
+        match self.privilege {
+            PRV_U => Exception::EcallFromU,
+            PRV_S => Exception::EcallFromS,
+            _ => Exception::EcallFromM,
+        }
+    }
+
+    /// Returns from a supervisor-mode trap handler.
+    fn sret(&mut self, raw_inst: u32) -> Result<(), Exception> {
+        let mstatus = self.csrs[MSTATUS as usize];
+        if self.privilege == PRV_U
+            || (self.privilege == PRV_S && mstatus & MSTATUS_TSR != 0)
+        {
+            return Err(Exception::IllegalInstruction(raw_inst));
+        }
+        self.privilege = ((mstatus & MSTATUS_SPP) >> 8) as u32;
+        // SIE = SPIE, SPIE = 1, SPP = U
+        let mut new =
+            mstatus & !(MSTATUS_SIE | MSTATUS_SPP | MSTATUS_MPRV);
+        if mstatus & MSTATUS_SPIE != 0 {
+            new |= MSTATUS_SIE;
+        }
+        self.csrs[MSTATUS as usize] = new | MSTATUS_SPIE;
+        self.pc = self.csrs[SEPC as usize];
+        Ok(())
+    }
+
+    /// Makes earlier page table stores visible to address translation.
+    /// The TLB has no ASIDs, and every `sfence.vma` flushes all of it.
+    fn sfence_vma(&mut self, raw_inst: u32) -> Result<(), Exception> {
+        let tvm = self.csrs[MSTATUS as usize] & MSTATUS_TVM != 0;
+        if self.privilege == PRV_U || (self.privilege == PRV_S && tvm) {
+            return Err(Exception::IllegalInstruction(raw_inst));
+        }
+        self.tlb.flush();
+        Ok(())
+    }
+
+    /// Enters the trap handler for `exception`: the supervisor-mode one
+    /// if `medeleg` delegates it from below machine mode, otherwise the
+    /// machine-mode one. Returns false without touching any state if the
+    /// program hasn't set that handler up, in which case the emulator
+    /// deals with it instead.
+    fn trap(&mut self, exception: Exception) -> bool {
+        let cause = exception.cause();
+        if self.privilege != PRV_M
+            && self.csrs[MEDELEG as usize] >> cause & 1 != 0
+        {
+            return self.trap_to_supervisor(exception);
+        }
+        // exceptions always go to the base, even in vectored mode
+        let base = self.csrs[MTVEC as usize] & !0b11;
+        if base == 0 || self.read_mem(base, 2).is_none() {
+            return false;
+        }
+        let mstatus = self.csrs[MSTATUS as usize];
+        let mut new =
+            mstatus & !(MSTATUS_MIE | MSTATUS_MPIE | MSTATUS_MPP);
+        if mstatus & MSTATUS_MIE != 0 {
+            new |= MSTATUS_MPIE;
+        }
+        self.csrs[MSTATUS as usize] = new | (self.privilege as u64) << 11;
+        self.csrs[MEPC as usize] = self.pc;
+        self.csrs[MCAUSE as usize] = cause as u64;
+        self.csrs[MTVAL as usize] = exception.value();
+        self.privilege = PRV_M;
+        self.pc = base;
+        true
+    }
+
+    fn trap_to_supervisor(&mut self, exception: Exception) -> bool {
+        // `stvec` holds a virtual address, so it can't be checked
+        let base = self.csrs[STVEC as usize] & !0b11;
+        if base == 0 {
+            return false;
+        }
+        let mstatus = self.csrs[MSTATUS as usize];
+        let mut new =
+            mstatus & !(MSTATUS_SIE | MSTATUS_SPIE | MSTATUS_SPP);
+        if mstatus & MSTATUS_SIE != 0 {
+            new |= MSTATUS_SPIE;
+        }
+        if self.privilege == PRV_S {
+            new |= MSTATUS_SPP;
+        }
+        self.csrs[MSTATUS as usize] = new;
+        self.csrs[SEPC as usize] = self.pc;
+        self.csrs[SCAUSE as usize] = exception.cause() as u64;
+        self.csrs[STVAL as usize] = exception.value();
+        self.privilege = PRV_S;
+        self.pc = base;
+        true
+    }
+
+    /// Fetches, decodes and executes a single instruction, trapping into
+    /// the program's handler if it raises an exception. Returns the
+    /// decoded instruction (`unimp` if it couldn't be fetched) and the
+    /// exception, if the program didn't handle it. With commit logging
+    /// on, what the instruction changed is left in `commit` unless it
+    /// trapped into the program's handler.
+    pub(crate) fn step(&mut self) -> (Instruction, Option<Exception>) {
+        self.cycle = self.cycle.wrapping_add(1);
+        self.watch_hit = None;
+        if self.log_commits {
+            self.commit = Some(Commit {
+                privilege: self.privilege,
+                xlen: self.xlen,
+                ..Commit::default()
+            });
+        }
+        // the cache holds instructions by physical address, except those
+        // that cross into another page, which may be mapped differently
+        // next time. PMP may have changed since they were cached.
+        let fetched =
+            self.translate(self.pc, Access::Fetch).and_then(|addr| {
+                match self.icache.get(addr) {
+                    Some(inst)
+                        if self.pmp.allows(
+                            addr,
+                            inst.size as usize,
+                            Access::Fetch,
+                            self.privilege,
+                        ) =>
+                    {
+                        Ok(inst)
                     }
-                    Err(err) => println!("bad argument: {}", err),
+                    _ => self.fetch(addr).map(|raw_inst| {
+                        let inst = Cpu::decode(raw_inst, self.xlen);
+                        if inst.size == 2 || self.pc & 0xfff != 0xffe {
+                            self.icache.insert(addr, inst);
+                        }
+                        inst
+                    }),
+                }
+            });
+        let (inst, result) = match fetched {
+            Ok(inst) => (inst, self.execute(&inst)),
+            Err(e) => (Instruction::new(), Err(e)),
+        };
+        let exception = match result {
+            Ok(()) => {
+                self.instret = self.instret.wrapping_add(1);
+                if let Some(commit) = &mut self.commit {
+                    commit.reg = inst
+                        .rd()
+                        .filter(|&rd| rd != 0)
+                        .map(|rd| (rd, self.registers[rd]));
                 }
+                None
             }
-            "reg" => {
-                let reg = tokens[1].parse::<usize>();
-                match reg {
-                    Ok(reg) => {
-                        if reg > self.registers.len() - 1 {
-                            println!("bad argument: no such register");
-                            return;
-                        }
-                        println!("{:#x}", self.registers[reg])
-                    }
-                    Err(err) => println!("bad argument: {}", err),
+            // a trapping instruction doesn't retire
+            Err(e) if self.trap(e) => {
+                self.commit = None;
+                None
+            }
+            Err(e) => Some(e),
+        };
+        (inst, exception)
+    }
+
+    /// Executes the M instructions (R type with funct7 = 0x01).
+    fn execute_muldiv(
+        &mut self,
+        funct3: u32,
+        rd: usize,
+        rs1: usize,
+        rs2: usize,
+    ) {
+        let lhs = self.registers[rs1];
+        let rhs = self.registers[rs2];
+        let bits = self.xlen.bits();
+        let result = match funct3 {
+            0x0 => lhs.wrapping_mul(rhs),
+            0x1 => {
+                let product =
+                    self.signed(lhs) as i128 * self.signed(rhs) as i128;
+                (product >> bits) as u64
+            }
+            0x2 => {
+                let product = self.signed(lhs) as i128 * rhs as i128;
+                (product >> bits) as u64
+            }
+            0x3 => ((lhs as u128 * rhs as u128) >> bits) as u64,
+            // division by zero returns all ones and the overflowing
+            // MIN / -1 returns the dividend; neither traps
+            0x4 => {
+                if rhs == 0 {
+                    u64::MAX
+                } else {
+                    self.signed(lhs).wrapping_div(self.signed(rhs)) as u64
                 }
             }
-            _ => {
-                println!("Unknown command: {}", tokens[0])
+            0x5 => lhs.checked_div(rhs).unwrap_or(u64::MAX),
+            0x6 => {
+                if rhs == 0 {
+                    lhs
+                } else {
+                    self.signed(lhs).wrapping_rem(self.signed(rhs)) as u64
+                }
             }
-        }
+            0x7 => lhs.checked_rem(rhs).unwrap_or(lhs),
+            _ => unreachable!(),
+        };
+        self.registers[rd] = self.wrap(result);
     }
 
-    fn run_interactive(&mut self, args: Args) -> i32 {
-        let ret: i32;
-        let pc = args.pc;
-        if let Some(pc) = pc {
-            self.pc = u32::from_str_radix(&pc, 16).unwrap_or(self.pc);
-        }
-        if args.stack {
-            self.registers[2] = (self.memory.len() - 1) as u32;
-        }
-        let mut buf = String::new();
-        loop {
-            buf.clear();
-            print!("> ");
-            std::io::stdout().flush().unwrap();
-            std::io::stdin().read_line(&mut buf).unwrap();
-            buf.pop();
-            self.command_handler(&*buf);
-
-            let raw_inst = self.fetch();
-            let mut inst: Instruction = self.decode(raw_inst);
-            let pc_copy = self.pc;
-
-            if (&*buf).is_empty() {
-                self.execute(&mut inst);
-                if args.registers {
-                    self.print_registers(args.aliases);
+    /// Executes the Zba, Zbb, Zbc and Zbs instructions on the OP opcode
+    /// (R type).
+    fn execute_bitmanip(
+        &mut self,
+        inst: &Instruction,
+        funct3: u32,
+        funct7: u32,
+        rd: usize,
+        rs1: usize,
+        rs2: usize,
+    ) -> Result<(), Exception> {
+        let lhs = self.registers[rs1];
+        let rhs = self.registers[rs2];
+        let bits = self.xlen.bits();
+        // rotates and single-bit instructions use the low log2(XLEN) bits
+        let shamt = rhs as u32 & (bits - 1);
+        let bit = 1 << shamt;
+        let result = match (funct7, funct3) {
+            (0x10, 0x2 | 0x4 | 0x6) => {
+                (lhs << (funct3 >> 1)).wrapping_add(rhs)
+            }
+            (0x20, 0x4) => !(lhs ^ rhs),
+            (0x20, 0x6) => lhs | !rhs,
+            (0x20, 0x7) => lhs & !rhs,
+            (0x05, 0x4) => self.signed(lhs).min(self.signed(rhs)) as u64,
+            (0x05, 0x5) => lhs.min(rhs),
+            (0x05, 0x6) => self.signed(lhs).max(self.signed(rhs)) as u64,
+            (0x05, 0x7) => lhs.max(rhs),
+            (0x05, 0x1..=0x3) => {
+                // carry-less multiplication: the product is at most
+                // 2 * XLEN - 1 bits wide
+                let product = (0..bits)
+                    .filter(|i| rhs >> i & 1 != 0)
+                    .fold(0u128, |acc, i| acc ^ (lhs as u128) << i);
+                match funct3 {
+                    0x1 => product as u64,
+                    0x2 => (product >> (bits - 1)) as u64,
+                    _ => (product >> bits) as u64,
                 }
-                println!(
-                    "{:<08x}:   {:08x}          	{}",
-                    pc_copy, raw_inst, inst.name
-                );
             }
+            (0x30, 0x1) => self.rotate_right(lhs, bits - shamt),
+            (0x30, 0x5) => self.rotate_right(lhs, shamt),
+            (0x04, 0x4) if rs2 == 0 => lhs as u16 as u64,
+            (0x24, 0x1) => lhs & !bit,
+            (0x24, 0x5) => lhs >> shamt & 1,
+            (0x34, 0x1) => lhs ^ bit,
+            (0x14, 0x1) => lhs | bit,
+            _ => return Err(Exception::IllegalInstruction(inst.raw)),
+        };
+        self.registers[rd] = self.wrap(result);
+        Ok(())
+    }
+
+    /// Executes the Zbb and Zbs instructions on the OP-IMM opcode (I
+    /// type). They share funct3 with the shifts and keep either a funct6
+    /// and a shamt or a whole funct12 in the immediate.
+    fn execute_bitmanip_imm(
+        &mut self,
+        inst: &Instruction,
+        funct3: u32,
+        rd: usize,
+        rs1: usize,
+        imm: u32,
+    ) -> Result<(), Exception> {
+        let src = self.registers[rs1];
+        let bits = self.xlen.bits();
+        let imm = imm & 0xfff;
+        let shamt = imm & 0b111111;
+        let bit = 1 << shamt;
+        let result = match (funct3, imm) {
+            (0x1, 0x600) => (src.leading_zeros() - (64 - bits)) as u64,
+            (0x1, 0x601) => src.trailing_zeros().min(bits) as u64,
+            (0x1, 0x602) => src.count_ones() as u64,
+            (0x1, 0x604) => src as i8 as u64,
+            (0x1, 0x605) => src as i16 as u64,
+            (0x5, 0x287) => (0..8)
+                .filter(|i| src >> (i * 8) & 0xff != 0)
+                .fold(0, |acc, i| acc | 0xff << (i * 8)),
+            (0x5, 0x698) => (src as u32).swap_bytes() as u64,
+            (0x5, 0x6b8) => src.swap_bytes(),
+            (0x1, _) if imm >> 6 == 0b010010 => src & !bit,
+            (0x5, _) if imm >> 6 == 0b010010 => src >> shamt & 1,
+            (0x1, _) if imm >> 6 == 0b011010 => src ^ bit,
+            (0x1, _) if imm >> 6 == 0b001010 => src | bit,
+            (0x5, _) if imm >> 6 == 0b011000 => {
+                self.rotate_right(src, shamt)
+            }
+            _ => return Err(Exception::IllegalInstruction(inst.raw)),
+        };
+        self.registers[rd] = self.wrap(result);
+        Ok(())
+    }
 
-            if (self.pc as usize) >= self.memory.len() {
-                if args.debug {
-                    println!("PC overflow.");
+    /// Executes the RV64I, RV64M and bit-manipulation word instructions
+    /// on the OP-32 opcode (R type), which work on the low 32 bits and
+    /// mostly sign-extend the result.
+    fn execute_word(
+        &mut self,
+        inst: &Instruction,
+        funct3: u32,
+        funct7: u32,
+        rd: usize,
+        rs1: usize,
+        rs2: usize,
+    ) -> Result<(), Exception> {
+        let lhs = self.registers[rs1] as u32;
+        let rhs = self.registers[rs2] as u32;
+        let result = match (funct7, funct3) {
+            // add.uw, sh[123]add.uw and zext.h zero-extend instead
+            (0x04, 0x0) | (0x10, 0x2 | 0x4 | 0x6) => {
+                self.registers[rd] = ((lhs as u64) << (funct3 >> 1))
+                    .wrapping_add(self.registers[rs2]);
+                return Ok(());
+            }
+            (0x04, 0x4) if rs2 == 0 => {
+                self.registers[rd] = lhs as u16 as u64;
+                return Ok(());
+            }
+            (0x00, 0x0) => lhs.wrapping_add(rhs),
+            (0x20, 0x0) => lhs.wrapping_sub(rhs),
+            (0x00, 0x1) => lhs << (rhs & 0b11111),
+            (0x00, 0x5) => lhs >> (rhs & 0b11111),
+            (0x20, 0x5) => ((lhs as i32) >> (rhs & 0b11111)) as u32,
+            (0x30, 0x1) => lhs.rotate_left(rhs & 0b11111),
+            (0x30, 0x5) => lhs.rotate_right(rhs & 0b11111),
+            (0x01, 0x0) => lhs.wrapping_mul(rhs),
+            (0x01, 0x4) => {
+                if rhs == 0 {
+                    u32::MAX
+                } else {
+                    (lhs as i32).wrapping_div(rhs as i32) as u32
                 }
-                ret = -1;
-                break;
-            }
-            match inst.name.as_str() {
-                "ecall" => match self.registers[17] {
-                    // `exit` syscall
-                    93 => {
-                        ret = self.registers[10] as i32;
-                        println!("Program exited with exit code: {}", ret);
-                        break;
-                    }
-                    _ => {
-                        if args.debug {
-                            println!(
-                                "Unimplemented ECALL: {}",
-                                self.registers[17],
-                            );
-                        }
-                        ret = -2;
-                        break;
-                    }
-                },
-                "unimp" => {
-                    if args.debug {
-                        println!("Reached an unimp instruction.");
-                    }
-                    ret = -3;
-                    break;
+            }
+            (0x01, 0x5) => lhs.checked_div(rhs).unwrap_or(u32::MAX),
+            (0x01, 0x6) => {
+                if rhs == 0 {
+                    lhs
+                } else {
+                    (lhs as i32).wrapping_rem(rhs as i32) as u32
                 }
-                _ => {}
             }
+            (0x01, 0x7) => lhs.checked_rem(rhs).unwrap_or(lhs),
+            _ => return Err(Exception::IllegalInstruction(inst.raw)),
+        };
+        self.registers[rd] = result as i32 as u64;
+        Ok(())
+    }
+
+    /// Executes the RV64I and bit-manipulation word instructions on the
+    /// OP-IMM-32 opcode (I type).
+    fn execute_word_imm(
+        &mut self,
+        inst: &Instruction,
+        funct3: u32,
+        rd: usize,
+        rs1: usize,
+        imm: u32,
+    ) -> Result<(), Exception> {
+        let src = self.registers[rs1] as u32;
+        let shamt = imm & 0b11111;
+        let result = match (funct3, imm >> 5 & 0b1111111) {
+            (0x0, _) => src.wrapping_add(imm),
+            (0x1, 0) => src << shamt,
+            (0x5, 0) => src >> shamt,
+            (0x5, 0b0100000) => ((src as i32) >> shamt) as u32,
+            // slli.uw has a 6-bit shamt and zero-extends
+            (0x1, 0b0000100 | 0b0000101) => {
+                self.registers[rd] = (src as u64) << (imm & 0b111111);
+                return Ok(());
+            }
+            (0x1, 0b0110000) if shamt == 0 => src.leading_zeros(),
+            (0x1, 0b0110000) if shamt == 1 => src.trailing_zeros(),
+            (0x1, 0b0110000) if shamt == 2 => src.count_ones(),
+            (0x5, 0b0110000) => src.rotate_right(shamt),
+            _ => return Err(Exception::IllegalInstruction(inst.raw)),
+        };
+        self.registers[rd] = result as i32 as u64;
+        Ok(())
+    }
+
+    /// Executes the A instructions (opcode 0b0101111), on words or,
+    /// on RV64, doublewords.
+    fn execute_atomic(
+        &mut self,
+        inst: &Instruction,
+        funct3: u32,
+        funct7: u32,
+        rd: usize,
+        rs1: usize,
+        rs2: usize,
+    ) -> Result<(), Exception> {
+        let size = match funct3 {
+            0b010 => 4,
+            0b011 => 8,
+            _ => return Err(Exception::IllegalInstruction(inst.raw)),
+        };
+        // sign-extends a memory value from the access size
+        let sext = |v: u64| if size == 4 { v as i32 as u64 } else { v };
+        let funct5 = funct7 >> 2;
+        let addr = self.registers[rs1];
+        let src = self.registers[rs2];
+        if funct5 == 0b00010 {
+            if addr & (size as u64 - 1) != 0 {
+                return Err(Exception::LoadAddressMisaligned(addr));
+            }
+            // the reservation is on physical memory, which stores to
+            // break it are made to
+            let paddr = self.translate(addr, Access::Load)?;
+            let value = self.load_physical(addr, paddr, size)?;
+            self.registers[rd] = self.wrap(sext(value));
+            self.reservation = Some((paddr, size));
+            return Ok(());
         }
-        ret
+        // the rest are stores, and fault as such
+        if addr & (size as u64 - 1) != 0 {
+            return Err(Exception::StoreAddressMisaligned(addr));
+        }
+        let paddr = self.translate(addr, Access::Store)?;
+        if funct5 == 0b00011 {
+            if self.reservation == Some((paddr, size)) {
+                self.store_physical(addr, paddr, src, size)?;
+                self.registers[rd] = 0;
+            } else {
+                self.registers[rd] = 1;
+            }
+            self.reservation = None;
+            return Ok(());
+        }
+        let old = self
+            .load_physical(addr, paddr, size)
+            .map(sext)
+            .map_err(|_| Exception::StoreAccessFault(addr))?;
+        let src = sext(src);
+        let new = match funct5 {
+            0b00001 => src,
+            0b00000 => old.wrapping_add(src),
+            0b00100 => old ^ src,
+            0b01100 => old & src,
+            0b01000 => old | src,
+            0b10000 => (old as i64).min(src as i64) as u64,
+            0b10100 => (old as i64).max(src as i64) as u64,
+            // both sign-extended, so unsigned order is unchanged
+            0b11000 => old.min(src),
+            0b11100 => old.max(src),
+            _ => return Err(Exception::IllegalInstruction(inst.raw)),
+        };
+        self.store_physical(addr, paddr, new, size)?;
+        self.registers[rd] = self.wrap(old);
+        Ok(())
     }
 
-    pub fn run(&mut self, args: Args) -> i32 {
-        if args.interactive {
-            return self.run_interactive(args);
+    /// Floating-point instructions are illegal while `mstatus.FS` is
+    /// off.
+    fn check_fp(&self, inst: &Instruction) -> Result<(), Exception> {
+        match self.csrs[MSTATUS as usize] & MSTATUS_FS {
+            0 => Err(Exception::IllegalInstruction(inst.raw)),
+            _ => Ok(()),
         }
-        let ret: i32;
-        let pc = args.pc;
-        if let Some(pc) = pc {
-            self.pc = u32::from_str_radix(&pc, 16).unwrap_or(self.pc);
+    }
+
+    /// Reads `f<n>` as a value in `fmt`. A single has to be NaN-boxed,
+    /// with the upper half of the register all ones, or it reads as the
+    /// canonical NaN.
+    fn read_freg(&self, fmt: Format, n: usize) -> u64 {
+        let value = self.fregs[n];
+        match fmt {
+            F64 => value,
+            _ if value >> 32 == 0xffff_ffff => value & 0xffff_ffff,
+            _ => fmt.canonical_nan(),
         }
-        if args.stack {
-            self.registers[2] = (self.memory.len() - 1) as u32;
+    }
+
+    /// Writes `value`, in `fmt`, to `f<n>`, NaN-boxing singles.
+    fn write_freg(&mut self, fmt: Format, n: usize, value: u64) {
+        let value = match fmt {
+            F64 => value,
+            _ => value | 0xffff_ffff_0000_0000,
+        };
+        self.fregs[n] = value;
+        self.dirty_fp();
+        if let Some(commit) = &mut self.commit {
+            commit.freg = Some((n, value));
         }
-        loop {
-            if args.registers {
-                self.print_registers(args.aliases);
-            }
-            let raw_inst = self.fetch();
-            let mut inst: Instruction = self.decode(raw_inst);
-            let pc_copy = self.pc;
-            self.execute(&mut inst);
-            if args.debug {
-                println!(
-                    "{:<08x}:   {:08x}          	{}",
-                    pc_copy, raw_inst, inst.name
-                );
+    }
+
+    /// Marks the floating-point state dirty in `mstatus.FS`, for an
+    /// instruction that changes it.
+    fn dirty_fp(&mut self) {
+        let mstatus = self.csrs[MSTATUS as usize];
+        if mstatus & MSTATUS_FS != MSTATUS_FS {
+            let mstatus =
+                mstatus | MSTATUS_FS | csr::mstatus_sd(self.xlen);
+            self.csrs[MSTATUS as usize] = mstatus;
+            if let Some(commit) = &mut self.commit {
+                commit.csrs.push((MSTATUS, mstatus));
             }
+        }
+    }
 
-            if (self.pc as usize) >= self.memory.len() {
-                if args.debug {
-                    println!("PC overflow.");
-                }
-                ret = -1;
-                break;
-            }
-            match inst.name.as_str() {
-                "ecall" => match self.registers[17] {
-                    // `exit` syscall
-                    93 => {
-                        ret = self.registers[10] as i32;
-                        println!("Program exited with exit code: {}", ret);
-                        break;
-                    }
-                    _ => {
-                        if args.debug {
-                            println!(
-                                "Unimplemented ECALL: {}",
-                                self.registers[17],
-                            );
-                        }
-                        ret = -2;
-                        break;
+    /// Accrues the exception flags an instruction raised in `fflags`.
+    fn raise(&mut self, flags: u32) {
+        if flags == 0 {
+            return;
+        }
+        self.csrs[FCSR as usize] |= flags as u64;
+        self.dirty_fp();
+        let fflags = self.read_csr(FFLAGS);
+        if let Some(commit) = &mut self.commit {
+            commit.csrs.push((FFLAGS, fflags));
+        }
+    }
+
+    /// The rounding mode an `rm` field selects: its own, or `frm` for 7
+    /// (dynamic). The reserved ones make the instruction illegal.
+    fn rounding(
+        &self,
+        inst: &Instruction,
+        rm: u32,
+    ) -> Result<Rounding, Exception> {
+        let rm = match rm {
+            0b111 => self.read_csr(FRM) as u32,
+            _ => rm,
+        };
+        Rounding::from_bits(rm)
+            .ok_or(Exception::IllegalInstruction(inst.raw))
+    }
+
+    /// Executes `flw` and `fld` (I type, opcode 0b0000111).
+    fn load_fp(
+        &mut self,
+        inst: &Instruction,
+        funct3: u32,
+        rd: usize,
+        rs1: usize,
+        imm: u32,
+    ) -> Result<(), Exception> {
+        self.check_fp(inst)?;
+        let addr =
+            self.wrap(self.registers[rs1].wrapping_add(imm as i32 as u64));
+        let (fmt, size) = match funct3 {
+            0b010 => (F32, 4),
+            0b011 => (F64, 8),
+            _ => return Err(Exception::IllegalInstruction(inst.raw)),
+        };
+        let value = self.read_data(addr, size)?;
+        self.write_freg(fmt, rd, value);
+        Ok(())
+    }
+
+    /// Executes `fsw` and `fsd` (S type, opcode 0b0100111), which store
+    /// the register's bits whether or not they're a valid value.
+    fn store_fp(
+        &mut self,
+        inst: &Instruction,
+        funct3: u32,
+        addr: u64,
+        rs2: usize,
+    ) -> Result<(), Exception> {
+        self.check_fp(inst)?;
+        let size = match funct3 {
+            0b010 => 4,
+            0b011 => 8,
+            _ => return Err(Exception::IllegalInstruction(inst.raw)),
+        };
+        self.write_data(addr, self.fregs[rs2], size)
+    }
+
+    /// Executes the F and D instructions on the OP-FP opcode (R type),
+    /// whose `funct7` ends in the format: 0 for single precision, 1 for
+    /// double.
+    fn execute_fp(
+        &mut self,
+        inst: &Instruction,
+        funct3: u32,
+        funct7: u32,
+        rd: usize,
+        rs1: usize,
+        rs2: usize,
+    ) -> Result<(), Exception> {
+        self.check_fp(inst)?;
+        let illegal = Exception::IllegalInstruction(inst.raw);
+        let fmt = match funct7 & 0b11 {
+            0b00 => F32,
+            0b01 => F64,
+            _ => return Err(illegal),
+        };
+        let (a, b) = (self.read_freg(fmt, rs1), self.read_freg(fmt, rs2));
+        let funct5 = funct7 >> 2;
+        // the operations that can round have an rm field in funct3
+        let mut env = match funct5 {
+            0b00000..=0b00011 | 0b01000 | 0b01011 | 0b11000 | 0b11010 => {
+                Env::new(self.rounding(inst, funct3)?)
+            }
+            _ => Env::new(Rounding::NearestEven),
+        };
+        match funct5 {
+            0b00000 => {
+                let result = fmt.add(&mut env, a, b);
+                self.write_freg(fmt, rd, result);
+            }
+            0b00001 => {
+                let result = fmt.sub(&mut env, a, b);
+                self.write_freg(fmt, rd, result);
+            }
+            0b00010 => {
+                let result = fmt.mul(&mut env, a, b);
+                self.write_freg(fmt, rd, result);
+            }
+            0b00011 => {
+                let result = fmt.div(&mut env, a, b);
+                self.write_freg(fmt, rd, result);
+            }
+            0b01011 if rs2 == 0 => {
+                let result = fmt.sqrt(&mut env, a);
+                self.write_freg(fmt, rd, result);
+            }
+            // fsgnj, fsgnjn and fsgnjx
+            0b00100 => {
+                let sign = match funct3 {
+                    0b000 => fmt.sign(b),
+                    0b001 => !fmt.sign(b),
+                    0b010 => fmt.sign(a) != fmt.sign(b),
+                    _ => return Err(illegal),
+                };
+                self.write_freg(fmt, rd, fmt.with_sign(a, sign));
+            }
+            0b00101 => {
+                let result = match funct3 {
+                    0b000 => fmt.min(&mut env, a, b),
+                    0b001 => fmt.max(&mut env, a, b),
+                    _ => return Err(illegal),
+                };
+                self.write_freg(fmt, rd, result);
+            }
+            // fcvt.s.d and fcvt.d.s
+            0b01000 => {
+                let from = match (fmt, rs2) {
+                    (F32, 1) => F64,
+                    (F64, 0) => F32,
+                    _ => return Err(illegal),
+                };
+                let value = self.read_freg(from, rs1);
+                let result = from.convert(&mut env, value, fmt);
+                self.write_freg(fmt, rd, result);
+            }
+            0b10100 => {
+                let result = match funct3 {
+                    0b010 => fmt.eq(&mut env, a, b),
+                    0b001 => fmt.lt(&mut env, a, b),
+                    0b000 => fmt.le(&mut env, a, b),
+                    _ => return Err(illegal),
+                };
+                self.registers[rd] = result as u64;
+            }
+            // fcvt.w, fcvt.wu, fcvt.l and fcvt.lu
+            0b11000 if rs2 < 4 => {
+                let bits = if rs2 < 2 { 32 } else { 64 };
+                let int = fmt.to_int(&mut env, a, rs2 & 1 == 0, bits);
+                self.registers[rd] = self.wrap(int);
+            }
+            // fcvt from w, wu, l and lu
+            0b11010 if rs2 < 4 => {
+                let int = self.registers[rs1];
+                let int = match rs2 {
+                    0 => int as i32 as u64,
+                    1 => int as u32 as u64,
+                    _ => int,
+                };
+                let result = fmt.from_int(&mut env, int, rs2 & 1 == 0);
+                self.write_freg(fmt, rd, result);
+            }
+            // fmv.x.w and fmv.x.d move the bits, boxed or not
+            0b11100 if rs2 == 0 && funct3 == 0b000 => {
+                self.registers[rd] = match fmt {
+                    F32 => self.wrap(self.fregs[rs1] as u32 as i32 as u64),
+                    _ => self.fregs[rs1],
+                };
+            }
+            0b11100 if rs2 == 0 && funct3 == 0b001 => {
+                self.registers[rd] = fmt.classify(a) as u64;
+            }
+            0b11110 if rs2 == 0 && funct3 == 0b000 => {
+                let value = match fmt {
+                    F32 => self.registers[rs1] as u32 as u64,
+                    _ => self.registers[rs1],
+                };
+                self.write_freg(fmt, rd, value);
+            }
+            _ => return Err(illegal),
+        }
+        self.raise(env.flags);
+        Ok(())
+    }
+
+    /// Executes the fused multiply-adds (R4 type), `fmadd`, `fmsub`,
+    /// `fnmsub` and `fnmadd` by opcode. `funct7` holds `rs3` and the
+    /// format.
+    fn execute_fma(
+        &mut self,
+        inst: &Instruction,
+        funct3: u32,
+        funct7: u32,
+        rd: usize,
+        rs1: usize,
+        rs2: usize,
+    ) -> Result<(), Exception> {
+        self.check_fp(inst)?;
+        let rs3 = (funct7 >> 2) as usize;
+        let fmt = match funct7 & 0b11 {
+            0b00 => F32,
+            0b01 => F64,
+            _ => return Err(Exception::IllegalInstruction(inst.raw)),
+        };
+        let mut env = Env::new(self.rounding(inst, funct3)?);
+        let a = self.read_freg(fmt, rs1);
+        let b = self.read_freg(fmt, rs2);
+        let c = self.read_freg(fmt, rs3);
+        let negate = |x| fmt.with_sign(x, !fmt.sign(x));
+        let (a, c) = match inst.opcode {
+            0b1000011 => (a, c),
+            0b1000111 => (a, negate(c)),
+            0b1001011 => (negate(a), c),
+            _ => (negate(a), negate(c)),
+        };
+        let result = fmt.mul_add(&mut env, a, b, c);
+        self.write_freg(fmt, rd, result);
+        self.raise(env.flags);
+        Ok(())
+    }
+
+    /// Executes the Zicsr instructions.
+    fn execute_csr(
+        &mut self,
+        inst: &Instruction,
+        funct3: u32,
+        rd: usize,
+        rs1: usize,
+        imm: u32,
+    ) -> Result<(), Exception> {
+        let csr = (imm & 0xfff) as u16;
+        // the immediate forms use the rs1 field as a zero-extended value
+        let operand = if funct3 & 0b100 != 0 {
+            rs1 as u64
+        } else {
+            self.registers[rs1]
+        };
+
+        // csrrs and csrrc with a zero rs1 field only read
+        let writes = funct3 & 0b11 == 0b01 || rs1 != 0;
+        let fp = matches!(csr, FFLAGS | FRM | FCSR);
+        let tvm = self.csrs[MSTATUS as usize] & MSTATUS_TVM != 0;
+        if !csr::exists(csr, self.xlen)
+            || csr::privilege(csr) > self.privilege
+            || (writes && csr::is_read_only(csr))
+            || (fp && self.check_fp(inst).is_err())
+            || (csr == SATP && self.privilege == PRV_S && tvm)
+        {
+            return Err(Exception::IllegalInstruction(inst.raw));
+        }
+        let old = self.read_csr(csr);
+        if writes {
+            let new = match funct3 & 0b11 {
+                0b01 => operand,
+                0b10 => old | operand,
+                _ => old & !operand,
+            };
+            self.write_csr(csr, new);
+            let value = self.read_csr(csr);
+            if let Some(commit) = &mut self.commit {
+                commit.csrs.push((csr, value));
+            }
+        }
+        self.registers[rd] = old;
+        Ok(())
+    }
+
+    /// Reads a CSR. Unimplemented CSRs read as zero.
+    pub fn read_csr(&self, csr: u16) -> u64 {
+        match csr {
+            MCYCLE | CYCLE | TIME => self.wrap(self.cycle),
+            MCYCLEH | CYCLEH | TIMEH => self.cycle >> 32,
+            MINSTRET | INSTRET => self.wrap(self.instret),
+            MINSTRETH | INSTRETH => self.instret >> 32,
+            FFLAGS => self.csrs[FCSR as usize] & 0x1f,
+            FRM => self.csrs[FCSR as usize] >> 5,
+            SSTATUS => {
+                let mask = match self.xlen {
+                    Xlen::X32 => SSTATUS_MASK,
+                    Xlen::X64 => SSTATUS_MASK | MSTATUS_UXL_64,
+                };
+                self.csrs[MSTATUS as usize]
+                    & (mask | csr::mstatus_sd(self.xlen))
+            }
+            // only the delegated interrupts are visible
+            SIE | SIP => {
+                let machine = if csr == SIE { MIE } else { MIP };
+                self.csrs[machine as usize] & self.csrs[MIDELEG as usize]
+            }
+            _ => self.csrs[csr as usize],
+        }
+    }
+
+    /// Writes a CSR, keeping WARL fields legal. Writes to read-only and
+    /// unimplemented CSRs are ignored.
+    fn write_csr(&mut self, csr: u16, value: u64) {
+        let old = self.csrs[csr as usize];
+        let new = match csr {
+            MSTATUS | SSTATUS => {
+                let mask = match csr {
+                    MSTATUS => {
+                        SSTATUS_MASK
+                            | MSTATUS_MIE
+                            | MSTATUS_MPIE
+                            | MSTATUS_MPP
+                            | MSTATUS_MPRV
+                            | MSTATUS_TVM
+                            | MSTATUS_TSR
                     }
-                },
-                "unimp" => {
-                    if args.debug {
-                        println!("Reached an unimp instruction.");
+                    _ => SSTATUS_MASK,
+                };
+                let old = self.csrs[MSTATUS as usize];
+                let mut new = (old & !mask) | (value & mask);
+                // MPP only holds the modes that exist: M, S and U
+                if (new & MSTATUS_MPP) >> 11 == 0b10 {
+                    new = (new & !MSTATUS_MPP) | (old & MSTATUS_MPP);
+                }
+                let sd = csr::mstatus_sd(self.xlen);
+                self.csrs[MSTATUS as usize] =
+                    match new & MSTATUS_FS == MSTATUS_FS {
+                        true => new | sd,
+                        false => new & !sd,
+                    };
+                return;
+            }
+            // only direct (0) and vectored (1) modes exist
+            MTVEC | STVEC if value & 0b11 > 1 => {
+                (value & !0b11) | (old & 0b11)
+            }
+            MTVEC | STVEC => value,
+            // IALIGN is 16 because of the C extension
+            MEPC | SEPC => value & !1,
+            MIE => value & MIE_MASK,
+            SIE => {
+                let delegated = self.csrs[MIDELEG as usize];
+                let mie = self.csrs[MIE as usize];
+                self.csrs[MIE as usize] =
+                    (mie & !delegated) | (value & delegated);
+                return;
+            }
+            MEDELEG => value & MEDELEG_MASK,
+            MIDELEG => value & SIE_MASK,
+            PMPCFG0..=PMPCFG3 => {
+                // locked entries keep their configuration, and W needs R
+                let mut new = 0;
+                for shift in (0..self.xlen.bits()).step_by(8) {
+                    let old = (old >> shift) as u8;
+                    let mut cfg = (value >> shift) as u8
+                        & (PMP_R | PMP_W | PMP_X | PMP_A | PMP_L);
+                    if old & PMP_L != 0 {
+                        cfg = old;
+                    } else if cfg & PMP_R == 0 {
+                        cfg &= !PMP_W;
                     }
-                    ret = -3;
-                    break;
+                    new |= (cfg as u64) << shift;
                 }
-                _ => {}
+                self.csrs[csr as usize] = new;
+                self.update_pmp();
+                return;
             }
-        }
-        ret
+            PMPADDR0..=PMPADDR15 => {
+                // so does the address of a locked entry, or the one below
+                // a locked TOR entry, which it's the bottom of
+                let i = (csr - PMPADDR0) as usize;
+                let next = match i + 1 {
+                    pmp::ENTRIES => 0,
+                    next => self.pmp_cfg(next),
+                };
+                if self.pmp_cfg(i) & PMP_L != 0
+                    || (next & PMP_L != 0 && next & PMP_A == PMP_TOR)
+                {
+                    return;
+                }
+                // addresses are 34 bits wide on RV32 and 56 on RV64,
+                // stored shifted right by two
+                self.csrs[csr as usize] = match self.xlen {
+                    Xlen::X32 => value & 0xffff_ffff,
+                    Xlen::X64 => value & ((1 << 54) - 1),
+                };
+                self.update_pmp();
+                return;
+            }
+            // RV64 would need Sv39 for anything but bare, so `satp` stays
+            // zero there
+            SATP if self.xlen == Xlen::X64 => return,
+            SATP => {
+                self.tlb.flush();
+                value & (SATP_SV32 | SATP_PPN)
+            }
+            MSCRATCH | MCAUSE | MTVAL | SSCRATCH | SCAUSE | STVAL => value,
+            FFLAGS | FRM | FCSR => {
+                let fcsr = self.csrs[FCSR as usize];
+                self.csrs[FCSR as usize] = match csr {
+                    FFLAGS => fcsr & !0x1f | value & 0x1f,
+                    FRM => fcsr & 0x1f | (value & 0b111) << 5,
+                    _ => value & 0xff,
+                };
+                self.dirty_fp();
+                return;
+            }
+            // on RV32 the counters are written a half at a time
+            MCYCLE | MINSTRET | MCYCLEH | MINSTRETH => {
+                let counter = match csr {
+                    MCYCLE | MCYCLEH => &mut self.cycle,
+                    _ => &mut self.instret,
+                };
+                *counter = match (csr, self.xlen) {
+                    (MCYCLE | MINSTRET, Xlen::X64) => value,
+                    (MCYCLE | MINSTRET, _) => {
+                        (*counter & !0xffff_ffff) | value
+                    }
+                    _ => (*counter & 0xffff_ffff) | value << 32,
+                };
+                return;
+            }
+            _ => return,
+        };
+        self.csrs[csr as usize] = new;
+    }
+
+    /// The configuration byte of PMP entry `i`, out of the `pmpcfg` CSR
+    /// that packs it.
+    fn pmp_cfg(&self, i: usize) -> u8 {
+        let per_csr = self.xlen.bits() as usize / 8;
+        // on RV64 only the even-numbered ones exist
+        let csr = PMPCFG0 as usize + i / per_csr * (per_csr / 4);
+        (self.csrs[csr] >> (8 * (i % per_csr))) as u8
+    }
+
+    /// Decodes the PMP regions again after a write to their CSRs.
+    fn update_pmp(&mut self) {
+        let cfgs = std::array::from_fn(|i| self.pmp_cfg(i));
+        let addrs =
+            std::array::from_fn(|i| self.csrs[PMPADDR0 as usize + i]);
+        self.pmp.update(&cfgs, &addrs);
     }
 
     fn sign_extend(data: u32, size: u32) -> u32 {
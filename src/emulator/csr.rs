@@ -0,0 +1,246 @@
+use super::cpu::Xlen;
+
+// Floating-point control and status: `fflags` and `frm` are fields of
+// `fcsr`
+pub const FFLAGS: u16 = 0x001;
+pub const FRM: u16 = 0x002;
+pub const FCSR: u16 = 0x003;
+
+// Supervisor trap setup: `sstatus` and `sie` are views of `mstatus` and
+// `mie`
+pub const SSTATUS: u16 = 0x100;
+pub const SIE: u16 = 0x104;
+pub const STVEC: u16 = 0x105;
+
+// Supervisor trap handling
+pub const SSCRATCH: u16 = 0x140;
+pub const SEPC: u16 = 0x141;
+pub const SCAUSE: u16 = 0x142;
+pub const STVAL: u16 = 0x143;
+pub const SIP: u16 = 0x144;
+
+// Supervisor address translation and protection
+pub const SATP: u16 = 0x180;
+
+// Machine information registers
+pub const MVENDORID: u16 = 0xf11;
+pub const MARCHID: u16 = 0xf12;
+pub const MIMPID: u16 = 0xf13;
+pub const MHARTID: u16 = 0xf14;
+
+// Machine trap setup
+pub const MSTATUS: u16 = 0x300;
+pub const MISA: u16 = 0x301;
+pub const MEDELEG: u16 = 0x302;
+pub const MIDELEG: u16 = 0x303;
+pub const MIE: u16 = 0x304;
+pub const MTVEC: u16 = 0x305;
+
+// Machine trap handling
+pub const MSCRATCH: u16 = 0x340;
+pub const MEPC: u16 = 0x341;
+pub const MCAUSE: u16 = 0x342;
+pub const MTVAL: u16 = 0x343;
+pub const MIP: u16 = 0x344;
+
+// Physical memory protection. On RV64 only the even-numbered `pmpcfg`
+// CSRs exist, each holding eight entries' configuration.
+pub const PMPCFG0: u16 = 0x3a0;
+pub const PMPCFG3: u16 = 0x3a3;
+pub const PMPADDR0: u16 = 0x3b0;
+pub const PMPADDR15: u16 = 0x3bf;
+
+// Machine counters
+pub const MCYCLE: u16 = 0xb00;
+pub const MINSTRET: u16 = 0xb02;
+pub const MCYCLEH: u16 = 0xb80;
+pub const MINSTRETH: u16 = 0xb82;
+
+// Unprivileged counters, read-only shadows of the machine ones
+pub const CYCLE: u16 = 0xc00;
+pub const TIME: u16 = 0xc01;
+pub const INSTRET: u16 = 0xc02;
+pub const CYCLEH: u16 = 0xc80;
+pub const TIMEH: u16 = 0xc81;
+pub const INSTRETH: u16 = 0xc82;
+
+/// Privilege levels, as encoded in `mstatus.MPP`.
+pub const PRV_U: u32 = 0;
+pub const PRV_S: u32 = 1;
+pub const PRV_M: u32 = 3;
+
+pub const MSTATUS_SIE: u64 = 1 << 1;
+pub const MSTATUS_MIE: u64 = 1 << 3;
+pub const MSTATUS_SPIE: u64 = 1 << 5;
+pub const MSTATUS_MPIE: u64 = 1 << 7;
+pub const MSTATUS_SPP: u64 = 1 << 8;
+pub const MSTATUS_MPP: u64 = 0b11 << 11;
+/// The state of the floating-point unit: off, initial, clean or dirty.
+pub const MSTATUS_FS: u64 = 0b11 << 13;
+pub const MSTATUS_FS_INITIAL: u64 = 0b01 << 13;
+/// Machine-mode loads and stores translate and check permissions as if
+/// at the privilege level in MPP.
+pub const MSTATUS_MPRV: u64 = 1 << 17;
+/// Supervisor mode may access user pages.
+pub const MSTATUS_SUM: u64 = 1 << 18;
+/// Loads may read executable pages that aren't readable.
+pub const MSTATUS_MXR: u64 = 1 << 19;
+/// Traps supervisor-mode accesses to `satp` and `sfence.vma`.
+pub const MSTATUS_TVM: u64 = 1 << 20;
+/// Traps `sret` in supervisor mode.
+pub const MSTATUS_TSR: u64 = 1 << 22;
+/// U-mode XLEN on RV64, fixed at 64 bits.
+pub const MSTATUS_UXL_64: u64 = 0b10 << 32;
+
+/// The fields of `mstatus` that `sstatus` shows, apart from SD and UXL.
+pub const SSTATUS_MASK: u64 = MSTATUS_SIE
+    | MSTATUS_SPIE
+    | MSTATUS_SPP
+    | MSTATUS_FS
+    | MSTATUS_SUM
+    | MSTATUS_MXR;
+
+/// Supervisor software, timer and external interrupt enable/pending
+/// bits.
+pub const SIE_MASK: u64 = 1 << 1 | 1 << 5 | 1 << 9;
+/// Software, timer and external interrupt enable/pending bits, for
+/// machine and supervisor mode.
+pub const MIE_MASK: u64 = SIE_MASK | 1 << 3 | 1 << 7 | 1 << 11;
+
+/// The exceptions that can be delegated to supervisor mode: all of them
+/// but an `ecall` from machine mode.
+pub const MEDELEG_MASK: u64 = 0xb3ff;
+
+/// `satp.MODE` on RV32, set for Sv32 translation.
+pub const SATP_SV32: u64 = 1 << 31;
+/// The physical page number of the root page table on RV32.
+pub const SATP_PPN: u64 = 0x3f_ffff;
+
+/// `mstatus.SD`, the top bit, summarizes a dirty FS.
+pub fn mstatus_sd(xlen: Xlen) -> u64 {
+    1 << (xlen.bits() - 1)
+}
+
+/// MXL in the top two bits, extensions I, M, A, F, D and C, plus
+/// supervisor and user mode.
+pub fn misa(xlen: Xlen) -> u64 {
+    let mxl = match xlen {
+        Xlen::X32 => 1 << 30,
+        Xlen::X64 => 2 << 62,
+    };
+    mxl | 1
+        | 1 << 2
+        | 1 << 3
+        | 1 << 5
+        | 1 << 8
+        | 1 << 12
+        | 1 << 18
+        | 1 << 20
+}
+
+/// CSRs with the top two address bits set can't be written.
+pub fn is_read_only(csr: u16) -> bool {
+    csr >> 10 == 0b11
+}
+
+/// The lowest privilege level that may access a CSR.
+pub fn privilege(csr: u16) -> u32 {
+    (csr as u32 >> 8) & 0b11
+}
+
+/// Whether a CSR is implemented; accessing any other one is illegal.
+/// The upper halves of the counters and the odd-numbered `pmpcfg` CSRs
+/// only exist on RV32.
+pub fn exists(csr: u16, xlen: Xlen) -> bool {
+    if matches!(csr, MCYCLEH | MINSTRETH | CYCLEH | TIMEH | INSTRETH) {
+        return xlen == Xlen::X32;
+    }
+    if let PMPCFG0..=PMPCFG3 = csr {
+        return xlen == Xlen::X32 || csr & 1 == 0;
+    }
+    matches!(
+        csr,
+        FFLAGS
+            | FRM
+            | FCSR
+            | MVENDORID
+            | MARCHID
+            | MIMPID
+            | MHARTID
+            | SSTATUS
+            | SIE
+            | STVEC
+            | SSCRATCH
+            | SEPC
+            | SCAUSE
+            | STVAL
+            | SIP
+            | SATP
+            | MSTATUS
+            | MISA
+            | MEDELEG
+            | MIDELEG
+            | MIE
+            | MTVEC
+            | MSCRATCH
+            | MEPC
+            | MCAUSE
+            | MTVAL
+            | MIP
+            | (PMPADDR0..=PMPADDR15)
+            | MCYCLE
+            | MINSTRET
+            | CYCLE
+            | TIME
+            | INSTRET
+    )
+}
+
+/// Returns the assembler name of a CSR, or its number in hex.
+pub fn name(csr: u16) -> String {
+    let name = match csr {
+        FFLAGS => "fflags",
+        FRM => "frm",
+        FCSR => "fcsr",
+        MVENDORID => "mvendorid",
+        MARCHID => "marchid",
+        MIMPID => "mimpid",
+        MHARTID => "mhartid",
+        SSTATUS => "sstatus",
+        SIE => "sie",
+        STVEC => "stvec",
+        SSCRATCH => "sscratch",
+        SEPC => "sepc",
+        SCAUSE => "scause",
+        STVAL => "stval",
+        SIP => "sip",
+        SATP => "satp",
+        MSTATUS => "mstatus",
+        MISA => "misa",
+        MEDELEG => "medeleg",
+        MIDELEG => "mideleg",
+        MIE => "mie",
+        MTVEC => "mtvec",
+        MSCRATCH => "mscratch",
+        MEPC => "mepc",
+        MCAUSE => "mcause",
+        MTVAL => "mtval",
+        MIP => "mip",
+        MCYCLE => "mcycle",
+        MINSTRET => "minstret",
+        MCYCLEH => "mcycleh",
+        MINSTRETH => "minstreth",
+        CYCLE => "cycle",
+        TIME => "time",
+        INSTRET => "instret",
+        CYCLEH => "cycleh",
+        TIMEH => "timeh",
+        INSTRETH => "instreth",
+        PMPCFG0..=PMPCFG3 => return format!("pmpcfg{}", csr - PMPCFG0),
+        PMPADDR0..=PMPADDR15 => {
+            return format!("pmpaddr{}", csr - PMPADDR0)
+        }
+        _ => return format!("{:#x}", csr),
+    };
+    String::from(name)
+}
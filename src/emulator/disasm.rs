@@ -0,0 +1,812 @@
+//! Turns decoded instructions back into assembly text, for tracing and
+//! for listing programs without running them. Executing an instruction
+//! doesn't need any of this.
+
+use super::cpu::{Cpu, ALIASES, FP_ALIASES};
+use super::csr;
+use super::error::LoadError;
+use super::instruction::*;
+use super::symbols::Symbols;
+use elf_rs::{ElfFile, SectionHeaderFlags, SectionType};
+use std::fmt::{self, Write};
+use std::path::Path;
+
+/// How instructions are spelled. The default is the plain base
+/// instruction set with numeric register names, like
+/// `objdump -M no-aliases`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Syntax {
+    /// Name registers by their ABI role (`sp`, `a0`) instead of `x2`,
+    /// `x10`.
+    pub aliases: bool,
+    /// Print pseudo-instructions (`li`, `mv`, `ret`, `beqz`...) where an
+    /// instruction is one.
+    pub pseudo: bool,
+}
+
+/// A register operand.
+struct Reg(usize, bool);
+
+impl fmt::Display for Reg {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.1 {
+            true => f.write_str(ALIASES[self.0]),
+            false => write!(f, "x{}", self.0),
+        }
+    }
+}
+
+/// A floating-point register operand.
+struct FReg(usize, bool);
+
+impl fmt::Display for FReg {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.1 {
+            true => f.write_str(FP_ALIASES[self.0]),
+            false => write!(f, "f{}", self.0),
+        }
+    }
+}
+
+/// The names of the rounding modes, by `rm` encoding.
+const ROUNDING: [&str; 8] =
+    ["rne", "rtz", "rdn", "rup", "rmm", "", "", "dyn"];
+
+/// The assembly text of `inst`, which sits at `pc`. Encodings the
+/// emulator doesn't implement come out as `unimp`.
+pub fn disassemble(inst: &Instruction, pc: u64) -> String {
+    disassemble_with(inst, pc, Syntax::default())
+}
+
+/// Like [`disassemble`], in the given syntax.
+pub fn disassemble_with(
+    inst: &Instruction,
+    pc: u64,
+    syntax: Syntax,
+) -> String {
+    text(inst, pc, syntax).unwrap_or_else(|| String::from("unimp"))
+}
+
+/// Where the branch or `jal` at `pc` goes. Other instructions, `jalr`
+/// included, have no target that can be known without running them.
+pub fn target(inst: &Instruction, pc: u64) -> Option<u64> {
+    match inst.type_data {
+        InstTypeData::B { imm, funct3, .. } if funct3 & 0b110 != 0b010 => {
+            Some(pc.wrapping_add(imm as i32 as u64))
+        }
+        InstTypeData::J { imm, .. } => {
+            Some(pc.wrapping_add(imm as i32 as u64))
+        }
+        _ => None,
+    }
+}
+
+/// An objdump-style listing of every executable section of the ELF file
+/// at `path`, labelled with its symbols. The program isn't loaded or run.
+pub fn listing(
+    path: impl AsRef<Path>,
+    syntax: Syntax,
+) -> Result<String, LoadError> {
+    let path = path.as_ref();
+    let buf = std::fs::read(path)?;
+    let elf = Cpu::parse_elf(&buf)?;
+    let xlen = Cpu::elf_xlen(&elf);
+    let symbols = Symbols::from_elf(&elf);
+    let mut out = format!(
+        "{}:     file format elf{}-littleriscv\n",
+        path.display(),
+        xlen.bits()
+    );
+    for section in elf.section_header_iter().filter(|shdr| {
+        shdr.sh_type() == SectionType::SHT_PROGBITS
+            && shdr.flags().contains(SectionHeaderFlags::SHF_EXECINSTR)
+    }) {
+        let name = String::from_utf8_lossy(section.section_name());
+        writeln!(out, "\nDisassembly of section {}:", name).unwrap();
+        let code = section.content();
+        let mut offset = 0;
+        while offset + 2 <= code.len() {
+            let pc = section.addr() + offset as u64;
+            if let Some(sym) = symbols.at(pc) {
+                writeln!(out, "\n{:08x} <{}>:", pc, sym.name).unwrap();
+            }
+            let half =
+                u16::from_le_bytes([code[offset], code[offset + 1]]);
+            let (raw, size) = match code.get(offset..offset + 4) {
+                Some(word) if half & 0b11 == 0b11 => {
+                    (u32::from_le_bytes(word.try_into().unwrap()), 4)
+                }
+                _ => (half as u32, 2),
+            };
+            let inst = Cpu::decode(raw, xlen);
+            let raw = match size {
+                4 => format!("{:08x}", raw),
+                _ => format!("{:04x}", raw),
+            };
+            write!(
+                out,
+                "{:8x}:\t{:<18}\t{}",
+                pc,
+                raw,
+                disassemble_with(&inst, pc, syntax)
+            )
+            .unwrap();
+            if let Some((sym, off)) =
+                target(&inst, pc).and_then(|addr| symbols.lookup(addr))
+            {
+                match off {
+                    0 => write!(out, " <{}>", sym.name),
+                    _ => write!(out, " <{}+{:#x}>", sym.name, off),
+                }
+                .unwrap();
+            }
+            out.push('\n');
+            offset += size;
+        }
+    }
+    Ok(out)
+}
+
+fn text(inst: &Instruction, pc: u64, syntax: Syntax) -> Option<String> {
+    let r = |n: usize| Reg(n, syntax.aliases);
+    let text = match inst.type_data {
+        InstTypeData::R {
+            rd,
+            funct3,
+            rs1,
+            rs2,
+            funct7,
+        } => {
+            if inst.opcode == 0b0101111 {
+                return atomic(funct3, funct7, rd, rs1, rs2, syntax);
+            }
+            if inst.opcode == 0b1010011 {
+                return float(funct3, funct7, rd, rs1, rs2, syntax);
+            }
+            if inst.opcode & 0b1110011 == 0b1000011 {
+                let regs = [rd, rs1, rs2, funct7 as usize >> 2];
+                return fused(inst.opcode, funct3, funct7, regs, syntax);
+            }
+            if inst.opcode == 0b0111011 {
+                return word(funct3, funct7, rd, rs1, rs2, syntax);
+            }
+            let name = match (funct3, funct7) {
+                (0x0, 0x01) => "mul",
+                (0x1, 0x01) => "mulh",
+                (0x2, 0x01) => "mulhsu",
+                (0x3, 0x01) => "mulhu",
+                (0x4, 0x01) => "div",
+                (0x5, 0x01) => "divu",
+                (0x6, 0x01) => "rem",
+                (0x7, 0x01) => "remu",
+                (0x0, 0x00) => "add",
+                (0x0, 0x20) => "sub",
+                (0x4, 0x00) => "xor",
+                (0x6, 0x00) => "or",
+                (0x7, 0x00) => "and",
+                (0x1, 0x00) => "sll",
+                (0x5, 0x00) => "srl",
+                (0x5, 0x20) => "sra",
+                (0x2, 0x00) => "slt",
+                (0x3, 0x00) => "sltu",
+                (0x2, 0x10) => "sh1add",
+                (0x4, 0x10) => "sh2add",
+                (0x6, 0x10) => "sh3add",
+                (0x4, 0x20) => "xnor",
+                (0x6, 0x20) => "orn",
+                (0x7, 0x20) => "andn",
+                (0x1, 0x05) => "clmul",
+                (0x2, 0x05) => "clmulr",
+                (0x3, 0x05) => "clmulh",
+                (0x4, 0x05) => "min",
+                (0x5, 0x05) => "minu",
+                (0x6, 0x05) => "max",
+                (0x7, 0x05) => "maxu",
+                (0x1, 0x30) => "rol",
+                (0x5, 0x30) => "ror",
+                (0x1, 0x24) => "bclr",
+                (0x5, 0x24) => "bext",
+                (0x1, 0x34) => "binv",
+                (0x1, 0x14) => "bset",
+                (0x4, 0x04) if rs2 == 0 => {
+                    return Some(format!("zext.h  {},{}", r(rd), r(rs1)));
+                }
+                _ => return None,
+            };
+            let pseudo = match (name, rs1, rs2) {
+                ("add", 0, _) => Some(("mv", rs2)),
+                ("sub", 0, _) => Some(("neg", rs2)),
+                ("sltu", 0, _) => Some(("snez", rs2)),
+                ("slt", 0, _) => Some(("sgtz", rs2)),
+                ("slt", _, 0) => Some(("sltz", rs1)),
+                _ => None,
+            };
+            match pseudo.filter(|_| syntax.pseudo) {
+                Some((name, rs)) => {
+                    format!("{:<7} {},{}", name, r(rd), r(rs))
+                }
+                None => {
+                    format!("{:<7} {},{},{}", name, r(rd), r(rs1), r(rs2))
+                }
+            }
+        }
+        InstTypeData::B {
+            imm,
+            funct3,
+            rs1,
+            rs2,
+        } => {
+            let name = match funct3 {
+                0x0 => "beq",
+                0x1 => "bne",
+                0x4 => "blt",
+                0x5 => "bge",
+                0x6 => "bltu",
+                0x7 => "bgeu",
+                _ => return None,
+            };
+            let target = pc.wrapping_add(imm as i32 as u64);
+            let pseudo = match (name, rs1, rs2) {
+                ("beq", _, 0) => Some(("beqz", rs1)),
+                ("bne", _, 0) => Some(("bnez", rs1)),
+                ("blt", _, 0) => Some(("bltz", rs1)),
+                ("bge", _, 0) => Some(("bgez", rs1)),
+                ("blt", 0, _) => Some(("bgtz", rs2)),
+                ("bge", 0, _) => Some(("blez", rs2)),
+                _ => None,
+            };
+            match pseudo.filter(|_| syntax.pseudo) {
+                Some((name, rs)) => {
+                    format!("{:<7} {},{:08x}", name, r(rs), target)
+                }
+                None => format!(
+                    "{:<7} {},{},{:08x}",
+                    name,
+                    r(rs1),
+                    r(rs2),
+                    target
+                ),
+            }
+        }
+        InstTypeData::J { rd, imm } => {
+            let target = pc.wrapping_add(imm as i32 as u64);
+            match rd {
+                0 if syntax.pseudo => format!("j       {:08x}", target),
+                1 if syntax.pseudo => format!("jal     {:08x}", target),
+                _ => format!("jal     {},{:08x}", r(rd), target),
+            }
+        }
+        InstTypeData::I {
+            rd,
+            funct3,
+            rs1,
+            imm,
+        } => match inst.opcode {
+            0b0010011 => {
+                let name = match funct3 {
+                    0x0 if rd == 0
+                        && rs1 == 0
+                        && imm == 0
+                        && syntax.pseudo =>
+                    {
+                        return Some(String::from("nop"))
+                    }
+                    0x0 => "addi",
+                    0x4 => "xori",
+                    0x6 => "ori",
+                    0x7 => "andi",
+                    0x2 => "slti",
+                    0x3 => "sltiu",
+                    _ => {
+                        let imm = imm & 0xfff;
+                        let unary = match (funct3, imm) {
+                            (0x1, 0x600) => Some("clz"),
+                            (0x1, 0x601) => Some("ctz"),
+                            (0x1, 0x602) => Some("cpop"),
+                            (0x1, 0x604) => Some("sext.b"),
+                            (0x1, 0x605) => Some("sext.h"),
+                            (0x5, 0x287) => Some("orc.b"),
+                            (0x5, 0x698 | 0x6b8) => Some("rev8"),
+                            _ => None,
+                        };
+                        if let Some(name) = unary {
+                            return Some(format!(
+                                "{:<7} {},{}",
+                                name,
+                                r(rd),
+                                r(rs1)
+                            ));
+                        }
+                        let name = match (funct3, imm >> 6) {
+                            (0x1, 0) => "slli",
+                            (0x5, 0) => "srli",
+                            (0x5, 0b010000) => "srai",
+                            (0x1, 0b010010) => "bclri",
+                            (0x5, 0b010010) => "bexti",
+                            (0x1, 0b011010) => "binvi",
+                            (0x1, 0b001010) => "bseti",
+                            (0x5, 0b011000) => "rori",
+                            _ => return None,
+                        };
+                        return Some(format!(
+                            "{:<7} {},{},{:#x}",
+                            name,
+                            r(rd),
+                            r(rs1),
+                            imm & 0b111111
+                        ));
+                    }
+                };
+                let imm = imm as i32;
+                match (name, rs1, imm) {
+                    ("addi", 0, _) if syntax.pseudo => {
+                        format!("li      {},{}", r(rd), imm)
+                    }
+                    ("addi", _, 0) if syntax.pseudo => {
+                        format!("mv      {},{}", r(rd), r(rs1))
+                    }
+                    ("xori", _, -1) if syntax.pseudo => {
+                        format!("not     {},{}", r(rd), r(rs1))
+                    }
+                    ("sltiu", _, 1) if syntax.pseudo => {
+                        format!("seqz    {},{}", r(rd), r(rs1))
+                    }
+                    _ => {
+                        format!("{:<7} {},{},{}", name, r(rd), r(rs1), imm)
+                    }
+                }
+            }
+            0b0011011 => {
+                let name = match (funct3, imm >> 5 & 0b1111111) {
+                    (0x0, _) if imm == 0 && syntax.pseudo => {
+                        return Some(format!(
+                            "sext.w  {},{}",
+                            r(rd),
+                            r(rs1)
+                        ));
+                    }
+                    (0x0, _) => {
+                        return Some(format!(
+                            "addiw   {},{},{}",
+                            r(rd),
+                            r(rs1),
+                            imm as i32
+                        ));
+                    }
+                    (0x1, 0) => "slliw",
+                    (0x5, 0) => "srliw",
+                    (0x5, 0b0100000) => "sraiw",
+                    (0x5, 0b0110000) => "roriw",
+                    (0x1, 0b0000100 | 0b0000101) => {
+                        return Some(format!(
+                            "slli.uw {},{},{:#x}",
+                            r(rd),
+                            r(rs1),
+                            imm & 0b111111
+                        ));
+                    }
+                    (0x1, 0b0110000) => {
+                        let name = match imm & 0b11111 {
+                            0 => "clzw",
+                            1 => "ctzw",
+                            2 => "cpopw",
+                            _ => return None,
+                        };
+                        return Some(format!(
+                            "{:<7} {},{}",
+                            name,
+                            r(rd),
+                            r(rs1)
+                        ));
+                    }
+                    _ => return None,
+                };
+                format!(
+                    "{:<7} {},{},{:#x}",
+                    name,
+                    r(rd),
+                    r(rs1),
+                    imm & 0b11111
+                )
+            }
+            0b0000011 => {
+                let name = match funct3 {
+                    0x0 => "lb",
+                    0x1 => "lh",
+                    0x2 => "lw",
+                    0x3 => "ld",
+                    0x4 => "lbu",
+                    0x5 => "lhu",
+                    0x6 => "lwu",
+                    _ => return None,
+                };
+                format!("{:<7} {},{}({})", name, r(rd), imm as i32, r(rs1))
+            }
+            0b0000111 => {
+                let name = match funct3 {
+                    0b010 => "flw",
+                    0b011 => "fld",
+                    _ => return None,
+                };
+                let rd = FReg(rd, syntax.aliases);
+                format!("{:<7} {},{}({})", name, rd, imm as i32, r(rs1))
+            }
+            0b1100111 if funct3 == 0 => match (rd, rs1, imm) {
+                (0, 1, 0) if syntax.pseudo => String::from("ret"),
+                (0, _, 0) if syntax.pseudo => {
+                    format!("jr      {}", r(rs1))
+                }
+                (1, _, 0) if syntax.pseudo => {
+                    format!("jalr    {}", r(rs1))
+                }
+                (1, _, _) if syntax.pseudo => {
+                    format!("jalr    {}({})", imm as i32, r(rs1))
+                }
+                _ => {
+                    format!("jalr    {},{}({})", r(rd), imm as i32, r(rs1))
+                }
+            },
+            0b1110011 => return system(funct3, rd, rs1, imm, syntax),
+            _ => return None,
+        },
+        InstTypeData::S {
+            imm,
+            funct3,
+            rs1,
+            rs2,
+        } => {
+            if inst.opcode == 0b0100111 {
+                let name = match funct3 {
+                    0b010 => "fsw",
+                    0b011 => "fsd",
+                    _ => return None,
+                };
+                let rs2 = FReg(rs2, syntax.aliases);
+                return Some(format!(
+                    "{:<7} {},{}({})",
+                    name,
+                    rs2,
+                    imm as i32,
+                    r(rs1)
+                ));
+            }
+            let name = match funct3 {
+                0x0 => "sb",
+                0x1 => "sh",
+                0x2 => "sw",
+                0x3 => "sd",
+                _ => return None,
+            };
+            format!("{:<7} {},{}({})", name, r(rs2), imm as i32, r(rs1))
+        }
+        InstTypeData::U { rd, imm } => {
+            let name = match inst.opcode {
+                0b0110111 => "lui",
+                _ => "auipc",
+            };
+            format!("{:<7} {},{:#x}", name, r(rd), imm)
+        }
+        InstTypeData::Fence if (inst.raw >> 12) & 0b111 == 0b001 => {
+            String::from("fence.i")
+        }
+        InstTypeData::Fence => {
+            let set = |bits: u32| {
+                let set: String = "iorw"
+                    .chars()
+                    .enumerate()
+                    .filter(|&(i, _)| bits & (0b1000 >> i) != 0)
+                    .map(|(_, c)| c)
+                    .collect();
+                set
+            };
+            let (pred, succ) =
+                ((inst.raw >> 24) & 0xf, (inst.raw >> 20) & 0xf);
+            match (pred, succ) {
+                (0, 0) => String::from("fence"),
+                _ => format!("fence   {},{}", set(pred), set(succ)),
+            }
+        }
+        InstTypeData::Unimp => return None,
+    };
+    Some(text)
+}
+
+/// The RV64 word instructions on the OP-32 opcode, with those of the
+/// bit-manipulation extensions.
+fn word(
+    funct3: u32,
+    funct7: u32,
+    rd: usize,
+    rs1: usize,
+    rs2: usize,
+    syntax: Syntax,
+) -> Option<String> {
+    let r = |n: usize| Reg(n, syntax.aliases);
+    let name = match (funct3, funct7) {
+        (0x0, 0x00) => "addw",
+        (0x0, 0x20) if rs1 == 0 && syntax.pseudo => {
+            return Some(format!("negw    {},{}", r(rd), r(rs2)));
+        }
+        (0x0, 0x20) => "subw",
+        (0x1, 0x00) => "sllw",
+        (0x5, 0x00) => "srlw",
+        (0x5, 0x20) => "sraw",
+        (0x1, 0x30) => "rolw",
+        (0x5, 0x30) => "rorw",
+        (0x0, 0x04) if rs2 == 0 && syntax.pseudo => {
+            return Some(format!("zext.w  {},{}", r(rd), r(rs1)));
+        }
+        (0x0, 0x04) => "add.uw",
+        (0x2, 0x10) => "sh1add.uw",
+        (0x4, 0x10) => "sh2add.uw",
+        (0x6, 0x10) => "sh3add.uw",
+        (0x4, 0x04) if rs2 == 0 => {
+            return Some(format!("zext.h  {},{}", r(rd), r(rs1)));
+        }
+        (0x0, 0x01) => "mulw",
+        (0x4, 0x01) => "divw",
+        (0x5, 0x01) => "divuw",
+        (0x6, 0x01) => "remw",
+        (0x7, 0x01) => "remuw",
+        _ => return None,
+    };
+    Some(format!("{:<7} {},{},{}", name, r(rd), r(rs1), r(rs2)))
+}
+
+fn atomic(
+    funct3: u32,
+    funct7: u32,
+    rd: usize,
+    rs1: usize,
+    rs2: usize,
+    syntax: Syntax,
+) -> Option<String> {
+    let size = match funct3 {
+        0b010 => "w",
+        0b011 => "d",
+        _ => return None,
+    };
+    let r = |n: usize| Reg(n, syntax.aliases);
+    let ordering = match funct7 & 0b11 {
+        0b00 => "",
+        0b01 => ".rl",
+        0b10 => ".aq",
+        _ => ".aqrl",
+    };
+    let name = match funct7 >> 2 {
+        0b00010 => {
+            let name = format!("lr.{}{}", size, ordering);
+            return Some(format!("{:<7} {},({})", name, r(rd), r(rs1)));
+        }
+        0b00011 => "sc",
+        0b00001 => "amoswap",
+        0b00000 => "amoadd",
+        0b00100 => "amoxor",
+        0b01100 => "amoand",
+        0b01000 => "amoor",
+        0b10000 => "amomin",
+        0b10100 => "amomax",
+        0b11000 => "amominu",
+        0b11100 => "amomaxu",
+        _ => return None,
+    };
+    let name = format!("{}.{}{}", name, size, ordering);
+    Some(format!("{:<7} {},{},({})", name, r(rd), r(rs2), r(rs1)))
+}
+
+/// The OP-FP instructions, the low bits of `funct7` giving the format.
+fn float(
+    funct3: u32,
+    funct7: u32,
+    rd: usize,
+    rs1: usize,
+    rs2: usize,
+    syntax: Syntax,
+) -> Option<String> {
+    let r = |n: usize| Reg(n, syntax.aliases).to_string();
+    let f = |n: usize| FReg(n, syntax.aliases).to_string();
+    let fmt = match funct7 & 0b11 {
+        0b00 => "s",
+        0b01 => "d",
+        _ => return None,
+    };
+    let other = match fmt {
+        "s" => "d",
+        _ => "s",
+    };
+    let int = ["w", "wu", "l", "lu"].get(rs2).copied();
+    // the name, operands, and the rm field's default when it has one
+    let (name, operands, rm) = match funct7 >> 2 {
+        0b00000..=0b00011 => {
+            let name =
+                ["fadd", "fsub", "fmul", "fdiv"][funct7 as usize >> 2];
+            (name.to_string(), vec![f(rd), f(rs1), f(rs2)], Some(0b111))
+        }
+        0b01011 if rs2 == 0 => {
+            (String::from("fsqrt"), vec![f(rd), f(rs1)], Some(0b111))
+        }
+        0b00100 => {
+            let (name, pseudo) = match funct3 {
+                0b000 => ("fsgnj", "fmv"),
+                0b001 => ("fsgnjn", "fneg"),
+                0b010 => ("fsgnjx", "fabs"),
+                _ => return None,
+            };
+            match rs1 == rs2 && syntax.pseudo {
+                true => (pseudo.to_string(), vec![f(rd), f(rs1)], None),
+                false => {
+                    (name.to_string(), vec![f(rd), f(rs1), f(rs2)], None)
+                }
+            }
+        }
+        0b00101 => {
+            let name = match funct3 {
+                0b000 => "fmin",
+                0b001 => "fmax",
+                _ => return None,
+            };
+            (name.to_string(), vec![f(rd), f(rs1), f(rs2)], None)
+        }
+        // narrowing rounds, widening is exact
+        0b01000 if rs2 == (fmt == "s") as usize => {
+            let rm = match fmt {
+                "s" => 0b111,
+                _ => 0b000,
+            };
+            let name = format!("fcvt.{}.{}", fmt, other);
+            return with_rounding(
+                &name,
+                format!("{},{}", f(rd), f(rs1)),
+                funct3,
+                rm,
+            );
+        }
+        0b10100 => {
+            let name = match funct3 {
+                0b010 => "feq",
+                0b001 => "flt",
+                0b000 => "fle",
+                _ => return None,
+            };
+            (name.to_string(), vec![r(rd), f(rs1), f(rs2)], None)
+        }
+        0b11000 => {
+            let name = format!("fcvt.{}", int?);
+            (name, vec![r(rd), f(rs1)], Some(0b111))
+        }
+        0b11010 => {
+            let name = format!("fcvt.{}.{}", fmt, int?);
+            // converting a word to a double is exact
+            let rm = match (fmt, rs2) {
+                ("d", 0 | 1) => 0b000,
+                _ => 0b111,
+            };
+            return with_rounding(
+                &name,
+                format!("{},{}", f(rd), r(rs1)),
+                funct3,
+                rm,
+            );
+        }
+        0b11100 if rs2 == 0 && funct3 == 0b000 => {
+            let name = match fmt {
+                "s" => "fmv.x.w",
+                _ => "fmv.x.d",
+            };
+            return Some(format!("{} {},{}", name, r(rd), f(rs1)));
+        }
+        0b11100 if rs2 == 0 && funct3 == 0b001 => {
+            (String::from("fclass"), vec![r(rd), f(rs1)], None)
+        }
+        0b11110 if rs2 == 0 && funct3 == 0b000 => {
+            let name = match fmt {
+                "s" => "fmv.w.x",
+                _ => "fmv.d.x",
+            };
+            return Some(format!("{} {},{}", name, f(rd), r(rs1)));
+        }
+        _ => return None,
+    };
+    let name = format!("{}.{}", name, fmt);
+    match rm {
+        Some(rm) => with_rounding(&name, operands.join(","), funct3, rm),
+        None if funct3 > 0b010 => None,
+        None => Some(format!("{:<7} {}", name, operands.join(","))),
+    }
+}
+
+/// The fused multiply-adds, which keep `rs3` and the format in `funct7`.
+fn fused(
+    opcode: u32,
+    funct3: u32,
+    funct7: u32,
+    regs: [usize; 4],
+    syntax: Syntax,
+) -> Option<String> {
+    let name = match opcode {
+        0b1000011 => "fmadd",
+        0b1000111 => "fmsub",
+        0b1001011 => "fnmsub",
+        _ => "fnmadd",
+    };
+    let name = match funct7 & 0b11 {
+        0b00 => format!("{}.s", name),
+        0b01 => format!("{}.d", name),
+        _ => return None,
+    };
+    let operands = regs.map(|n| FReg(n, syntax.aliases).to_string());
+    with_rounding(&name, operands.join(","), funct3, 0b111)
+}
+
+/// An instruction with an `rm` field, which is only spelled out when it
+/// isn't `default`. Reserved modes make the encoding invalid.
+fn with_rounding(
+    name: &str,
+    operands: String,
+    rm: u32,
+    default: u32,
+) -> Option<String> {
+    match ROUNDING[rm as usize] {
+        "" => None,
+        _ if rm == default => Some(format!("{:<7} {}", name, operands)),
+        mode => Some(format!("{:<7} {},{}", name, operands, mode)),
+    }
+}
+
+fn system(
+    funct3: u32,
+    rd: usize,
+    rs1: usize,
+    imm: u32,
+    syntax: Syntax,
+) -> Option<String> {
+    let r = |n: usize| Reg(n, syntax.aliases);
+    let name = match funct3 {
+        0b000 if rd == 0 && (imm & 0xfff) >> 5 == 0b0001001 => {
+            let rs2 = (imm & 0b11111) as usize;
+            return Some(match (rs1, rs2) {
+                (0, 0) if syntax.pseudo => String::from("sfence.vma"),
+                (_, 0) if syntax.pseudo => {
+                    format!("sfence.vma {}", r(rs1))
+                }
+                _ => format!("sfence.vma {},{}", r(rs1), r(rs2)),
+            });
+        }
+        0b000 if rd != 0 || rs1 != 0 => return None,
+        0b000 => {
+            let name = match imm {
+                0x0 => "ecall",
+                0x1 => "ebreak",
+                0b0001_0000_0010 => "sret",
+                0b0011_0000_0010 => "mret",
+                0b0001_0000_0101 => "wfi",
+                _ => return None,
+            };
+            return Some(String::from(name));
+        }
+        0b001 => "csrrw",
+        0b010 => "csrrs",
+        0b011 => "csrrc",
+        0b101 => "csrrwi",
+        0b110 => "csrrsi",
+        0b111 => "csrrci",
+        _ => return None,
+    };
+    let csr = csr::name((imm & 0xfff) as u16);
+    // the immediate forms use the rs1 field as a zero-extended value
+    let src = match funct3 & 0b100 {
+        0 => r(rs1).to_string(),
+        _ => rs1.to_string(),
+    };
+    Some(match (name, rd, rs1) {
+        ("csrrs", _, 0) if syntax.pseudo => {
+            format!("csrr    {},{}", r(rd), csr)
+        }
+        (_, 0, _) if syntax.pseudo => {
+            // csrrw x0 becomes csrw, csrrsi x0 becomes csrsi, ...
+            let name = format!("csr{}", &name[4..]);
+            format!("{:<7} {},{}", name, csr, src)
+        }
+        _ => format!("{:<7} {},{},{}", name, r(rd), csr, src),
+    })
+}
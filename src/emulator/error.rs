@@ -0,0 +1,69 @@
+use std::fmt;
+
+/// Why a program couldn't be loaded.
+#[derive(Debug)]
+pub enum LoadError {
+    /// The file couldn't be read.
+    Io(std::io::Error),
+    /// The file isn't an ELF file.
+    NotElf,
+    /// The ELF file is for an architecture other than RV32 or RV64.
+    UnsupportedArchitecture(String),
+    /// A segment lies outside the emulator's memory.
+    SegmentOutOfMemory {
+        vaddr: u64,
+        memsz: u64,
+        mem_size: usize,
+    },
+    /// The entry point doesn't fit in XLEN bits.
+    EntryOutOfRange(u64),
+    /// The arguments, environment and auxiliary vector don't fit in
+    /// memory below the stack top.
+    StackOutOfMemory { top: u64 },
+}
+
+impl fmt::Display for LoadError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            LoadError::Io(err) => write!(f, "{}", err),
+            LoadError::NotElf => write!(f, "not an ELF file"),
+            LoadError::UnsupportedArchitecture(arch) => {
+                write!(f, "unsupported architecture: {}", arch)
+            }
+            LoadError::SegmentOutOfMemory {
+                vaddr,
+                memsz,
+                mem_size,
+            } => write!(
+                f,
+                "segment at {:#010x} ({} bytes) does not fit in {} KiB of memory",
+                vaddr,
+                memsz,
+                mem_size / 1024
+            ),
+            LoadError::EntryOutOfRange(entry) => {
+                write!(f, "entry point {:#x} is out of range", entry)
+            }
+            LoadError::StackOutOfMemory { top } => write!(
+                f,
+                "initial stack does not fit in memory below {:#010x}",
+                top
+            ),
+        }
+    }
+}
+
+impl std::error::Error for LoadError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            LoadError::Io(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<std::io::Error> for LoadError {
+    fn from(err: std::io::Error) -> Self {
+        LoadError::Io(err)
+    }
+}
@@ -0,0 +1,651 @@
+//! IEEE 754 single and double precision arithmetic in software, for the
+//! F and D extensions. The host's floating point can't be told how to
+//! round or asked which exceptions an operation raised, so values are
+//! kept as raw bits and every operation is computed exactly and then
+//! rounded in whichever of RISC-V's five modes is in effect.
+//!
+//! NaN results are always the canonical NaN, as RISC-V requires.
+
+/// Inexact.
+pub const NX: u32 = 1;
+/// Underflow.
+pub const UF: u32 = 1 << 1;
+/// Overflow.
+pub const OF: u32 = 1 << 2;
+/// Divide by zero.
+pub const DZ: u32 = 1 << 3;
+/// Invalid operation.
+pub const NV: u32 = 1 << 4;
+
+/// The rounding modes, in the order of their `rm` encodings.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Rounding {
+    /// To nearest, ties to even.
+    NearestEven,
+    TowardZero,
+    /// Toward negative infinity.
+    Down,
+    /// Toward positive infinity.
+    Up,
+    /// To nearest, ties away from zero.
+    NearestMaxMagnitude,
+}
+
+impl Rounding {
+    /// Decodes an `rm` field or `frm`. 5 and 6 are reserved, as is 7
+    /// which in an instruction stands for `frm`.
+    pub fn from_bits(bits: u32) -> Option<Self> {
+        Some(match bits {
+            0 => Rounding::NearestEven,
+            1 => Rounding::TowardZero,
+            2 => Rounding::Down,
+            3 => Rounding::Up,
+            4 => Rounding::NearestMaxMagnitude,
+            _ => return None,
+        })
+    }
+}
+
+/// What an operation needs besides its operands: how to round, and the
+/// exception flags it raises, which are only ever added to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Env {
+    pub rounding: Rounding,
+    pub flags: u32,
+}
+
+impl Env {
+    pub fn new(rounding: Rounding) -> Self {
+        Env { rounding, flags: 0 }
+    }
+}
+
+/// A binary interchange format. Values of either are passed around as
+/// their bits in a `u64`, singles in the low half.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Format {
+    exp_bits: u32,
+    frac_bits: u32,
+}
+
+pub const F32: Format = Format {
+    exp_bits: 8,
+    frac_bits: 23,
+};
+
+pub const F64: Format = Format {
+    exp_bits: 11,
+    frac_bits: 52,
+};
+
+impl Format {
+    fn bias(self) -> i32 {
+        (1 << (self.exp_bits - 1)) - 1
+    }
+
+    /// The exponent field of infinities and NaNs.
+    fn max_exp(self) -> u64 {
+        (1 << self.exp_bits) - 1
+    }
+
+    fn sign_bit(self) -> u64 {
+        1 << (self.exp_bits + self.frac_bits)
+    }
+
+    fn frac_mask(self) -> u64 {
+        (1 << self.frac_bits) - 1
+    }
+
+    fn exp(self, x: u64) -> u64 {
+        (x >> self.frac_bits) & self.max_exp()
+    }
+
+    pub fn sign(self, x: u64) -> bool {
+        x & self.sign_bit() != 0
+    }
+
+    /// `x` with its sign bit set to `sign`.
+    pub fn with_sign(self, x: u64, sign: bool) -> u64 {
+        match sign {
+            true => x | self.sign_bit(),
+            false => x & !self.sign_bit(),
+        }
+    }
+
+    pub fn is_nan(self, x: u64) -> bool {
+        self.exp(x) == self.max_exp() && x & self.frac_mask() != 0
+    }
+
+    /// A signaling NaN has the top bit of its fraction clear.
+    fn is_snan(self, x: u64) -> bool {
+        self.is_nan(x) && x >> (self.frac_bits - 1) & 1 == 0
+    }
+
+    fn is_inf(self, x: u64) -> bool {
+        self.exp(x) == self.max_exp() && x & self.frac_mask() == 0
+    }
+
+    fn is_zero(self, x: u64) -> bool {
+        x & !self.sign_bit() == 0
+    }
+
+    /// The NaN every operation that produces one returns.
+    pub fn canonical_nan(self) -> u64 {
+        self.max_exp() << self.frac_bits | 1 << (self.frac_bits - 1)
+    }
+
+    fn inf(self, sign: bool) -> u64 {
+        self.with_sign(self.max_exp() << self.frac_bits, sign)
+    }
+
+    fn zero(self, sign: bool) -> u64 {
+        self.with_sign(0, sign)
+    }
+
+    /// The exponent and significand of a finite, non-zero `x`, whose
+    /// magnitude is `sig * 2^exp`.
+    fn unpack(self, x: u64) -> (i32, u64) {
+        let exp = self.exp(x) as i32;
+        let frac = x & self.frac_mask();
+        let min = 1 - self.bias() - self.frac_bits as i32;
+        match exp {
+            0 => (min, frac),
+            _ => (min + exp - 1, frac | 1 << self.frac_bits),
+        }
+    }
+
+    /// The canonical NaN, for an operation on `operands` at least one of
+    /// which is a NaN. Signaling ones make it invalid.
+    fn propagate(self, env: &mut Env, operands: &[u64]) -> u64 {
+        if operands.iter().any(|&x| self.is_snan(x)) {
+            env.flags |= NV;
+        }
+        self.canonical_nan()
+    }
+
+    fn invalid(self, env: &mut Env) -> u64 {
+        env.flags |= NV;
+        self.canonical_nan()
+    }
+
+    pub fn add(self, env: &mut Env, a: u64, b: u64) -> u64 {
+        if self.is_nan(a) || self.is_nan(b) {
+            return self.propagate(env, &[a, b]);
+        }
+        let (sa, sb) = (self.sign(a), self.sign(b));
+        if self.is_inf(a) {
+            if self.is_inf(b) && sa != sb {
+                return self.invalid(env);
+            }
+            return a;
+        }
+        if self.is_inf(b) {
+            return b;
+        }
+        match (self.is_zero(a), self.is_zero(b)) {
+            (true, true) => self.zero(zero_sign(env, sa, sb)),
+            (true, false) => b,
+            (false, true) => a,
+            (false, false) => {
+                let (ea, ma) = self.unpack(a);
+                let (eb, mb) = self.unpack(b);
+                self.sum(env, (sa, ea, ma as u128), (sb, eb, mb as u128))
+            }
+        }
+    }
+
+    pub fn sub(self, env: &mut Env, a: u64, b: u64) -> u64 {
+        self.add(env, a, b ^ self.sign_bit())
+    }
+
+    pub fn mul(self, env: &mut Env, a: u64, b: u64) -> u64 {
+        if self.is_nan(a) || self.is_nan(b) {
+            return self.propagate(env, &[a, b]);
+        }
+        let sign = self.sign(a) != self.sign(b);
+        let zero = self.is_zero(a) || self.is_zero(b);
+        if self.is_inf(a) || self.is_inf(b) {
+            return match zero {
+                true => self.invalid(env),
+                false => self.inf(sign),
+            };
+        }
+        if zero {
+            return self.zero(sign);
+        }
+        let (ea, ma) = self.unpack(a);
+        let (eb, mb) = self.unpack(b);
+        self.round(env, sign, ea + eb, ma as u128 * mb as u128)
+    }
+
+    pub fn div(self, env: &mut Env, a: u64, b: u64) -> u64 {
+        if self.is_nan(a) || self.is_nan(b) {
+            return self.propagate(env, &[a, b]);
+        }
+        let sign = self.sign(a) != self.sign(b);
+        match (self.is_inf(a), self.is_inf(b)) {
+            (true, true) => return self.invalid(env),
+            (true, false) => return self.inf(sign),
+            (false, true) => return self.zero(sign),
+            (false, false) => {}
+        }
+        match (self.is_zero(a), self.is_zero(b)) {
+            (true, true) => return self.invalid(env),
+            (false, true) => {
+                env.flags |= DZ;
+                return self.inf(sign);
+            }
+            (true, false) => return self.zero(sign),
+            (false, false) => {}
+        }
+        let (ea, ma) = self.unpack(a);
+        let (eb, mb) = self.unpack(b);
+        // with both significands normalized to 64 bits, the quotient
+        // has at least 64, plus a sticky bit for the remainder
+        let (za, zb) = (ma.leading_zeros(), mb.leading_zeros());
+        let num = ((ma << za) as u128) << 64;
+        let den = (mb << zb) as u128;
+        let quotient = (num / den) | !num.is_multiple_of(den) as u128;
+        let exp = (ea - za as i32 - 64) - (eb - zb as i32);
+        self.round(env, sign, exp, quotient)
+    }
+
+    pub fn sqrt(self, env: &mut Env, a: u64) -> u64 {
+        if self.is_nan(a) {
+            return self.propagate(env, &[a]);
+        }
+        if self.is_zero(a) {
+            return a;
+        }
+        if self.sign(a) {
+            return self.invalid(env);
+        }
+        if self.is_inf(a) {
+            return a;
+        }
+        let (exp, sig) = self.unpack(a);
+        // widen the significand to 125 or 126 bits, whichever makes the
+        // exponent even, so the root has at least 62
+        let mut shift = sig.leading_zeros() as i32 + 62;
+        if (exp - shift) % 2 != 0 {
+            shift -= 1;
+        }
+        let (root, rem) = isqrt((sig as u128) << shift);
+        self.round(
+            env,
+            false,
+            (exp - shift) / 2,
+            root | (rem != 0) as u128,
+        )
+    }
+
+    /// `a * b + c` with a single rounding.
+    pub fn mul_add(self, env: &mut Env, a: u64, b: u64, c: u64) -> u64 {
+        let sign = self.sign(a) != self.sign(b);
+        let zero = self.is_zero(a) || self.is_zero(b);
+        let inf = self.is_inf(a) || self.is_inf(b);
+        if self.is_nan(a) || self.is_nan(b) || self.is_nan(c) {
+            // infinity times zero is invalid even if `c` is a quiet NaN
+            if inf && zero {
+                env.flags |= NV;
+            }
+            return self.propagate(env, &[a, b, c]);
+        }
+        if inf {
+            if zero || self.is_inf(c) && self.sign(c) != sign {
+                return self.invalid(env);
+            }
+            return self.inf(sign);
+        }
+        if self.is_inf(c) {
+            return c;
+        }
+        if zero {
+            return match self.is_zero(c) {
+                true => self.zero(zero_sign(env, sign, self.sign(c))),
+                false => c,
+            };
+        }
+        let (ea, ma) = self.unpack(a);
+        let (eb, mb) = self.unpack(b);
+        let product = (sign, ea + eb, ma as u128 * mb as u128);
+        if self.is_zero(c) {
+            return self.round(env, product.0, product.1, product.2);
+        }
+        let (ec, mc) = self.unpack(c);
+        self.sum(env, product, (self.sign(c), ec, mc as u128))
+    }
+
+    /// The smaller of `a` and `b`, -0 being smaller than +0. A NaN is
+    /// only returned if both are.
+    pub fn min(self, env: &mut Env, a: u64, b: u64) -> u64 {
+        self.min_max(env, a, b, true)
+    }
+
+    /// The larger of `a` and `b`, like [`Format::min`].
+    pub fn max(self, env: &mut Env, a: u64, b: u64) -> u64 {
+        self.min_max(env, a, b, false)
+    }
+
+    fn min_max(self, env: &mut Env, a: u64, b: u64, min: bool) -> u64 {
+        if self.is_snan(a) || self.is_snan(b) {
+            env.flags |= NV;
+        }
+        match (self.is_nan(a), self.is_nan(b)) {
+            (true, true) => self.canonical_nan(),
+            (true, false) => b,
+            (false, true) => a,
+            (false, false) if (self.key(a) < self.key(b)) == min => a,
+            (false, false) => b,
+        }
+    }
+
+    /// Orders values that aren't NaNs, putting -0 before +0.
+    fn key(self, x: u64) -> i64 {
+        let magnitude = (x & !self.sign_bit()) as i64;
+        match self.sign(x) {
+            true => -magnitude - 1,
+            false => magnitude,
+        }
+    }
+
+    /// Quiet equality: only signaling NaNs are invalid.
+    pub fn eq(self, env: &mut Env, a: u64, b: u64) -> bool {
+        if self.is_snan(a) || self.is_snan(b) {
+            env.flags |= NV;
+        }
+        if self.is_nan(a) || self.is_nan(b) {
+            return false;
+        }
+        a == b || self.is_zero(a) && self.is_zero(b)
+    }
+
+    /// Signaling less than: any NaN is invalid.
+    pub fn lt(self, env: &mut Env, a: u64, b: u64) -> bool {
+        if self.is_nan(a) || self.is_nan(b) {
+            env.flags |= NV;
+            return false;
+        }
+        self.key(a) < self.key(b) && !(self.is_zero(a) && self.is_zero(b))
+    }
+
+    /// Signaling less than or equal: any NaN is invalid.
+    pub fn le(self, env: &mut Env, a: u64, b: u64) -> bool {
+        if self.is_nan(a) || self.is_nan(b) {
+            env.flags |= NV;
+            return false;
+        }
+        self.key(a) <= self.key(b) || self.is_zero(a) && self.is_zero(b)
+    }
+
+    /// The `fclass` mask of `x`: one bit of -infinity, negative normal,
+    /// negative subnormal, -0, +0, positive subnormal, positive normal,
+    /// +infinity, signaling NaN and quiet NaN, from bit 0 up.
+    pub fn classify(self, x: u64) -> u32 {
+        let sign = self.sign(x);
+        let class = if self.is_nan(x) {
+            match self.is_snan(x) {
+                true => 8,
+                false => 9,
+            }
+        } else if self.is_inf(x) {
+            7
+        } else if self.is_zero(x) {
+            4
+        } else if self.exp(x) == 0 {
+            5
+        } else {
+            6
+        };
+        match (sign, class) {
+            (true, 4..=7) => 1 << (7 - class),
+            _ => 1 << class,
+        }
+    }
+
+    /// Converts `x` to a `bits`-bit integer, 32 or 64, signed or not,
+    /// which comes back sign-extended to 64 bits the way RV64 keeps
+    /// 32-bit results. NaNs and values that round to something out of
+    /// range are invalid, and give the closest integer there is, NaNs
+    /// counting as positive infinity.
+    pub fn to_int(
+        self,
+        env: &mut Env,
+        x: u64,
+        signed: bool,
+        bits: u32,
+    ) -> u64 {
+        let (min, max) = match signed {
+            true => (-(1i128 << (bits - 1)), (1i128 << (bits - 1)) - 1),
+            false => (0, (1i128 << bits) - 1),
+        };
+        let sext = |int: i128| {
+            let shift = 64 - bits;
+            ((int as u64) << shift) as i64 >> shift
+        };
+        let sign = self.sign(x) && !self.is_nan(x);
+        let saturated = match sign {
+            true => sext(min) as u64,
+            false => sext(max) as u64,
+        };
+        if self.is_nan(x) || self.is_inf(x) {
+            env.flags |= NV;
+            return saturated;
+        }
+        if self.is_zero(x) {
+            return 0;
+        }
+        let (exp, sig) = self.unpack(x);
+        let (magnitude, inexact) = match exp {
+            // anything this large is out of range anyway
+            64.. => (i128::MAX, false),
+            0.. => ((sig as i128) << exp, false),
+            _ => {
+                let (int, inexact) =
+                    shift_round(sig as u128, -exp, sign, env.rounding);
+                (int as i128, inexact)
+            }
+        };
+        let int = if sign { -magnitude } else { magnitude };
+        if int < min || int > max {
+            env.flags |= NV;
+            return saturated;
+        }
+        if inexact {
+            env.flags |= NX;
+        }
+        sext(int) as u64
+    }
+
+    /// Converts a 64-bit integer, signed or not, to this format. Narrower
+    /// integers are extended to 64 bits first.
+    pub fn from_int(self, env: &mut Env, x: u64, signed: bool) -> u64 {
+        let negative = signed && (x as i64) < 0;
+        let magnitude = match negative {
+            true => (x as i64).unsigned_abs(),
+            false => x,
+        };
+        if magnitude == 0 {
+            return self.zero(false);
+        }
+        self.round(env, negative, 0, magnitude as u128)
+    }
+
+    /// Converts `x` to the format `to`, which is exact when widening.
+    pub fn convert(self, env: &mut Env, x: u64, to: Format) -> u64 {
+        if self.is_nan(x) {
+            self.propagate(env, &[x]);
+            return to.canonical_nan();
+        }
+        let sign = self.sign(x);
+        if self.is_inf(x) {
+            return to.inf(sign);
+        }
+        if self.is_zero(x) {
+            return to.zero(sign);
+        }
+        let (exp, sig) = self.unpack(x);
+        to.round(env, sign, exp, sig as u128)
+    }
+
+    /// Rounds the sum of two non-zero values, each `sig * 2^exp` with a
+    /// significand of at most 106 bits, the width of a product.
+    fn sum(
+        self,
+        env: &mut Env,
+        a: (bool, i32, u128),
+        b: (bool, i32, u128),
+    ) -> u64 {
+        // line the significands up at bit 125, with room for a carry
+        let normalize = |(sign, exp, sig): (bool, i32, u128)| {
+            let shift = sig.leading_zeros() as i32 - 2;
+            (sign, exp - shift, sig << shift)
+        };
+        let (mut a, mut b) = (normalize(a), normalize(b));
+        if a.1 < b.1 {
+            std::mem::swap(&mut a, &mut b);
+        }
+        // bits shifted out of the smaller one can only be lost when it is
+        // at least 19 places below, too far to cancel more than one bit
+        let small = shift_right_jam(b.2, (a.1 - b.1) as u32);
+        if a.0 == b.0 {
+            return self.round(env, a.0, a.1, a.2 + small);
+        }
+        match a.2.cmp(&small) {
+            std::cmp::Ordering::Greater => {
+                self.round(env, a.0, a.1, a.2 - small)
+            }
+            std::cmp::Ordering::Less => {
+                self.round(env, b.0, a.1, small - a.2)
+            }
+            std::cmp::Ordering::Equal => {
+                self.zero(env.rounding == Rounding::Down)
+            }
+        }
+    }
+
+    /// Rounds `sig * 2^exp` to this format, raising NX, UF and OF as
+    /// they apply. `sig` isn't zero, and its lowest bit may be a sticky
+    /// bit standing for others shifted out below it.
+    fn round(self, env: &mut Env, sign: bool, exp: i32, sig: u128) -> u64 {
+        let frac_bits = self.frac_bits as i32;
+        let emin = 1 - self.bias();
+        // the value is in [2^top, 2^(top + 1))
+        let top = exp + 127 - sig.leading_zeros() as i32;
+        // the weight of the result's last bit; subnormals have less
+        // precision
+        let mut lsb = top.max(emin) - frac_bits;
+        let (mut kept, inexact) =
+            shift_round(sig, lsb - exp, sign, env.rounding);
+        if kept >> (frac_bits + 1) != 0 {
+            // rounded up to the next power of two
+            kept >>= 1;
+            lsb += 1;
+        }
+        if inexact {
+            env.flags |= NX;
+            // tininess is detected after rounding, as if the exponent
+            // were unbounded
+            let tiny = top < emin - 1
+                || top == emin - 1 && {
+                    let shift = top - frac_bits - exp;
+                    let (kept, _) =
+                        shift_round(sig, shift, sign, env.rounding);
+                    kept >> (frac_bits + 1) == 0
+                };
+            if tiny {
+                env.flags |= UF;
+            }
+        }
+        let biased = match kept >> frac_bits {
+            0 => 0,
+            _ => (lsb + frac_bits + self.bias()) as u64,
+        };
+        if biased >= self.max_exp() {
+            return self.overflow(env, sign);
+        }
+        self.with_sign(
+            biased << self.frac_bits | kept as u64 & self.frac_mask(),
+            sign,
+        )
+    }
+
+    /// The result of rounding a value too large for this format.
+    fn overflow(self, env: &mut Env, sign: bool) -> u64 {
+        env.flags |= OF | NX;
+        let infinite = match env.rounding {
+            Rounding::NearestEven | Rounding::NearestMaxMagnitude => true,
+            Rounding::TowardZero => false,
+            Rounding::Down => sign,
+            Rounding::Up => !sign,
+        };
+        match infinite {
+            true => self.inf(sign),
+            false => self.with_sign(
+                (self.max_exp() - 1) << self.frac_bits | self.frac_mask(),
+                sign,
+            ),
+        }
+    }
+}
+
+/// The sign of an exact zero sum of terms with signs `a` and `b`.
+fn zero_sign(env: &Env, a: bool, b: bool) -> bool {
+    match a == b {
+        true => a,
+        false => env.rounding == Rounding::Down,
+    }
+}
+
+/// Drops the low `shift` bits of `sig`, rounding what's left to an
+/// integer in the direction `rounding` picks for a value of that `sign`.
+/// Returns it, and whether any bits dropped were set.
+fn shift_round(
+    sig: u128,
+    shift: i32,
+    sign: bool,
+    rounding: Rounding,
+) -> (u128, bool) {
+    if shift <= 0 {
+        return (sig << -shift, false);
+    }
+    let (kept, rem, half) = match shift {
+        // everything is dropped, and it's less than half
+        128.. => (0, 1, 2),
+        _ => (sig >> shift, sig & ((1 << shift) - 1), 1 << (shift - 1)),
+    };
+    let up = match rounding {
+        Rounding::NearestEven => {
+            rem > half || rem == half && kept & 1 == 1
+        }
+        Rounding::TowardZero => false,
+        Rounding::Down => sign && rem != 0,
+        Rounding::Up => !sign && rem != 0,
+        Rounding::NearestMaxMagnitude => rem >= half,
+    };
+    (kept + up as u128, rem != 0)
+}
+
+/// `x >> shift`, with the lowest bit set if any bits shifted out were.
+fn shift_right_jam(x: u128, shift: u32) -> u128 {
+    match shift {
+        0 => x,
+        128.. => (x != 0) as u128,
+        _ => x >> shift | (x << (128 - shift) != 0) as u128,
+    }
+}
+
+/// The integer square root of `n` and the remainder, a bit at a time.
+fn isqrt(n: u128) -> (u128, u128) {
+    let (mut root, mut rem) = (0u128, 0u128);
+    for i in (0..64).rev() {
+        rem = rem << 2 | (n >> (2 * i)) & 0b11;
+        let trial = root << 2 | 1;
+        root <<= 1;
+        if rem >= trial {
+            rem -= trial;
+            root |= 1;
+        }
+    }
+    (root, rem)
+}
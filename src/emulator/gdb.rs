@@ -0,0 +1,456 @@
+//! A GDB remote serial protocol stub, so `gdb` can debug a [`Machine`]
+//! with `target remote`.
+
+use super::cpu::{WatchKind, Watchpoint};
+use super::machine::{FaultKind, Machine, StopReason};
+use std::collections::VecDeque;
+use std::io::{self, Read, Write};
+use std::net::TcpStream;
+
+/// The target description, with `XLEN` standing for the register width.
+const TARGET_XML: &str = r#"<?xml version="1.0"?>
+<!DOCTYPE target SYSTEM "gdb-target.dtd">
+<target version="1.0">
+  <architecture>riscv:rvXLEN</architecture>
+  <feature name="org.gnu.gdb.riscv.cpu">
+    <reg name="zero" bitsize="XLEN" type="int" regnum="0"/>
+    <reg name="ra" bitsize="XLEN" type="code_ptr"/>
+    <reg name="sp" bitsize="XLEN" type="data_ptr"/>
+    <reg name="gp" bitsize="XLEN" type="data_ptr"/>
+    <reg name="tp" bitsize="XLEN" type="data_ptr"/>
+    <reg name="t0" bitsize="XLEN" type="int"/>
+    <reg name="t1" bitsize="XLEN" type="int"/>
+    <reg name="t2" bitsize="XLEN" type="int"/>
+    <reg name="fp" bitsize="XLEN" type="data_ptr"/>
+    <reg name="s1" bitsize="XLEN" type="int"/>
+    <reg name="a0" bitsize="XLEN" type="int"/>
+    <reg name="a1" bitsize="XLEN" type="int"/>
+    <reg name="a2" bitsize="XLEN" type="int"/>
+    <reg name="a3" bitsize="XLEN" type="int"/>
+    <reg name="a4" bitsize="XLEN" type="int"/>
+    <reg name="a5" bitsize="XLEN" type="int"/>
+    <reg name="a6" bitsize="XLEN" type="int"/>
+    <reg name="a7" bitsize="XLEN" type="int"/>
+    <reg name="s2" bitsize="XLEN" type="int"/>
+    <reg name="s3" bitsize="XLEN" type="int"/>
+    <reg name="s4" bitsize="XLEN" type="int"/>
+    <reg name="s5" bitsize="XLEN" type="int"/>
+    <reg name="s6" bitsize="XLEN" type="int"/>
+    <reg name="s7" bitsize="XLEN" type="int"/>
+    <reg name="s8" bitsize="XLEN" type="int"/>
+    <reg name="s9" bitsize="XLEN" type="int"/>
+    <reg name="s10" bitsize="XLEN" type="int"/>
+    <reg name="s11" bitsize="XLEN" type="int"/>
+    <reg name="t3" bitsize="XLEN" type="int"/>
+    <reg name="t4" bitsize="XLEN" type="int"/>
+    <reg name="t5" bitsize="XLEN" type="int"/>
+    <reg name="t6" bitsize="XLEN" type="int"/>
+    <reg name="pc" bitsize="XLEN" type="code_ptr"/>
+  </feature>
+</target>
+"#;
+
+/// How often `continue` checks whether GDB wants to interrupt it.
+const INTERRUPT_POLL_STEPS: u32 = 4096;
+
+/// GDB's own signal numbers, used in stop replies.
+const SIGINT: u8 = 2;
+const SIGILL: u8 = 4;
+const SIGTRAP: u8 = 5;
+const SIGBUS: u8 = 10;
+const SIGSEGV: u8 = 11;
+const SIGSYS: u8 = 12;
+const SIGXCPU: u8 = 24;
+
+/// How a debugging session ended.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Session {
+    /// The program exited with this code while being debugged.
+    Exited(i32),
+    /// GDB detached, leaving the program to run on its own.
+    Detached,
+    /// GDB killed the program or went away.
+    Killed,
+}
+
+/// Serves GDB on `stream` until the program exits or GDB is done.
+pub fn serve(
+    machine: &mut Machine,
+    stream: TcpStream,
+) -> io::Result<Session> {
+    stream.set_nodelay(true)?;
+    GdbStub {
+        machine,
+        stream,
+        input: VecDeque::new(),
+    }
+    .serve()
+}
+
+struct GdbStub<'a> {
+    machine: &'a mut Machine,
+    stream: TcpStream,
+    /// Bytes received but not processed yet.
+    input: VecDeque<u8>,
+}
+
+impl GdbStub<'_> {
+    fn serve(&mut self) -> io::Result<Session> {
+        loop {
+            let packet = match self.read_packet()? {
+                Some(packet) => packet,
+                None => return Ok(Session::Killed),
+            };
+            let reply = match packet.as_bytes().first() {
+                Some(b'?') => format!("S{:02x}", SIGTRAP),
+                Some(b'g') => self.read_registers(),
+                Some(b'G') => self.write_registers(&packet[1..]),
+                Some(b'p') => self.read_register(&packet[1..]),
+                Some(b'P') => self.write_register(&packet[1..]),
+                Some(b'm') => self.read_memory(&packet[1..]),
+                Some(b'M') => self.write_memory(&packet[1..]),
+                Some(b's') | Some(b'c') => {
+                    if let Some(addr) = parse_hex(&packet[1..]) {
+                        self.machine.cpu_mut().set_pc(addr);
+                    }
+                    let stop = if packet.starts_with('s') {
+                        self.machine.step().stop
+                    } else {
+                        self.resume()?
+                    };
+                    match stop {
+                        Some(StopReason::Exited(code)) => {
+                            self.write_packet(&stop_reply(
+                                StopReason::Exited(code),
+                            ))?;
+                            return Ok(Session::Exited(code));
+                        }
+                        Some(stop) => stop_reply(stop),
+                        // a step that went fine, or an interrupt
+                        None if packet.starts_with('s') => {
+                            format!("S{:02x}", SIGTRAP)
+                        }
+                        None => format!("S{:02x}", SIGINT),
+                    }
+                }
+                Some(b'Z') => self.breakpoint(&packet[1..], true),
+                Some(b'z') => self.breakpoint(&packet[1..], false),
+                Some(b'H') => String::from("OK"),
+                Some(b'D') => {
+                    self.write_packet("OK")?;
+                    return Ok(Session::Detached);
+                }
+                Some(b'k') => return Ok(Session::Killed),
+                Some(b'q') => self.query(&packet),
+                _ => String::new(),
+            };
+            self.write_packet(&reply)?;
+        }
+    }
+
+    /// Runs until the program stops or GDB interrupts it, in which case
+    /// it returns `None`.
+    fn resume(&mut self) -> io::Result<Option<StopReason>> {
+        loop {
+            for _ in 0..INTERRUPT_POLL_STEPS {
+                if let Some(stop) = self.machine.step().stop {
+                    return Ok(Some(stop));
+                }
+            }
+            if self.interrupted()? {
+                return Ok(None);
+            }
+        }
+    }
+
+    fn query(&self, packet: &str) -> String {
+        if packet.starts_with("qSupported") {
+            String::from("PacketSize=4000;qXfer:features:read+")
+        } else if packet == "qAttached" {
+            String::from("1")
+        } else if let Some(range) =
+            packet.strip_prefix("qXfer:features:read:target.xml:")
+        {
+            let (offset, length) = match parse_pair(range, ',') {
+                Some(pair) => pair,
+                None => return String::from("E01"),
+            };
+            let bits = self.machine.cpu().xlen().bits();
+            let xml = TARGET_XML.replace("XLEN", &bits.to_string());
+            let start = (offset as usize).min(xml.len());
+            let end = start.saturating_add(length as usize).min(xml.len());
+            let more = if end < xml.len() { 'm' } else { 'l' };
+            format!("{}{}", more, &xml[start..end])
+        } else {
+            String::new()
+        }
+    }
+
+    /// The size of a register in bytes.
+    fn word(&self) -> usize {
+        self.machine.cpu().xlen().bits() as usize / 8
+    }
+
+    fn read_registers(&self) -> String {
+        let cpu = self.machine.cpu();
+        (0..32)
+            .map(|n| cpu.register(n))
+            .chain([cpu.pc()])
+            .map(|value| to_hex(&value.to_le_bytes()[..self.word()]))
+            .collect()
+    }
+
+    fn write_registers(&mut self, data: &str) -> String {
+        let word = self.word();
+        let bytes = match from_hex(data) {
+            Some(bytes) if bytes.len() >= 33 * word => bytes,
+            _ => return String::from("E01"),
+        };
+        let cpu = self.machine.cpu_mut();
+        for (n, word) in bytes.chunks_exact(word).take(33).enumerate() {
+            let value = from_le(word);
+            match n {
+                32 => cpu.set_pc(value),
+                n => cpu.set_register(n, value),
+            }
+        }
+        String::from("OK")
+    }
+
+    fn read_register(&self, data: &str) -> String {
+        let cpu = self.machine.cpu();
+        let value = match parse_hex(data) {
+            Some(n @ 0..=31) => cpu.register(n as usize),
+            Some(32) => cpu.pc(),
+            _ => return String::from("E01"),
+        };
+        to_hex(&value.to_le_bytes()[..self.word()])
+    }
+
+    fn write_register(&mut self, data: &str) -> String {
+        let (n, value) = match data.split_once('=') {
+            Some((n, value)) => (parse_hex(n), from_hex(value)),
+            None => return String::from("E01"),
+        };
+        let value = match value.filter(|v| v.len() == self.word()) {
+            Some(bytes) => from_le(&bytes),
+            None => return String::from("E01"),
+        };
+        let cpu = self.machine.cpu_mut();
+        match n {
+            Some(n @ 0..=31) => cpu.set_register(n as usize, value),
+            Some(32) => cpu.set_pc(value),
+            _ => return String::from("E01"),
+        }
+        String::from("OK")
+    }
+
+    fn read_memory(&self, data: &str) -> String {
+        parse_pair(data, ',')
+            .and_then(|(addr, len)| {
+                self.machine.cpu().read_memory(addr, len as usize)
+            })
+            .map(to_hex)
+            .unwrap_or_else(|| String::from("E14"))
+    }
+
+    fn write_memory(&mut self, data: &str) -> String {
+        let written = data.split_once(':').and_then(|(range, bytes)| {
+            let (addr, len) = parse_pair(range, ',')?;
+            let bytes = from_hex(bytes)?;
+            if bytes.len() != len as usize {
+                return None;
+            }
+            self.machine.cpu_mut().write_memory(addr, &bytes)
+        });
+        match written {
+            Some(()) => String::from("OK"),
+            None => String::from("E14"),
+        }
+    }
+
+    /// Handles `Z`/`z`: type 0 and 1 are breakpoints, 2 to 4 write, read
+    /// and access watchpoints.
+    fn breakpoint(&mut self, data: &str, insert: bool) -> String {
+        let mut fields = data.split(',');
+        let (kind, addr, len) = match (
+            fields.next(),
+            fields.next().and_then(parse_hex),
+            fields.next().and_then(parse_hex),
+        ) {
+            (Some(kind), Some(addr), Some(len)) => (kind, addr, len),
+            _ => return String::from("E01"),
+        };
+        let kind = match kind {
+            "0" | "1" => {
+                if insert {
+                    self.machine.add_breakpoint(addr);
+                } else {
+                    self.machine.remove_breakpoint(addr);
+                }
+                return String::from("OK");
+            }
+            "2" => WatchKind::Write,
+            "3" => WatchKind::Read,
+            "4" => WatchKind::Access,
+            _ => return String::new(),
+        };
+        let watchpoint = Watchpoint { addr, len, kind };
+        if insert {
+            self.machine.add_watchpoint(watchpoint);
+        } else {
+            self.machine.remove_watchpoint(watchpoint);
+        }
+        String::from("OK")
+    }
+
+    /// Reads the next packet, acknowledging it. Returns `None` once GDB
+    /// closes the connection.
+    fn read_packet(&mut self) -> io::Result<Option<String>> {
+        loop {
+            // skip acks and anything else outside a packet
+            loop {
+                match self.read_byte()? {
+                    Some(b'$') => break,
+                    Some(_) => continue,
+                    None => return Ok(None),
+                }
+            }
+            let mut data = Vec::new();
+            loop {
+                match self.read_byte()? {
+                    Some(b'#') => break,
+                    Some(byte) => data.push(byte),
+                    None => return Ok(None),
+                }
+            }
+            let mut checksum = [0; 2];
+            for digit in checksum.iter_mut() {
+                match self.read_byte()? {
+                    Some(byte) => *digit = byte,
+                    None => return Ok(None),
+                }
+            }
+            let expected = std::str::from_utf8(&checksum)
+                .ok()
+                .and_then(|sum| u8::from_str_radix(sum, 16).ok());
+            if expected == Some(checksum_of(&data)) {
+                self.stream.write_all(b"+")?;
+                return Ok(Some(
+                    String::from_utf8_lossy(&data).into_owned(),
+                ));
+            }
+            self.stream.write_all(b"-")?;
+        }
+    }
+
+    fn write_packet(&mut self, data: &str) -> io::Result<()> {
+        let mut escaped = Vec::with_capacity(data.len());
+        for byte in data.bytes() {
+            if let b'$' | b'#' | b'}' | b'*' = byte {
+                escaped.extend([b'}', byte ^ 0x20]);
+            } else {
+                escaped.push(byte);
+            }
+        }
+        let mut packet = vec![b'$'];
+        packet.extend(&escaped);
+        packet.extend(format!("#{:02x}", checksum_of(&escaped)).bytes());
+        self.stream.write_all(&packet)
+    }
+
+    fn read_byte(&mut self) -> io::Result<Option<u8>> {
+        if self.input.is_empty() {
+            let mut buf = [0; 4096];
+            let n = self.stream.read(&mut buf)?;
+            if n == 0 {
+                return Ok(None);
+            }
+            self.input.extend(&buf[..n]);
+        }
+        Ok(self.input.pop_front())
+    }
+
+    /// Checks, without blocking, whether GDB sent an interrupt (^C).
+    fn interrupted(&mut self) -> io::Result<bool> {
+        let mut buf = [0; 4096];
+        self.stream.set_nonblocking(true)?;
+        let result = self.stream.read(&mut buf);
+        self.stream.set_nonblocking(false)?;
+        match result {
+            Ok(n) => self.input.extend(&buf[..n]),
+            Err(err) if err.kind() == io::ErrorKind::WouldBlock => {}
+            Err(err) => return Err(err),
+        }
+        match self.input.iter().position(|&byte| byte == 0x03) {
+            Some(index) => {
+                self.input.remove(index);
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    }
+}
+
+/// The reply telling GDB why the program stopped.
+fn stop_reply(stop: StopReason) -> String {
+    let signal = match stop {
+        StopReason::Exited(code) => return format!("W{:02x}", code as u8),
+        StopReason::Watchpoint { addr, kind, .. } => {
+            let kind = match kind {
+                WatchKind::Write => "watch",
+                WatchKind::Read => "rwatch",
+                WatchKind::Access => "awatch",
+            };
+            return format!("T{:02x}{}:{:x};", SIGTRAP, kind, addr);
+        }
+        StopReason::Breakpoint { .. } => SIGTRAP,
+        StopReason::IllegalInstruction { .. } => SIGILL,
+        StopReason::MemoryFault { kind, .. } => match kind {
+            FaultKind::MisalignedFetch
+            | FaultKind::MisalignedLoad
+            | FaultKind::MisalignedStore => SIGBUS,
+            FaultKind::Fetch
+            | FaultKind::Load
+            | FaultKind::Store
+            | FaultKind::PageFetch
+            | FaultKind::PageLoad
+            | FaultKind::PageStore => SIGSEGV,
+        },
+        StopReason::UnknownSyscall(_) => SIGSYS,
+        StopReason::StepLimit => SIGXCPU,
+    };
+    format!("S{:02x}", signal)
+}
+
+fn checksum_of(data: &[u8]) -> u8 {
+    data.iter().fold(0, |sum, byte| sum.wrapping_add(*byte))
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+fn from_hex(hex: &str) -> Option<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+/// A little-endian register value of any width.
+fn from_le(bytes: &[u8]) -> u64 {
+    bytes.iter().rev().fold(0, |acc, &b| acc << 8 | b as u64)
+}
+
+fn parse_hex(hex: &str) -> Option<u64> {
+    u64::from_str_radix(hex, 16).ok()
+}
+
+fn parse_pair(data: &str, separator: char) -> Option<(u64, u64)> {
+    let (first, second) = data.split_once(separator)?;
+    Some((parse_hex(first)?, parse_hex(second)?))
+}
@@ -0,0 +1,57 @@
+use super::instruction::Instruction;
+
+/// Number of entries, a power of two. Instructions are 2-byte aligned, so
+/// the cache covers 16 KiB of straight-line code.
+const ENTRIES: usize = 8192;
+
+/// A direct-mapped cache of decoded instructions, indexed by their
+/// address, so hot code is only fetched and decoded once.
+pub(crate) struct ICache {
+    entries: Box<[Option<(u64, Instruction)>]>,
+}
+
+impl ICache {
+    pub(crate) fn new() -> Self {
+        ICache {
+            entries: vec![None; ENTRIES].into_boxed_slice(),
+        }
+    }
+
+    fn index(pc: u64) -> usize {
+        (pc >> 1) as usize & (ENTRIES - 1)
+    }
+
+    /// The instruction decoded at `pc`, if it's still cached.
+    pub(crate) fn get(&self, pc: u64) -> Option<Instruction> {
+        match self.entries[ICache::index(pc)] {
+            Some((tag, inst)) if tag == pc => Some(inst),
+            _ => None,
+        }
+    }
+
+    pub(crate) fn insert(&mut self, pc: u64, inst: Instruction) {
+        self.entries[ICache::index(pc)] = Some((pc, inst));
+    }
+
+    /// Forgets any instruction overlapping the `len` bytes written at
+    /// `addr`.
+    pub(crate) fn invalidate(&mut self, addr: u64, len: usize) {
+        if len >= ENTRIES * 2 {
+            return self.flush();
+        }
+        // a 4-byte instruction starting 2 bytes earlier overlaps too
+        let start = (addr & !1).wrapping_sub(2);
+        let span = addr.wrapping_add(len as u64).wrapping_sub(start);
+        for i in 0..span.div_ceil(2) {
+            let pc = start.wrapping_add(2 * i);
+            let entry = &mut self.entries[ICache::index(pc)];
+            if matches!(entry, Some((tag, _)) if *tag == pc) {
+                *entry = None;
+            }
+        }
+    }
+
+    pub(crate) fn flush(&mut self) {
+        self.entries.fill(None);
+    }
+}
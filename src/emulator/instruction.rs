@@ -1,23 +1,63 @@
-#[derive(Debug)]
+/// A decoded instruction. Compressed instructions are expanded into the
+/// 32-bit instruction they stand for, keeping their own `raw` and `size`.
+#[derive(Debug, Clone, Copy)]
 pub struct Instruction {
-    pub name: String,
+    pub raw: u32,
     pub opcode: u32,
     pub type_data: InstTypeData,
     pub type_name: InstTypeName,
+    /// Length of the encoding in bytes: 2 for RVC, 4 otherwise.
+    pub size: u32,
 }
 
 impl Instruction {
     pub fn new() -> Self {
         Instruction {
-            name: String::from("default"),
+            raw: 0,
             opcode: 0,
             type_data: InstTypeData::Unimp,
             type_name: InstTypeName::Unimp,
+            size: 4,
         }
     }
+
+    /// The integer register the instruction writes, for formats that
+    /// have a destination and instructions that don't write a
+    /// floating-point register instead.
+    pub fn rd(&self) -> Option<usize> {
+        match self.type_data {
+            // loads into floating-point registers
+            InstTypeData::I { .. } if self.opcode == 0b0000111 => None,
+            // only comparisons, conversions to integers, moves to integer
+            // registers and classifications write one
+            InstTypeData::R { rd, funct7, .. }
+                if self.opcode == 0b1010011 =>
+            {
+                matches!(funct7 >> 2, 0b10100 | 0b11000 | 0b11100)
+                    .then_some(rd)
+            }
+            // the fused multiply-adds
+            InstTypeData::R { .. }
+                if self.opcode & 0b1110011 == 0b1000011 =>
+            {
+                None
+            }
+            InstTypeData::R { rd, .. }
+            | InstTypeData::I { rd, .. }
+            | InstTypeData::U { rd, .. }
+            | InstTypeData::J { rd, .. } => Some(rd),
+            _ => None,
+        }
+    }
+}
+
+impl Default for Instruction {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy)]
 pub enum InstTypeName {
     R,
     I,
@@ -29,7 +69,7 @@ pub enum InstTypeName {
     Fence,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy)]
 pub enum InstTypeData {
     R {
         rd: usize,
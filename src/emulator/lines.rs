@@ -0,0 +1,355 @@
+//! Source lines for addresses, decoded from an ELF file's DWARF
+//! `.debug_line` section. Versions 2 to 5 are understood, in the 32-bit
+//! DWARF format that RISC-V toolchains produce for RV32 and RV64 alike.
+
+use elf_rs::{Elf, ElfFile};
+
+// standard opcodes
+const DW_LNS_COPY: u8 = 1;
+const DW_LNS_ADVANCE_PC: u8 = 2;
+const DW_LNS_ADVANCE_LINE: u8 = 3;
+const DW_LNS_SET_FILE: u8 = 4;
+const DW_LNS_CONST_ADD_PC: u8 = 8;
+const DW_LNS_FIXED_ADVANCE_PC: u8 = 9;
+
+// extended opcodes
+const DW_LNE_END_SEQUENCE: u8 = 1;
+const DW_LNE_SET_ADDRESS: u8 = 2;
+const DW_LNE_DEFINE_FILE: u8 = 3;
+
+// DWARF 5 directory and file entries: the content that matters, and the
+// forms any content can come in
+const DW_LNCT_PATH: u64 = 1;
+const DW_FORM_BLOCK: u64 = 0x09;
+const DW_FORM_DATA1: u64 = 0x0b;
+const DW_FORM_DATA2: u64 = 0x05;
+const DW_FORM_DATA4: u64 = 0x06;
+const DW_FORM_DATA8: u64 = 0x07;
+const DW_FORM_DATA16: u64 = 0x1e;
+const DW_FORM_STRING: u64 = 0x08;
+const DW_FORM_STRP: u64 = 0x0e;
+const DW_FORM_UDATA: u64 = 0x0f;
+const DW_FORM_LINE_STRP: u64 = 0x1f;
+
+/// A row of the line table: the code from `addr` up to the next row
+/// comes from `line` of `files[file]`. A row without a file ends a
+/// sequence of code.
+#[derive(Debug, Clone, Copy)]
+struct Row {
+    addr: u64,
+    file: Option<usize>,
+    line: u32,
+}
+
+/// The source file and line of each address a program has line
+/// information for.
+#[derive(Debug, Clone, Default)]
+pub struct Lines {
+    files: Vec<String>,
+    rows: Vec<Row>,
+}
+
+impl Lines {
+    /// Decodes the `.debug_line` section of `elf`. A file built without
+    /// debug information has no lines, and units that can't be decoded
+    /// are skipped.
+    pub fn from_elf(elf: &Elf) -> Self {
+        let section = |name: &[u8]| {
+            elf.section_header_iter()
+                .find(|shdr| shdr.section_name() == name)
+                .map(|shdr| shdr.content())
+                .unwrap_or_default()
+        };
+        let debug_line = section(b".debug_line");
+        let strings = Strings {
+            line_str: section(b".debug_line_str"),
+            str: section(b".debug_str"),
+        };
+        let mut lines = Lines::default();
+        let mut offset = 0;
+        while let Some(len) = Reader::new(debug_line, offset).u32() {
+            // 0xffffffff would introduce the 64-bit format
+            if len == 0xffff_ffff {
+                break;
+            }
+            let end = (offset + 4).saturating_add(len as usize);
+            let unit = &debug_line[..end.min(debug_line.len())];
+            lines.unit(&mut Reader::new(unit, offset + 4), &strings);
+            offset = end;
+        }
+        // where one sequence ends and another starts, the start counts
+        lines.rows.sort_by_key(|row| (row.addr, row.file.is_some()));
+        lines
+    }
+
+    /// The file and line the code at `addr` comes from.
+    pub fn lookup(&self, addr: u64) -> Option<(&str, u32)> {
+        let i = self.rows.partition_point(|row| row.addr <= addr);
+        let row = self.rows[..i].last()?;
+        Some((&self.files[row.file?], row.line))
+    }
+
+    /// Decodes one unit: its header, and the line number program that
+    /// produces its rows.
+    fn unit(&mut self, r: &mut Reader, strings: &Strings) -> Option<()> {
+        let version = r.u16()?;
+        if !(2..=5).contains(&version) {
+            return None;
+        }
+        if version >= 5 {
+            // address_size, segment_selector_size
+            r.skip(2)?;
+        }
+        let header_len = r.u32()? as usize;
+        let program = r.pos + header_len;
+        let min_inst_len = r.u8()? as u64;
+        if version >= 4 {
+            // maximum_operations_per_instruction, only for VLIW
+            r.skip(1)?;
+        }
+        // default_is_stmt
+        r.skip(1)?;
+        let line_base = r.u8()? as i8 as i32;
+        let line_range = r.u8()?;
+        let opcode_base = r.u8()?;
+        let arg_counts: Vec<u8> =
+            (1..opcode_base).map(|_| r.u8()).collect::<Option<_>>()?;
+        if line_range == 0 {
+            return None;
+        }
+
+        // the unit's file numbers, mapped to `self.files`; they count
+        // from 1 before DWARF 5 and from 0 since
+        let mut files: Vec<Option<usize>> = Vec::new();
+        if version >= 5 {
+            r.entries(strings)?;
+            for name in r.entries(strings)? {
+                files.push(Some(self.add_file(name)));
+            }
+        } else {
+            files.push(None);
+            // include directories
+            while !r.cstr()?.is_empty() {}
+            loop {
+                let name = r.cstr()?;
+                if name.is_empty() {
+                    break;
+                }
+                // directory index, modification time, length
+                for _ in 0..3 {
+                    r.uleb()?;
+                }
+                files.push(Some(self.add_file(name)));
+            }
+        }
+
+        r.pos = program;
+        let (mut addr, mut file, mut line) = (0u64, 1usize, 1u32);
+        let row = |files: &[Option<usize>], addr, file: usize, line| Row {
+            addr,
+            file: files.get(file).copied().flatten(),
+            line,
+        };
+        while r.pos < r.data.len() {
+            let opcode = r.u8()?;
+            if opcode >= opcode_base {
+                // a special opcode advances both and adds a row
+                let adjusted = opcode - opcode_base;
+                addr = addr.wrapping_add(
+                    (adjusted / line_range) as u64 * min_inst_len,
+                );
+                line = line.wrapping_add_signed(
+                    line_base + (adjusted % line_range) as i32,
+                );
+                self.rows.push(row(&files, addr, file, line));
+                continue;
+            }
+            match opcode {
+                0 => {
+                    let len = r.uleb()? as usize;
+                    let next = r.pos + len;
+                    match r.u8()? {
+                        DW_LNE_END_SEQUENCE => {
+                            self.rows.push(Row {
+                                addr,
+                                file: None,
+                                line,
+                            });
+                            (addr, file, line) = (0, 1, 1);
+                        }
+                        DW_LNE_SET_ADDRESS => {
+                            addr = (0..len.saturating_sub(1))
+                                .map(|_| r.u8())
+                                .collect::<Option<Vec<_>>>()?
+                                .iter()
+                                .rev()
+                                .fold(0, |acc, &b| acc << 8 | b as u64);
+                        }
+                        DW_LNE_DEFINE_FILE => {
+                            let name = r.cstr()?;
+                            files.push(Some(self.add_file(name)));
+                        }
+                        _ => {}
+                    }
+                    r.pos = next;
+                }
+                DW_LNS_COPY => {
+                    self.rows.push(row(&files, addr, file, line))
+                }
+                DW_LNS_ADVANCE_PC => {
+                    let advance = r.uleb()?;
+                    addr = addr.wrapping_add(advance * min_inst_len);
+                }
+                DW_LNS_ADVANCE_LINE => {
+                    line = line.wrapping_add(r.sleb()? as u32);
+                }
+                DW_LNS_SET_FILE => file = r.uleb()? as usize,
+                DW_LNS_CONST_ADD_PC => {
+                    let adjusted = 255 - opcode_base;
+                    addr = addr.wrapping_add(
+                        (adjusted / line_range) as u64 * min_inst_len,
+                    );
+                }
+                DW_LNS_FIXED_ADVANCE_PC => {
+                    addr = addr.wrapping_add(r.u16()? as u64);
+                }
+                // everything else only sets flags this has no use for
+                _ => {
+                    for _ in 0..arg_counts[opcode as usize - 1] {
+                        r.uleb()?;
+                    }
+                }
+            }
+        }
+        Some(())
+    }
+
+    fn add_file(&mut self, name: String) -> usize {
+        self.files.push(name);
+        self.files.len() - 1
+    }
+}
+
+/// The string sections DWARF 5 file names can point into.
+struct Strings<'a> {
+    line_str: &'a [u8],
+    str: &'a [u8],
+}
+
+/// Reads little-endian DWARF data, returning `None` past the end.
+struct Reader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn new(data: &'a [u8], pos: usize) -> Self {
+        Reader { data, pos }
+    }
+
+    fn bytes(&mut self, len: usize) -> Option<&'a [u8]> {
+        let bytes = self.data.get(self.pos..self.pos.checked_add(len)?)?;
+        self.pos += len;
+        Some(bytes)
+    }
+
+    fn skip(&mut self, len: usize) -> Option<()> {
+        self.bytes(len).map(|_| ())
+    }
+
+    fn u8(&mut self) -> Option<u8> {
+        Some(self.bytes(1)?[0])
+    }
+
+    fn u16(&mut self) -> Option<u16> {
+        Some(u16::from_le_bytes(self.bytes(2)?.try_into().unwrap()))
+    }
+
+    fn u32(&mut self) -> Option<u32> {
+        Some(u32::from_le_bytes(self.bytes(4)?.try_into().unwrap()))
+    }
+
+    fn uleb(&mut self) -> Option<u64> {
+        let mut value = 0;
+        for shift in (0..64).step_by(7) {
+            let byte = self.u8()?;
+            value |= ((byte & 0x7f) as u64) << shift;
+            if byte & 0x80 == 0 {
+                return Some(value);
+            }
+        }
+        None
+    }
+
+    fn sleb(&mut self) -> Option<i64> {
+        let mut value = 0;
+        for shift in (0..64).step_by(7) {
+            let byte = self.u8()?;
+            value |= ((byte & 0x7f) as i64) << shift;
+            if byte & 0x80 == 0 {
+                // sign-extend from the last byte's sign bit
+                let unused = 64 - (shift + 7).min(64);
+                return Some(value << unused >> unused);
+            }
+        }
+        None
+    }
+
+    fn cstr(&mut self) -> Option<String> {
+        let len =
+            self.data.get(self.pos..)?.iter().position(|&b| b == 0)?;
+        let s = String::from_utf8_lossy(self.bytes(len)?).into_owned();
+        self.pos += 1;
+        Some(s)
+    }
+
+    /// Reads a DWARF 5 directory or file name table, returning the paths.
+    fn entries(&mut self, strings: &Strings) -> Option<Vec<String>> {
+        let formats: Vec<(u64, u64)> = (0..self.u8()?)
+            .map(|_| Some((self.uleb()?, self.uleb()?)))
+            .collect::<Option<_>>()?;
+        let mut paths = Vec::new();
+        for _ in 0..self.uleb()? {
+            let mut path = String::new();
+            for &(content, form) in &formats {
+                let value = self.form(form, strings)?;
+                if content == DW_LNCT_PATH {
+                    path = value.unwrap_or_default();
+                }
+            }
+            paths.push(path);
+        }
+        Some(paths)
+    }
+
+    /// Reads an attribute value in `form`, returning it if it's a string.
+    fn form(
+        &mut self,
+        form: u64,
+        strings: &Strings,
+    ) -> Option<Option<String>> {
+        let at = |section: &[u8], offset: u32| {
+            Reader::new(section, offset as usize).cstr()
+        };
+        let len = match form {
+            DW_FORM_STRING => return Some(Some(self.cstr()?)),
+            DW_FORM_LINE_STRP => {
+                return Some(at(strings.line_str, self.u32()?))
+            }
+            DW_FORM_STRP => return Some(at(strings.str, self.u32()?)),
+            DW_FORM_UDATA => {
+                self.uleb()?;
+                return Some(None);
+            }
+            DW_FORM_DATA1 => 1,
+            DW_FORM_DATA2 => 2,
+            DW_FORM_DATA4 => 4,
+            DW_FORM_DATA8 => 8,
+            DW_FORM_DATA16 => 16,
+            DW_FORM_BLOCK => self.uleb()? as usize,
+            _ => return None,
+        };
+        self.skip(len)?;
+        Some(None)
+    }
+}
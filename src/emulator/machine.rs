@@ -0,0 +1,532 @@
+use super::cpu::{Commit, Cpu, WatchKind, Watchpoint, Xlen};
+use super::error::LoadError;
+use super::instruction::Instruction;
+use super::stack;
+use super::syscall::Syscalls;
+use super::trace::{Record, Writer};
+use super::trap::Exception;
+use std::collections::BTreeSet;
+use std::fmt;
+use std::io;
+use std::path::Path;
+
+/// Why a program stopped running.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StopReason {
+    /// The program exited with this code, through the `exit` or
+    /// `exit_group` system call or `tohost`.
+    Exited(i32),
+    /// The program made a system call the emulator doesn't implement.
+    UnknownSyscall(u64),
+    /// The program ran into an illegal instruction without a trap handler.
+    IllegalInstruction { pc: u64, raw: u32 },
+    /// The program made a bad memory access without a trap handler.
+    MemoryFault { addr: u64, kind: FaultKind },
+    /// The program reached a breakpoint, or hit an `ebreak` without a
+    /// trap handler.
+    Breakpoint { pc: u64 },
+    /// The instruction at `pc`, encoded as `raw`, accessed memory under
+    /// the watchpoint at `addr`. `old` and `new` are what the memory it
+    /// accessed held before and after, the same for a load.
+    Watchpoint {
+        addr: u64,
+        kind: WatchKind,
+        pc: u64,
+        raw: u32,
+        old: u64,
+        new: u64,
+    },
+    /// The machine executed as many instructions as it was allowed to.
+    StepLimit,
+}
+
+/// The kind of access behind a [`StopReason::MemoryFault`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FaultKind {
+    Fetch,
+    Load,
+    Store,
+    MisalignedFetch,
+    MisalignedLoad,
+    MisalignedStore,
+    // page faults: the virtual address wasn't mapped, or not with the
+    // permissions the access needed
+    PageFetch,
+    PageLoad,
+    PageStore,
+}
+
+impl fmt::Display for StopReason {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            StopReason::Exited(code) => {
+                write!(f, "program exited with exit code {}", code)
+            }
+            StopReason::UnknownSyscall(n) => {
+                write!(f, "unimplemented system call {}", n)
+            }
+            StopReason::IllegalInstruction { pc, raw } => write!(
+                f,
+                "illegal instruction {:#010x} at {:#010x}",
+                raw, pc
+            ),
+            StopReason::MemoryFault { addr, kind } => {
+                let kind = match kind {
+                    FaultKind::Fetch => "fetch from",
+                    FaultKind::Load => "load from",
+                    FaultKind::Store => "store to",
+                    FaultKind::MisalignedFetch => "misaligned fetch from",
+                    FaultKind::MisalignedLoad => "misaligned load from",
+                    FaultKind::MisalignedStore => "misaligned store to",
+                    FaultKind::PageFetch => "fetch from virtual address",
+                    FaultKind::PageLoad => "load from virtual address",
+                    FaultKind::PageStore => "store to virtual address",
+                };
+                write!(f, "{} {:#010x} failed", kind, addr)
+            }
+            StopReason::Breakpoint { pc } => {
+                write!(f, "breakpoint at {:#010x}", pc)
+            }
+            StopReason::Watchpoint {
+                addr,
+                kind,
+                pc,
+                old,
+                new,
+                ..
+            } => {
+                let kind = match kind {
+                    WatchKind::Read => "read",
+                    WatchKind::Write => "write",
+                    WatchKind::Access => "access",
+                };
+                write!(
+                    f,
+                    "{} watchpoint at {:#010x} hit at {:#010x}: {:#x} -> {:#x}",
+                    kind, addr, pc, old, new
+                )
+            }
+            StopReason::StepLimit => write!(f, "step limit reached"),
+        }
+    }
+}
+
+/// What happened during a single [`Machine::step`].
+#[derive(Debug)]
+pub struct Step {
+    /// Address of the instruction.
+    pub pc: u64,
+    /// Its encoding, zero if it couldn't be fetched.
+    pub raw: u32,
+    pub inst: Instruction,
+    /// Set if the program stopped at this instruction.
+    pub stop: Option<StopReason>,
+}
+
+/// Configures a [`Machine`] and loads a program into it.
+pub struct MachineBuilder {
+    mem_size: usize,
+    xlen: Option<Xlen>,
+    pc: Option<u64>,
+    stack: bool,
+    stack_top: Option<u64>,
+    args: Vec<String>,
+    env: Vec<String>,
+    step_limit: Option<u64>,
+    log_commits: bool,
+    trace: Option<Writer>,
+}
+
+impl MachineBuilder {
+    /// Sets the memory size in KiB (default = 16).
+    pub fn mem_size(mut self, mem_size: usize) -> Self {
+        self.mem_size = mem_size;
+        self
+    }
+
+    /// Runs the program as RV32 or RV64 regardless of its ELF class.
+    pub fn xlen(mut self, xlen: Xlen) -> Self {
+        self.xlen = Some(xlen);
+        self
+    }
+
+    /// Starts execution at `pc` instead of the ELF entry point.
+    pub fn pc(mut self, pc: u64) -> Self {
+        self.pc = Some(pc);
+        self
+    }
+
+    /// Sets up the stack before execution the way Linux does, with the
+    /// program's arguments, environment and auxiliary vector on it.
+    pub fn stack(mut self, stack: bool) -> Self {
+        self.stack = stack;
+        self
+    }
+
+    /// Puts the top of the stack at `addr` instead of the end of memory.
+    pub fn stack_top(mut self, addr: u64) -> Self {
+        self.stack_top = Some(addr);
+        self
+    }
+
+    /// Passes `args` to the program, after its own path as `argv[0]`.
+    pub fn args<I, S>(mut self, args: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        self.args.extend(args.into_iter().map(Into::into));
+        self
+    }
+
+    /// Adds a `KEY=VALUE` variable to the program's environment.
+    pub fn env(mut self, var: impl Into<String>) -> Self {
+        self.env.push(var.into());
+        self
+    }
+
+    /// Stops the program after it has executed `steps` instructions.
+    pub fn step_limit(mut self, steps: u64) -> Self {
+        self.step_limit = Some(steps);
+        self
+    }
+
+    /// Records what each instruction changes, for [`Machine::commit`],
+    /// at some cost in speed.
+    pub fn log_commits(mut self, log_commits: bool) -> Self {
+        self.log_commits = log_commits;
+        self
+    }
+
+    /// Records every instruction that retires with `trace`. Implies
+    /// [`MachineBuilder::log_commits`].
+    pub fn trace(mut self, trace: Writer) -> Self {
+        self.trace = Some(trace);
+        self
+    }
+
+    /// Creates the machine and loads the ELF file at `path` into it.
+    pub fn load(
+        self,
+        path: impl AsRef<Path>,
+    ) -> Result<Machine, LoadError> {
+        let path = path.as_ref();
+        let mut builder = Cpu::builder().mem_size(self.mem_size);
+        if let Some(xlen) = self.xlen {
+            builder = builder.xlen(xlen);
+        }
+        let mut cpu = builder.build();
+        cpu.load(path)?;
+        cpu.log_commits = self.log_commits || self.trace.is_some();
+        if let Some(pc) = self.pc {
+            cpu.set_pc(pc);
+        }
+        let top = self.stack_top.unwrap_or_else(|| {
+            cpu.mem_base().wrapping_add(cpu.mem_size() as u64)
+        });
+        if self.stack {
+            let mut args = vec![path.to_string_lossy().into_owned()];
+            args.extend(self.args);
+            stack::build(&mut cpu, top, &args, &self.env)
+                .ok_or(LoadError::StackOutOfMemory { top })?;
+        }
+        Ok(Machine {
+            syscalls: Syscalls::new(&cpu, top),
+            cpu,
+            steps: 0,
+            step_limit: self.step_limit,
+            breakpoints: BTreeSet::new(),
+            trace: self.trace,
+            trace_error: None,
+        })
+    }
+}
+
+/// A [`Cpu`] running a program, along with the execution environment
+/// the emulator provides: Linux system calls for programs that don't
+/// handle `ecall` themselves, and `tohost`.
+pub struct Machine {
+    cpu: Cpu,
+    syscalls: Syscalls,
+    steps: u64,
+    step_limit: Option<u64>,
+    breakpoints: BTreeSet<u64>,
+    trace: Option<Writer>,
+    /// Why the trace was cut short, if it was.
+    trace_error: Option<io::Error>,
+}
+
+impl Machine {
+    pub fn builder() -> MachineBuilder {
+        MachineBuilder {
+            mem_size: 16,
+            xlen: None,
+            pc: None,
+            stack: false,
+            stack_top: None,
+            args: Vec::new(),
+            env: Vec::new(),
+            step_limit: None,
+            log_commits: false,
+            trace: None,
+        }
+    }
+
+    pub fn cpu(&self) -> &Cpu {
+        &self.cpu
+    }
+
+    pub fn cpu_mut(&mut self) -> &mut Cpu {
+        &mut self.cpu
+    }
+
+    /// The number of instructions executed so far, including ones that
+    /// trapped.
+    pub fn steps(&self) -> u64 {
+        self.steps
+    }
+
+    /// Flushes the trace, if there is one, and reports whether writing it
+    /// failed at any point. A trace that fails stops being written.
+    pub fn finish_trace(&mut self) -> io::Result<()> {
+        if let Some(err) = self.trace_error.take() {
+            return Err(err);
+        }
+        match &mut self.trace {
+            Some(trace) => trace.flush(),
+            None => Ok(()),
+        }
+    }
+
+    /// What the last instruction changed, if the machine logs commits
+    /// and the instruction retired.
+    pub fn commit(&self) -> Option<&Commit> {
+        self.cpu.commit.as_ref()
+    }
+
+    /// Stops the program before it executes the instruction at `addr`.
+    pub fn add_breakpoint(&mut self, addr: u64) {
+        self.breakpoints.insert(addr);
+    }
+
+    /// Returns false if there was no breakpoint at `addr`.
+    pub fn remove_breakpoint(&mut self, addr: u64) -> bool {
+        self.breakpoints.remove(&addr)
+    }
+
+    /// The breakpoint addresses, in ascending order.
+    pub fn breakpoints(&self) -> impl Iterator<Item = u64> + '_ {
+        self.breakpoints.iter().copied()
+    }
+
+    pub fn add_watchpoint(&mut self, watchpoint: Watchpoint) {
+        self.cpu.watchpoints.push(watchpoint);
+    }
+
+    /// Returns false if there was no such watchpoint.
+    pub fn remove_watchpoint(&mut self, watchpoint: Watchpoint) -> bool {
+        let watchpoints = &mut self.cpu.watchpoints;
+        match watchpoints.iter().position(|w| *w == watchpoint) {
+            Some(index) => {
+                watchpoints.remove(index);
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub fn watchpoints(&self) -> &[Watchpoint] {
+        &self.cpu.watchpoints
+    }
+
+    /// Evaluates an address or value typed at a prompt: registers (`x5`,
+    /// `a0`, `pc`, or `$a0` as in gdb), symbols and hex numbers, added
+    /// and subtracted, as in `sp+8` or `buf-0x10`. Register names win
+    /// over hex, so the numbers 0xa0 to 0xa7 need their `0x`.
+    pub fn eval(&self, expr: &str) -> Result<u64, String> {
+        let cpu = &self.cpu;
+        let term = |term: &str| {
+            let name = term.strip_prefix('$').unwrap_or(term);
+            if name == "pc" {
+                return Ok(cpu.pc());
+            }
+            if let Some(reg) = Cpu::register_number(name) {
+                return Ok(cpu.register(reg));
+            }
+            if term.starts_with('$') {
+                return Err(format!("no register called {}", name));
+            }
+            if let Some(addr) = cpu.symbols().find(term) {
+                return Ok(addr);
+            }
+            u64::from_str_radix(term.trim_start_matches("0x"), 16).map_err(
+                |_| format!("no register or symbol called {}", term),
+            )
+        };
+        let mut value = 0u64;
+        let mut negate = false;
+        let mut first = true;
+        let mut rest = expr;
+        loop {
+            let end = rest.find(['+', '-']).unwrap_or(rest.len());
+            let text = rest[..end].trim();
+            // a leading sign applies to an implied zero
+            if !(first && text.is_empty()) {
+                let term = term(text)?;
+                value = match negate {
+                    true => value.wrapping_sub(term),
+                    false => value.wrapping_add(term),
+                };
+            }
+            if end == rest.len() {
+                return Ok(value);
+            }
+            first = false;
+            negate = rest.as_bytes()[end] == b'-';
+            rest = &rest[end + 1..];
+        }
+    }
+
+    /// Executes a single instruction. The first instruction always runs,
+    /// even if there is a breakpoint on it, so stepping moves past it.
+    pub fn step(&mut self) -> Step {
+        let pc = self.cpu.pc();
+        let (inst, exception) = self.cpu.step();
+        self.steps += 1;
+        let raised = exception.is_some();
+        let stop = self.stop_reason(pc, exception).or_else(|| {
+            if let Some((w, old, new)) = self.cpu.watch_hit {
+                return Some(StopReason::Watchpoint {
+                    addr: w.addr,
+                    kind: w.kind,
+                    pc,
+                    raw: inst.raw,
+                    old,
+                    new,
+                });
+            }
+            let next = self.cpu.pc();
+            if self.breakpoints.contains(&next) {
+                return Some(StopReason::Breakpoint { pc: next });
+            }
+            let limit = self.step_limit?;
+            (self.steps >= limit).then_some(StopReason::StepLimit)
+        });
+        if self.cpu.log_commits {
+            self.commit_step(pc, &inst, raised, stop.is_some());
+        }
+        Step {
+            pc,
+            raw: inst.raw,
+            inst,
+            stop,
+        }
+    }
+
+    /// Completes the commit log entry of an instruction and adds it to
+    /// the trace.
+    fn commit_step(
+        &mut self,
+        pc: u64,
+        inst: &Instruction,
+        raised: bool,
+        stopped: bool,
+    ) {
+        // an instruction that raised an exception only retires if it was
+        // an `ecall` the emulator handled, and then it returns in a0
+        if raised {
+            let a0 = self.cpu.register(10);
+            match (stopped, &mut self.cpu.commit) {
+                (false, Some(commit)) => commit.reg = Some((10, a0)),
+                _ => self.cpu.commit = None,
+            }
+        }
+        if let (Some(trace), Some(commit)) =
+            (&mut self.trace, &self.cpu.commit)
+        {
+            let record = Record::new(pc, inst, commit.clone());
+            if let Err(err) = trace.write(&record) {
+                self.trace = None;
+                self.trace_error = Some(err);
+            }
+        }
+    }
+
+    /// Runs until the program stops, or `stop` returns true before an
+    /// instruction is executed, in which case it returns `None`.
+    pub fn run_until(
+        &mut self,
+        mut stop: impl FnMut(&Cpu) -> bool,
+    ) -> Option<StopReason> {
+        loop {
+            if stop(&self.cpu) {
+                return None;
+            }
+            if let Some(stop) = self.step().stop {
+                return Some(stop);
+            }
+        }
+    }
+
+    /// Runs until the program stops.
+    pub fn run(&mut self) -> StopReason {
+        loop {
+            if let Some(stop) = self.step().stop {
+                return stop;
+            }
+        }
+    }
+
+    /// Decides whether the program is over after a step, either because
+    /// it reported its exit code or because of an exception it didn't
+    /// handle.
+    fn stop_reason(
+        &mut self,
+        pc: u64,
+        exception: Option<Exception>,
+    ) -> Option<StopReason> {
+        if let Some(code) = self.cpu.exit_code() {
+            return Some(StopReason::Exited(code));
+        }
+        let fault = |addr, kind| StopReason::MemoryFault { addr, kind };
+        let stop = match exception? {
+            Exception::EcallFromU
+            | Exception::EcallFromS
+            | Exception::EcallFromM => {
+                return self.syscalls.handle(&mut self.cpu);
+            }
+            Exception::IllegalInstruction(raw) => {
+                StopReason::IllegalInstruction { pc, raw }
+            }
+            Exception::Breakpoint(pc) => StopReason::Breakpoint { pc },
+            Exception::InstructionAddressMisaligned(addr) => {
+                fault(addr, FaultKind::MisalignedFetch)
+            }
+            Exception::InstructionAccessFault(addr) => {
+                fault(addr, FaultKind::Fetch)
+            }
+            Exception::LoadAddressMisaligned(addr) => {
+                fault(addr, FaultKind::MisalignedLoad)
+            }
+            Exception::LoadAccessFault(addr) => {
+                fault(addr, FaultKind::Load)
+            }
+            Exception::StoreAddressMisaligned(addr) => {
+                fault(addr, FaultKind::MisalignedStore)
+            }
+            Exception::StoreAccessFault(addr) => {
+                fault(addr, FaultKind::Store)
+            }
+            Exception::InstructionPageFault(addr) => {
+                fault(addr, FaultKind::PageFetch)
+            }
+            Exception::LoadPageFault(addr) => {
+                fault(addr, FaultKind::PageLoad)
+            }
+            Exception::StorePageFault(addr) => {
+                fault(addr, FaultKind::PageStore)
+            }
+        };
+        Some(stop)
+    }
+}
@@ -0,0 +1,82 @@
+use super::trap::Exception;
+
+// Sv32 page table entry bits
+pub(crate) const PTE_V: u64 = 1 << 0;
+pub(crate) const PTE_R: u64 = 1 << 1;
+pub(crate) const PTE_W: u64 = 1 << 2;
+pub(crate) const PTE_X: u64 = 1 << 3;
+pub(crate) const PTE_U: u64 = 1 << 4;
+pub(crate) const PTE_A: u64 = 1 << 6;
+pub(crate) const PTE_D: u64 = 1 << 7;
+
+/// Number of entries, a power of two.
+const ENTRIES: usize = 64;
+
+/// The kind of memory access being translated.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Access {
+    Fetch,
+    Load,
+    /// Stores and AMOs.
+    Store,
+}
+
+impl Access {
+    pub(crate) fn page_fault(self, addr: u64) -> Exception {
+        match self {
+            Access::Fetch => Exception::InstructionPageFault(addr),
+            Access::Load => Exception::LoadPageFault(addr),
+            Access::Store => Exception::StorePageFault(addr),
+        }
+    }
+
+    pub(crate) fn access_fault(self, addr: u64) -> Exception {
+        match self {
+            Access::Fetch => Exception::InstructionAccessFault(addr),
+            Access::Load => Exception::LoadAccessFault(addr),
+            Access::Store => Exception::StoreAccessFault(addr),
+        }
+    }
+}
+
+/// A translation of one 4 KiB virtual page, which may be part of a
+/// megapage.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct TlbEntry {
+    pub(crate) vpn: u64,
+    pub(crate) ppn: u64,
+    /// The leaf PTE, with its A bit and, after a store, its D bit set.
+    pub(crate) pte: u64,
+}
+
+/// A direct-mapped cache of address translations, indexed by virtual
+/// page number. Like real TLBs it isn't kept coherent with the page
+/// tables: software flushes it with `sfence.vma`.
+pub(crate) struct Tlb {
+    entries: [Option<TlbEntry>; ENTRIES],
+}
+
+impl Tlb {
+    pub(crate) fn new() -> Self {
+        Tlb {
+            entries: [None; ENTRIES],
+        }
+    }
+
+    fn index(vpn: u64) -> usize {
+        vpn as usize & (ENTRIES - 1)
+    }
+
+    /// The translation of the page `vpn`, if it's still cached.
+    pub(crate) fn get(&self, vpn: u64) -> Option<TlbEntry> {
+        self.entries[Tlb::index(vpn)].filter(|entry| entry.vpn == vpn)
+    }
+
+    pub(crate) fn insert(&mut self, entry: TlbEntry) {
+        self.entries[Tlb::index(entry.vpn)] = Some(entry);
+    }
+
+    pub(crate) fn flush(&mut self) {
+        self.entries.fill(None);
+    }
+}
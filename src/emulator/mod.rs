@@ -1,2 +1,17 @@
 pub mod cpu;
+pub mod csr;
+pub mod disasm;
+pub mod error;
+pub mod float;
+pub mod gdb;
+pub mod icache;
 pub mod instruction;
+pub mod lines;
+pub mod machine;
+pub mod mmu;
+pub mod pmp;
+pub mod stack;
+pub mod symbols;
+pub mod syscall;
+pub mod trace;
+pub mod trap;
@@ -0,0 +1,113 @@
+use super::csr::PRV_M;
+use super::mmu::Access;
+
+/// Number of PMP entries.
+pub(crate) const ENTRIES: usize = 16;
+
+// pmpcfg fields, one byte per entry
+pub(crate) const PMP_R: u8 = 1 << 0;
+pub(crate) const PMP_W: u8 = 1 << 1;
+pub(crate) const PMP_X: u8 = 1 << 2;
+/// How the entry matches addresses: off, TOR, NA4 or NAPOT.
+pub(crate) const PMP_A: u8 = 0b11 << 3;
+/// Locks the entry until reset, and applies it to machine mode too.
+pub(crate) const PMP_L: u8 = 1 << 7;
+
+/// Top of range: from the previous entry's address up to this one's.
+pub(crate) const PMP_TOR: u8 = 1 << 3;
+/// Naturally aligned four-byte region.
+pub(crate) const PMP_NA4: u8 = 2 << 3;
+/// Naturally aligned power-of-two region, at least eight bytes.
+pub(crate) const PMP_NAPOT: u8 = 3 << 3;
+
+/// The bytes from `start` up to `end` that an entry covers.
+#[derive(Debug, Clone, Copy)]
+struct Region {
+    start: u64,
+    end: u64,
+    cfg: u8,
+}
+
+/// The physical memory protection regions, decoded from the `pmpcfg`
+/// and `pmpaddr` CSRs whenever they change.
+pub(crate) struct Pmp {
+    /// The entries that aren't off, lowest-numbered first.
+    regions: Vec<Region>,
+    /// Whether any of them is locked, so machine mode has to check.
+    locked: bool,
+}
+
+impl Pmp {
+    pub(crate) fn new() -> Self {
+        Pmp {
+            regions: Vec::new(),
+            locked: false,
+        }
+    }
+
+    /// Decodes the configuration bytes and addresses of all entries.
+    /// The addresses are physical addresses shifted right by two.
+    pub(crate) fn update(
+        &mut self,
+        cfgs: &[u8; ENTRIES],
+        addrs: &[u64; ENTRIES],
+    ) {
+        self.regions.clear();
+        for (i, (&cfg, &addr)) in cfgs.iter().zip(addrs).enumerate() {
+            let (start, end) = match cfg & PMP_A {
+                PMP_TOR => {
+                    let start = match i {
+                        0 => 0,
+                        _ => addrs[i - 1] << 2,
+                    };
+                    (start, addr << 2)
+                }
+                PMP_NA4 => (addr << 2, (addr << 2) + 4),
+                PMP_NAPOT => {
+                    // the trailing ones encode the size
+                    let ones = addr.trailing_ones();
+                    let start = (addr >> ones << ones) << 2;
+                    (start, start + (8 << ones))
+                }
+                _ => continue,
+            };
+            self.regions.push(Region { start, end, cfg });
+        }
+        self.locked = self.regions.iter().any(|r| r.cfg & PMP_L != 0);
+    }
+
+    /// Whether an `access` of `size` bytes at the physical address
+    /// `addr` is allowed at `privilege`. The lowest-numbered entry that
+    /// matches any of the bytes decides, and it has to match all of them.
+    /// Machine mode passes unless the entry is locked, and so does an
+    /// access no entry matches, which fails in the other modes.
+    pub(crate) fn allows(
+        &self,
+        addr: u64,
+        size: usize,
+        access: Access,
+        privilege: u32,
+    ) -> bool {
+        if privilege == PRV_M && !self.locked {
+            return true;
+        }
+        let end = addr.wrapping_add(size as u64);
+        let Some(region) =
+            self.regions.iter().find(|r| addr < r.end && r.start < end)
+        else {
+            return privilege == PRV_M;
+        };
+        if addr < region.start || end > region.end {
+            return false;
+        }
+        if privilege == PRV_M && region.cfg & PMP_L == 0 {
+            return true;
+        }
+        let permission = match access {
+            Access::Fetch => PMP_X,
+            Access::Load => PMP_R,
+            Access::Store => PMP_W,
+        };
+        region.cfg & permission != 0
+    }
+}
@@ -0,0 +1,113 @@
+//! The initial process stack the System V ABI promises a program at its
+//! entry point: `argc`, the `argv` and `envp` pointer arrays and the
+//! auxiliary vector, with the strings they point to above them. Each
+//! entry is an XLEN-wide word.
+
+use super::cpu::Cpu;
+use super::csr::MISA;
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+
+// auxiliary vector entry types
+const AT_NULL: u64 = 0;
+const AT_PHDR: u64 = 3;
+const AT_PHENT: u64 = 4;
+const AT_PHNUM: u64 = 5;
+const AT_PAGESZ: u64 = 6;
+const AT_ENTRY: u64 = 9;
+const AT_UID: u64 = 11;
+const AT_EUID: u64 = 12;
+const AT_GID: u64 = 13;
+const AT_EGID: u64 = 14;
+const AT_HWCAP: u64 = 16;
+const AT_SECURE: u64 = 23;
+const AT_RANDOM: u64 = 25;
+
+const PAGE_SIZE: u64 = 4096;
+
+/// Builds the initial stack below `top` and points `sp` at `argc`.
+/// Returns `None` if it doesn't fit in memory.
+pub(crate) fn build(
+    cpu: &mut Cpu,
+    top: u64,
+    args: &[String],
+    env: &[String],
+) -> Option<()> {
+    let mut sp = top;
+    let width = cpu.xlen().bits() as u64 / 8;
+
+    // 16 random bytes for the C library to seed stack protectors with
+    let random = [random(), random()].concat();
+    let at_random = push(cpu, &mut sp, &random)?;
+    let mut strings = |cpu: &mut Cpu, strings: &[String]| {
+        strings
+            .iter()
+            .map(|s| push(cpu, &mut sp, &[s.as_bytes(), &[0]].concat()))
+            .collect::<Option<Vec<_>>>()
+    };
+    let argv = strings(cpu, args)?;
+    let envp = strings(cpu, env)?;
+    // programs find their TLS template through AT_PHDR, so the headers
+    // are copied onto the stack if no segment loaded them
+    let image = cpu.image().clone();
+    let phdr = match image.phdr {
+        Some(phdr) => phdr,
+        None => {
+            sp &= !(width - 1);
+            push(cpu, &mut sp, &image.phdrs)?
+        }
+    };
+
+    let auxv = [
+        (AT_PHDR, phdr),
+        (AT_PHENT, image.phent as u64),
+        (
+            AT_PHNUM,
+            (image.phdrs.len() as u32 / image.phent.max(1)) as u64,
+        ),
+        (AT_PAGESZ, PAGE_SIZE),
+        (AT_ENTRY, image.entry),
+        (AT_UID, 0),
+        (AT_EUID, 0),
+        (AT_GID, 0),
+        (AT_EGID, 0),
+        (AT_HWCAP, hwcap(cpu)),
+        (AT_SECURE, 0),
+        (AT_RANDOM, at_random),
+        (AT_NULL, 0),
+    ];
+    let mut words = vec![argv.len() as u64];
+    words.extend(&argv);
+    words.push(0);
+    words.extend(&envp);
+    words.push(0);
+    words.extend(auxv.iter().flat_map(|&(key, value)| [key, value]));
+
+    // the ABI wants `sp` 16-byte aligned at the entry point
+    let bytes: Vec<u8> = words
+        .iter()
+        .flat_map(|w| w.to_le_bytes().into_iter().take(width as usize))
+        .collect();
+    sp = sp.checked_sub(bytes.len() as u64)? & !15;
+    cpu.write_memory(sp, &bytes)?;
+    cpu.set_register(2, sp);
+    Some(())
+}
+
+/// Copies `bytes` into memory just below `sp` and moves `sp` down to
+/// them.
+fn push(cpu: &mut Cpu, sp: &mut u64, bytes: &[u8]) -> Option<u64> {
+    *sp = sp.checked_sub(bytes.len() as u64)?;
+    cpu.write_memory(*sp, bytes)?;
+    Some(*sp)
+}
+
+/// The single-letter extensions in `misa`, which is what Linux reports
+/// in AT_HWCAP.
+fn hwcap(cpu: &Cpu) -> u64 {
+    cpu.read_csr(MISA) & 0x03ff_ffff
+}
+
+fn random() -> [u8; 8] {
+    RandomState::new().build_hasher().finish().to_le_bytes()
+}
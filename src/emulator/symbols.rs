@@ -0,0 +1,113 @@
+//! Names for addresses, taken from an ELF file's symbol table.
+
+use elf_rs::{Elf, ElfFile, SectionType};
+
+// st_info types and bindings
+const STT_FUNC: u8 = 2;
+const STT_SECTION: u8 = 3;
+const STT_FILE: u8 = 4;
+const STB_LOCAL: u8 = 0;
+const SHN_UNDEF: u16 = 0;
+
+/// A named address.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Symbol {
+    pub name: String,
+    pub addr: u64,
+    pub size: u64,
+}
+
+/// A program's symbols, sorted by address.
+#[derive(Debug, Clone, Default)]
+pub struct Symbols {
+    /// Where several symbols share an address, the one to name it by
+    /// comes first: global before local, functions before anything else.
+    symbols: Vec<Symbol>,
+}
+
+impl Symbols {
+    /// Reads the `.symtab` of `elf`. Section, file and undefined symbols
+    /// are left out, as are the `$x`/`$d` mapping symbols. A stripped
+    /// file has no symbols.
+    pub fn from_elf(elf: &Elf) -> Self {
+        let mut symbols = Vec::new();
+        let symtab = elf
+            .section_header_iter()
+            .find(|shdr| shdr.sh_type() == SectionType::SHT_SYMTAB);
+        let strtab = symtab.as_ref().and_then(|symtab| {
+            elf.section_header_nth(symtab.link() as usize)
+        });
+        if let (Some(symtab), Some(strtab)) = (symtab, strtab) {
+            let strtab = strtab.content();
+            let field = |bytes: &[u8], at: usize, len: usize| {
+                bytes[at..at + len]
+                    .iter()
+                    .rev()
+                    .fold(0, |acc, &b| acc << 8 | b as u64)
+            };
+            // Elf32_Sym: st_name, st_value, st_size, st_info, st_other,
+            // st_shndx; Elf64_Sym moves st_value and st_size to the end
+            // and widens them
+            let elf64 = matches!(elf, Elf::Elf64(_));
+            let (entry, info_at, value_at, word) = match elf64 {
+                false => (16, 12, 4, 4),
+                true => (24, 4, 8, 8),
+            };
+            for sym in symtab.content().chunks_exact(entry) {
+                let (info, shndx) =
+                    (sym[info_at], field(sym, info_at + 2, 2) as u16);
+                let start = field(sym, 0, 4) as usize;
+                let name = match strtab.get(start..).and_then(|s| {
+                    s.iter().position(|&b| b == 0).map(|len| &s[..len])
+                }) {
+                    Some(name) => String::from_utf8_lossy(name),
+                    None => continue,
+                };
+                if name.is_empty()
+                    || name.starts_with('$')
+                    || matches!(info & 0xf, STT_SECTION | STT_FILE)
+                    || shndx == SHN_UNDEF
+                {
+                    continue;
+                }
+                let rank =
+                    (info >> 4 == STB_LOCAL, info & 0xf != STT_FUNC);
+                symbols.push((
+                    rank,
+                    Symbol {
+                        name: name.into_owned(),
+                        addr: field(sym, value_at, word),
+                        size: field(sym, value_at + word, word),
+                    },
+                ));
+            }
+        }
+        symbols.sort_by_key(|(rank, sym)| (sym.addr, *rank));
+        Symbols {
+            symbols: symbols.into_iter().map(|(_, sym)| sym).collect(),
+        }
+    }
+
+    /// The address of the symbol called `name`.
+    pub fn find(&self, name: &str) -> Option<u64> {
+        self.symbols
+            .iter()
+            .find(|sym| sym.name == name)
+            .map(|sym| sym.addr)
+    }
+
+    /// The symbol that starts at `addr`, if any.
+    pub fn at(&self, addr: u64) -> Option<&Symbol> {
+        let i = self.symbols.partition_point(|sym| sym.addr < addr);
+        self.symbols.get(i).filter(|sym| sym.addr == addr)
+    }
+
+    /// The nearest symbol at or below `addr`, and how far past it `addr`
+    /// is.
+    pub fn lookup(&self, addr: u64) -> Option<(&Symbol, u64)> {
+        let i = self.symbols.partition_point(|sym| sym.addr <= addr);
+        let below = self.symbols[..i].last()?;
+        let sym = self.at(below.addr)?;
+        Some((sym, addr - sym.addr))
+    }
+}
@@ -0,0 +1,566 @@
+//! Linux system calls for statically linked user-mode programs, in the
+//! spirit of qemu-user: guest file descriptors map to host files, and
+//! the heap and anonymous mappings are carved out of the emulator's
+//! memory.
+
+use super::cpu::{Cpu, Xlen};
+use super::machine::StopReason;
+use std::fs::{File, Metadata, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+const PAGE_SIZE: u64 = 4096;
+
+// RISC-V Linux system call numbers
+const IOCTL: u64 = 29;
+const OPENAT: u64 = 56;
+const CLOSE: u64 = 57;
+const LSEEK: u64 = 62;
+const READ: u64 = 63;
+const WRITE: u64 = 64;
+const READV: u64 = 65;
+const WRITEV: u64 = 66;
+const FSTAT: u64 = 80;
+const EXIT: u64 = 93;
+const EXIT_GROUP: u64 = 94;
+const SET_TID_ADDRESS: u64 = 96;
+const CLOCK_GETTIME: u64 = 113;
+const RT_SIGACTION: u64 = 134;
+const RT_SIGPROCMASK: u64 = 135;
+const UNAME: u64 = 160;
+const GETTIMEOFDAY: u64 = 169;
+const GETPID: u64 = 172;
+const GETPPID: u64 = 173;
+const GETUID: u64 = 174;
+const GETEUID: u64 = 175;
+const GETGID: u64 = 176;
+const GETEGID: u64 = 177;
+const GETTID: u64 = 178;
+const BRK: u64 = 214;
+const MUNMAP: u64 = 215;
+const MMAP: u64 = 222;
+const CLOCK_GETTIME64: u64 = 403;
+
+// errno values
+const ENOENT: u64 = 2;
+const EIO: u64 = 5;
+const EBADF: u64 = 9;
+const ENOMEM: u64 = 12;
+const EACCES: u64 = 13;
+const EFAULT: u64 = 14;
+const EEXIST: u64 = 17;
+const EINVAL: u64 = 22;
+const ENODEV: u64 = 19;
+const ENOTTY: u64 = 25;
+const ESPIPE: u64 = 29;
+const EOVERFLOW: u64 = 75;
+const ENOTSUP: u64 = 95;
+
+const AT_FDCWD: i32 = -100;
+const O_ACCMODE: u64 = 0o3;
+const O_WRONLY: u64 = 0o1;
+const O_RDWR: u64 = 0o2;
+const O_CREAT: u64 = 0o100;
+const O_EXCL: u64 = 0o200;
+const O_TRUNC: u64 = 0o1000;
+const O_APPEND: u64 = 0o2000;
+const MAP_FIXED: u64 = 0x10;
+const MAP_ANONYMOUS: u64 = 0x20;
+const CLOCK_REALTIME: u64 = 0;
+const CLOCK_TAI: u64 = 11;
+
+/// The result of a system call, or the errno it fails with.
+type SysResult = Result<u64, u64>;
+
+enum Fd {
+    Stdin,
+    Stdout,
+    Stderr,
+    File(File),
+}
+
+/// The state the system calls keep between calls.
+pub(crate) struct Syscalls {
+    /// Open files, indexed by guest file descriptor.
+    fds: Vec<Option<Fd>>,
+    /// Where the heap starts and the current program break.
+    brk_start: u64,
+    brk: u64,
+    /// Anonymous mappings grow down from `mmap_end`; `mmap_top` is the
+    /// lowest one so far.
+    mmap_end: u64,
+    mmap_top: u64,
+    start: Instant,
+}
+
+impl Syscalls {
+    /// Lays out the heap after the program loaded into `cpu`. A quarter
+    /// of memory below `stack_top` is left to the stack.
+    pub(crate) fn new(cpu: &Cpu, stack_top: u64) -> Self {
+        let brk_start = page_align(cpu.image().end);
+        let mmap_end = stack_top.wrapping_sub(cpu.mem_size() as u64 / 4)
+            & !(PAGE_SIZE - 1);
+        Syscalls {
+            fds: vec![Some(Fd::Stdin), Some(Fd::Stdout), Some(Fd::Stderr)],
+            brk_start,
+            brk: brk_start,
+            mmap_end,
+            mmap_top: mmap_end,
+            start: Instant::now(),
+        }
+    }
+
+    /// Carries out the system call the program asked for with `ecall`
+    /// and moves on to the next instruction, unless the program exits or
+    /// the call isn't implemented.
+    pub(crate) fn handle(&mut self, cpu: &mut Cpu) -> Option<StopReason> {
+        let n = cpu.register(17);
+        let a = |i: usize| cpu.register(10 + i);
+        let (a0, a1, a2, a3, a4) = (a(0), a(1), a(2), a(3), a(4));
+        let result = match n {
+            EXIT | EXIT_GROUP => {
+                return Some(StopReason::Exited(a0 as i32))
+            }
+            READ => self.read(cpu, a0, a1, a2),
+            WRITE => self.write(cpu, a0, a1, a2),
+            READV => self.readv(cpu, a0, a1, a2),
+            WRITEV => self.writev(cpu, a0, a1, a2),
+            OPENAT => self.openat(cpu, a0 as i32, a1, a2, a3),
+            CLOSE => self.close(a0),
+            LSEEK => self.lseek(cpu, a0, a1, a2, a3, a4),
+            FSTAT => self.fstat(cpu, a0, a1),
+            IOCTL => self.fd(a0).and(Err(ENOTTY)),
+            BRK => Ok(self.brk(cpu, a0)),
+            MMAP => self.mmap(cpu, a0, a1, a3),
+            MUNMAP => self.munmap(a0, a1),
+            CLOCK_GETTIME => self.clock_gettime(cpu, a0, a1, false),
+            CLOCK_GETTIME64 => self.clock_gettime(cpu, a0, a1, true),
+            GETTIMEOFDAY => gettimeofday(cpu, a0, a1),
+            UNAME => uname(cpu, a0),
+            GETPID | GETTID | SET_TID_ADDRESS => {
+                Ok(std::process::id() as u64)
+            }
+            GETPPID => Ok(1),
+            GETUID | GETEUID | GETGID | GETEGID => Ok(0),
+            // signals are never delivered, so there's nothing to set up
+            RT_SIGACTION | RT_SIGPROCMASK => Ok(0),
+            n => return Some(StopReason::UnknownSyscall(n)),
+        };
+        let ret = match result {
+            Ok(value) => value,
+            Err(errno) => errno.wrapping_neg(),
+        };
+        cpu.set_register(10, ret);
+        cpu.set_pc(cpu.pc().wrapping_add(4));
+        None
+    }
+
+    fn fd(&mut self, fd: u64) -> Result<&mut Fd, u64> {
+        match self.fds.get_mut(fd as usize) {
+            Some(Some(fd)) => Ok(fd),
+            _ => Err(EBADF),
+        }
+    }
+
+    fn read(
+        &mut self,
+        cpu: &mut Cpu,
+        fd: u64,
+        buf: u64,
+        len: u64,
+    ) -> SysResult {
+        cpu.read_memory(buf, len as usize).ok_or(EFAULT)?;
+        let mut bytes = vec![0; len as usize];
+        let n = match self.fd(fd)? {
+            Fd::Stdin => io::stdin().read(&mut bytes),
+            Fd::File(file) => file.read(&mut bytes),
+            Fd::Stdout | Fd::Stderr => return Err(EBADF),
+        }
+        .map_err(errno)?;
+        cpu.write_memory(buf, &bytes[..n]).ok_or(EFAULT)?;
+        Ok(n as u64)
+    }
+
+    fn write(
+        &mut self,
+        cpu: &Cpu,
+        fd: u64,
+        buf: u64,
+        len: u64,
+    ) -> SysResult {
+        let bytes = cpu.read_memory(buf, len as usize).ok_or(EFAULT)?;
+        let n = match self.fd(fd)? {
+            Fd::Stdin => return Err(EBADF),
+            // written out right away so the output interleaves properly
+            // with the emulator's own
+            Fd::Stdout => {
+                let mut stdout = io::stdout();
+                stdout.write_all(bytes).and(stdout.flush())
+            }
+            Fd::Stderr => io::stderr().write_all(bytes),
+            Fd::File(file) => file.write_all(bytes),
+        };
+        n.map_err(errno)?;
+        Ok(len)
+    }
+
+    /// The `(base, len)` pairs of an array of `iovcnt` `struct iovec`s,
+    /// two XLEN-wide words each.
+    fn iovecs(
+        cpu: &Cpu,
+        iov: u64,
+        iovcnt: u64,
+    ) -> Result<Vec<(u64, u64)>, u64> {
+        let word = cpu.xlen().bits() as usize / 8;
+        let bytes = cpu
+            .read_memory(iov, iovcnt as usize * 2 * word)
+            .ok_or(EFAULT)?;
+        Ok(bytes
+            .chunks(2 * word)
+            .map(|iovec| {
+                (read_word(&iovec[..word]), read_word(&iovec[word..]))
+            })
+            .collect())
+    }
+
+    fn readv(
+        &mut self,
+        cpu: &mut Cpu,
+        fd: u64,
+        iov: u64,
+        iovcnt: u64,
+    ) -> SysResult {
+        let mut total = 0;
+        for (base, len) in Syscalls::iovecs(cpu, iov, iovcnt)? {
+            let n = self.read(cpu, fd, base, len)?;
+            total += n;
+            if n < len {
+                break;
+            }
+        }
+        Ok(total)
+    }
+
+    fn writev(
+        &mut self,
+        cpu: &Cpu,
+        fd: u64,
+        iov: u64,
+        iovcnt: u64,
+    ) -> SysResult {
+        let mut total = 0;
+        for (base, len) in Syscalls::iovecs(cpu, iov, iovcnt)? {
+            total += self.write(cpu, fd, base, len)?;
+        }
+        Ok(total)
+    }
+
+    fn openat(
+        &mut self,
+        cpu: &Cpu,
+        dirfd: i32,
+        path: u64,
+        flags: u64,
+        mode: u64,
+    ) -> SysResult {
+        let path = read_cstr(cpu, path)?;
+        if dirfd != AT_FDCWD && !path.starts_with('/') {
+            return Err(ENOTSUP);
+        }
+        let mut options = OpenOptions::new();
+        match flags & O_ACCMODE {
+            O_WRONLY => options.write(true),
+            O_RDWR => options.read(true).write(true),
+            _ => options.read(true),
+        };
+        options
+            .append(flags & O_APPEND != 0)
+            .truncate(flags & O_TRUNC != 0);
+        if flags & O_CREAT != 0 {
+            if flags & O_EXCL != 0 {
+                options.create_new(true);
+            } else {
+                options.create(true);
+            }
+        }
+        #[cfg(unix)]
+        std::os::unix::fs::OpenOptionsExt::mode(&mut options, mode as u32);
+        #[cfg(not(unix))]
+        let _ = mode;
+        let file = Fd::File(options.open(path).map_err(errno)?);
+
+        // like Linux, hand out the lowest free descriptor
+        match self.fds.iter().position(Option::is_none) {
+            Some(fd) => {
+                self.fds[fd] = Some(file);
+                Ok(fd as u64)
+            }
+            None => {
+                self.fds.push(Some(file));
+                Ok(self.fds.len() as u64 - 1)
+            }
+        }
+    }
+
+    fn close(&mut self, fd: u64) -> SysResult {
+        self.fd(fd)?;
+        // dropping the guest's stdio leaves the emulator's alone
+        self.fds[fd as usize] = None;
+        Ok(0)
+    }
+
+    /// Handles both newlib's three argument `lseek` and the `_llseek`
+    /// Linux and musl use on RV32, which splits the offset into two
+    /// registers and returns the new one through a pointer. newlib zeroes
+    /// the unused argument registers, so a null result pointer tells them
+    /// apart. RV64 only has the three argument form.
+    fn lseek(
+        &mut self,
+        cpu: &mut Cpu,
+        fd: u64,
+        a1: u64,
+        a2: u64,
+        result: u64,
+        a4: u64,
+    ) -> SysResult {
+        let result = match cpu.xlen() {
+            Xlen::X32 => result,
+            Xlen::X64 => 0,
+        };
+        let (offset, whence) = match result {
+            0 => (cpu.signed(a1), a2),
+            _ => ((a1 << 32 | a2) as i64, a4),
+        };
+        let pos = match whence {
+            0 => SeekFrom::Start(offset.try_into().map_err(|_| EINVAL)?),
+            1 => SeekFrom::Current(offset),
+            2 => SeekFrom::End(offset),
+            _ => return Err(EINVAL),
+        };
+        let file = match self.fd(fd)? {
+            Fd::File(file) => file,
+            _ => return Err(ESPIPE),
+        };
+        let pos = file.seek(pos).map_err(errno)?;
+        match result {
+            0 if cpu.xlen() == Xlen::X32 && pos > u32::MAX as u64 => {
+                Err(EOVERFLOW)
+            }
+            0 => Ok(pos),
+            _ => {
+                cpu.write_memory(result, &pos.to_le_bytes())
+                    .ok_or(EFAULT)?;
+                Ok(0)
+            }
+        }
+    }
+
+    /// Fills in newlib's `struct kernel_stat`.
+    fn fstat(&mut self, cpu: &mut Cpu, fd: u64, buf: u64) -> SysResult {
+        // S_IFCHR with rw--w----, like a terminal
+        let mut mode = 0o020620;
+        let mut size = 0;
+        let mut mtime = 0;
+        if let Fd::File(file) = self.fd(fd)? {
+            let meta = file.metadata().map_err(errno)?;
+            mode = file_mode(&meta);
+            size = meta.len();
+            mtime = meta
+                .modified()
+                .ok()
+                .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+                .map_or(0, |t| t.as_secs());
+        }
+        let mut stat = [0u8; 128];
+        stat[16..20].copy_from_slice(&mode.to_le_bytes());
+        stat[20..24].copy_from_slice(&1u32.to_le_bytes());
+        stat[48..56].copy_from_slice(&size.to_le_bytes());
+        stat[56..60].copy_from_slice(&(PAGE_SIZE as u32).to_le_bytes());
+        stat[64..72].copy_from_slice(&size.div_ceil(512).to_le_bytes());
+        for time in [72, 88, 104] {
+            stat[time..time + 8].copy_from_slice(&mtime.to_le_bytes());
+        }
+        cpu.write_memory(buf, &stat).ok_or(EFAULT)?;
+        Ok(0)
+    }
+
+    /// Moves the program break to `addr` if it can, and returns where it
+    /// ends up.
+    fn brk(&mut self, cpu: &mut Cpu, addr: u64) -> u64 {
+        if addr >= self.brk_start && addr <= self.mmap_top {
+            if addr > self.brk {
+                // the old break stays if the new memory isn't there
+                let len = (addr - self.brk) as usize;
+                if cpu.write_memory(self.brk, &vec![0; len]).is_none() {
+                    return self.brk;
+                }
+            }
+            self.brk = addr;
+        }
+        self.brk
+    }
+
+    fn mmap(
+        &mut self,
+        cpu: &mut Cpu,
+        addr: u64,
+        len: u64,
+        flags: u64,
+    ) -> SysResult {
+        if flags & MAP_ANONYMOUS == 0 {
+            // file mappings aren't supported
+            return Err(ENODEV);
+        }
+        let fixed = flags & MAP_FIXED != 0;
+        if len == 0 || fixed && !addr.is_multiple_of(PAGE_SIZE) {
+            return Err(EINVAL);
+        }
+        let len = len.checked_add(PAGE_SIZE - 1).ok_or(ENOMEM)?
+            & !(PAGE_SIZE - 1);
+        let addr = if fixed {
+            addr
+        } else {
+            let addr = self.mmap_top.checked_sub(len).ok_or(ENOMEM)?;
+            if addr < self.brk {
+                return Err(ENOMEM);
+            }
+            self.mmap_top = addr;
+            addr
+        };
+        cpu.write_memory(addr, &vec![0; len as usize])
+            .ok_or(ENOMEM)?;
+        Ok(addr)
+    }
+
+    /// Only the most recent mapping is actually given back; the space
+    /// used by others stays reserved.
+    fn munmap(&mut self, addr: u64, len: u64) -> SysResult {
+        if !addr.is_multiple_of(PAGE_SIZE) {
+            return Err(EINVAL);
+        }
+        if addr == self.mmap_top {
+            self.mmap_top =
+                page_align(addr.saturating_add(len)).min(self.mmap_end);
+        }
+        Ok(0)
+    }
+
+    /// Writes a `struct timespec`, with 64-bit fields for
+    /// `clock_gettime64` or on RV64 and 32-bit ones otherwise.
+    fn clock_gettime(
+        &self,
+        cpu: &mut Cpu,
+        clock: u64,
+        tp: u64,
+        time64: bool,
+    ) -> SysResult {
+        let time = match clock {
+            CLOCK_REALTIME => unix_time(),
+            // every other clock counts from when the program started
+            1..=CLOCK_TAI => self.start.elapsed(),
+            _ => return Err(EINVAL),
+        };
+        let (secs, nanos) = (time.as_secs(), time.subsec_nanos());
+        let bytes = match time64 || cpu.xlen() == Xlen::X64 {
+            true => {
+                [secs.to_le_bytes(), (nanos as u64).to_le_bytes()].concat()
+            }
+            false => {
+                [(secs as u32).to_le_bytes(), nanos.to_le_bytes()].concat()
+            }
+        };
+        cpu.write_memory(tp, &bytes).ok_or(EFAULT)?;
+        Ok(0)
+    }
+}
+
+/// Writes a `struct timeval`, two XLEN-wide fields.
+fn gettimeofday(cpu: &mut Cpu, tv: u64, tz: u64) -> SysResult {
+    if tv != 0 {
+        let time = unix_time();
+        let word = cpu.xlen().bits() as usize / 8;
+        let bytes = [
+            &time.as_secs().to_le_bytes()[..word],
+            &(time.subsec_micros() as u64).to_le_bytes()[..word],
+        ]
+        .concat();
+        cpu.write_memory(tv, &bytes).ok_or(EFAULT)?;
+    }
+    if tz != 0 {
+        cpu.write_memory(tz, &[0; 8]).ok_or(EFAULT)?;
+    }
+    Ok(0)
+}
+
+fn uname(cpu: &mut Cpu, buf: u64) -> SysResult {
+    let machine = match cpu.xlen() {
+        Xlen::X32 => "riscv32",
+        Xlen::X64 => "riscv64",
+    };
+    let fields = ["Linux", "rvemu", "6.1.0", "#1", machine, ""];
+    let mut utsname = [0u8; 6 * 65];
+    for (field, value) in utsname.chunks_mut(65).zip(fields) {
+        field[..value.len()].copy_from_slice(value.as_bytes());
+    }
+    cpu.write_memory(buf, &utsname).ok_or(EFAULT)?;
+    Ok(0)
+}
+
+fn unix_time() -> std::time::Duration {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+}
+
+fn file_mode(meta: &Metadata) -> u32 {
+    #[cfg(unix)]
+    {
+        std::os::unix::fs::MetadataExt::mode(meta)
+    }
+    #[cfg(not(unix))]
+    {
+        let kind = if meta.is_dir() { 0o040000 } else { 0o100000 };
+        let perm = if meta.permissions().readonly() {
+            0o444
+        } else {
+            0o644
+        };
+        kind | perm
+    }
+}
+
+/// Reads the NUL-terminated string at `addr`.
+fn read_cstr(cpu: &Cpu, addr: u64) -> Result<String, u64> {
+    let end = cpu.mem_base().wrapping_add(cpu.mem_size() as u64);
+    let bytes = cpu
+        .read_memory(addr, end.wrapping_sub(addr) as usize)
+        .ok_or(EFAULT)?;
+    let len = bytes.iter().position(|&b| b == 0).ok_or(EFAULT)?;
+    String::from_utf8(bytes[..len].to_vec()).map_err(|_| ENOENT)
+}
+
+/// Reads a little-endian word of any width.
+fn read_word(bytes: &[u8]) -> u64 {
+    bytes.iter().rev().fold(0, |acc, &b| acc << 8 | b as u64)
+}
+
+fn page_align(addr: u64) -> u64 {
+    addr.wrapping_add(PAGE_SIZE - 1) & !(PAGE_SIZE - 1)
+}
+
+/// The errno a host I/O error corresponds to.
+fn errno(err: io::Error) -> u64 {
+    // the host's numbers are the guest's when the host runs Linux too
+    if cfg!(target_os = "linux") {
+        if let Some(n) = err.raw_os_error() {
+            return n as u64;
+        }
+    }
+    match err.kind() {
+        io::ErrorKind::NotFound => ENOENT,
+        io::ErrorKind::PermissionDenied => EACCES,
+        io::ErrorKind::AlreadyExists => EEXIST,
+        io::ErrorKind::InvalidInput => EINVAL,
+        _ => EIO,
+    }
+}
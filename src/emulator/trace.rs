@@ -0,0 +1,777 @@
+//! Execution traces: Spike's commit log format, trace files in JSON
+//! Lines or a compact binary format that [`Reader`] reads back, and
+//! running a program in lockstep with a reference trace.
+
+use super::cpu::{Commit, Cpu, MemAccess, Xlen};
+use super::csr;
+use super::disasm;
+use super::instruction::Instruction;
+use super::machine::{Machine, Step, StopReason};
+use std::fmt;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, Write};
+use std::path::Path;
+
+/// Starts a binary trace file; the last byte is the format's version.
+const MAGIC: &[u8; 8] = b"rvtrace\x02";
+
+/// Marks an RV64 record in the privilege byte of a binary record.
+const RV64: u8 = 1 << 7;
+
+/// Marks a floating-point register's number in a binary register entry.
+const FREG: u8 = 1 << 5;
+
+/// Formats a retired instruction the way Spike's `--log-commits` does:
+///
+/// ```text
+/// core   0: 3 0x80000000 (0x00000297) x5  0x80000000
+/// core   0: 3 0x80000184 (0x0020a023) mem 0x80002000 0x00aa00aa
+/// ```
+///
+/// That is the privilege level, the pc and the encoding, followed by the
+/// register and CSR writes, the addresses loaded from and the stores.
+/// Addresses and registers are printed XLEN bits wide.
+pub fn spike(step: &Step, commit: &Commit) -> String {
+    spike_line(step.pc, step.raw, commit)
+}
+
+fn spike_line(pc: u64, raw: u32, commit: &Commit) -> String {
+    // compressed encodings are printed as 16 bits
+    let size = if raw & 0b11 == 0b11 { 4 } else { 2 };
+    let x = commit.xlen.bits() as usize / 4 + 2;
+    let mut line = format!(
+        "core   0: {} {:#0x$x} ({:#0width$x})",
+        commit.privilege,
+        pc,
+        raw,
+        width = size * 2 + 2
+    );
+    if let Some((rd, value)) = commit.reg {
+        line += &format!(" x{:<2} {:#0x$x}", rd, value);
+    }
+    if let Some((rd, value)) = commit.freg {
+        line += &format!(" f{:<2} {:#018x}", rd, value);
+    }
+    for &(n, value) in &commit.csrs {
+        line += &format!(" c{}_{} {:#0x$x}", n, csr::name(n), value);
+    }
+    for access in commit.mem.iter().filter(|access| !access.write) {
+        line += &format!(" mem {:#0x$x}", access.addr);
+    }
+    for access in commit.mem.iter().filter(|access| access.write) {
+        line += &format!(
+            " mem {:#0x$x} {:#0width$x}",
+            access.addr,
+            access.value,
+            width = access.size as usize * 2 + 2
+        );
+    }
+    line
+}
+
+/// The formats of trace files.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    /// A JSON object per line:
+    ///
+    /// ```text
+    /// {"pc":2147484036,"raw":2138147,"mnemonic":"sw","priv":0,"xlen":32,"reg":null,"freg":null,"csrs":[],"mem":[{"addr":2147491840,"size":4,"value":11141290,"write":true}]}
+    /// ```
+    Json,
+    /// Little-endian records after a header of `rvtrace` and a version
+    /// byte: `u8`s for the privilege level, with bit 7 set on RV64, and
+    /// the numbers of register, CSR and memory entries, then the encoding
+    /// as a `u32` and the pc as an XLEN-wide word. A register entry is a
+    /// `u8` register number and a word, or for a floating-point register
+    /// the number with bit 5 set and a `u64`. A CSR entry is a `u16`
+    /// number and a word, and a memory entry a word address, a `u8` size
+    /// with bit 7 set for stores, and a `u32` value, `u64` for 8 bytes.
+    /// Version 1 files, with only RV32 records and the pc first, can
+    /// still be read.
+    /// Mnemonics aren't stored, but decoded from the encoding on reading.
+    Binary,
+    /// Spike's `--log-commits` format, as [`spike`] prints it. Lines
+    /// that aren't commits, such as Spike's exception reports, are
+    /// skipped on reading. Spike doesn't log the size or value of loads,
+    /// which read back as zero.
+    Spike,
+}
+
+/// A retired instruction, as recorded in a trace file.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Record {
+    pub pc: u64,
+    pub raw: u32,
+    /// The instruction's name, such as `addi`. Compressed instructions go
+    /// by the instruction they expand to.
+    pub mnemonic: String,
+    /// What the instruction changed.
+    pub commit: Commit,
+}
+
+/// Records display as Spike commit log lines.
+impl fmt::Display for Record {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", spike_line(self.pc, self.raw, &self.commit))
+    }
+}
+
+impl Record {
+    pub fn new(pc: u64, inst: &Instruction, commit: Commit) -> Self {
+        Record {
+            pc,
+            raw: inst.raw,
+            mnemonic: mnemonic(inst, pc),
+            commit,
+        }
+    }
+}
+
+fn mnemonic(inst: &Instruction, pc: u64) -> String {
+    let text = disasm::disassemble(inst, pc);
+    text.split_whitespace()
+        .next()
+        .unwrap_or_default()
+        .to_string()
+}
+
+/// Writes [`Record`]s to a trace file.
+pub struct Writer {
+    out: Box<dyn Write>,
+    format: Format,
+}
+
+impl Writer {
+    /// Starts a trace in `format`; `out` is best buffered.
+    pub fn new(
+        out: impl Write + 'static,
+        format: Format,
+    ) -> io::Result<Self> {
+        let mut out: Box<dyn Write> = Box::new(out);
+        if format == Format::Binary {
+            out.write_all(MAGIC)?;
+        }
+        Ok(Writer { out, format })
+    }
+
+    /// Creates the file at `path` and starts a trace in it.
+    pub fn create(
+        path: impl AsRef<Path>,
+        format: Format,
+    ) -> io::Result<Self> {
+        Writer::new(io::BufWriter::new(File::create(path)?), format)
+    }
+
+    pub fn write(&mut self, record: &Record) -> io::Result<()> {
+        match self.format {
+            Format::Json => writeln!(self.out, "{}", json(record)),
+            Format::Binary => self.out.write_all(&binary(record)),
+            Format::Spike => writeln!(self.out, "{}", record),
+        }
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.out.flush()
+    }
+}
+
+fn json(record: &Record) -> String {
+    let commit = &record.commit;
+    let reg = match commit.reg {
+        Some((rd, value)) => format!("[{},{}]", rd, value),
+        None => "null".to_string(),
+    };
+    let freg = match commit.freg {
+        Some((rd, value)) => format!("[{},{}]", rd, value),
+        None => "null".to_string(),
+    };
+    let csrs: Vec<String> = commit
+        .csrs
+        .iter()
+        .map(|(csr, value)| format!("[{},{}]", csr, value))
+        .collect();
+    let mem: Vec<String> = commit
+        .mem
+        .iter()
+        .map(|access| {
+            format!(
+                "{{\"addr\":{},\"size\":{},\"value\":{},\"write\":{}}}",
+                access.addr, access.size, access.value, access.write
+            )
+        })
+        .collect();
+    format!(
+        "{{\"pc\":{},\"raw\":{},\"mnemonic\":{:?},\"priv\":{},\"xlen\":{},\"reg\":{},\"freg\":{},\"csrs\":[{}],\"mem\":[{}]}}",
+        record.pc,
+        record.raw,
+        record.mnemonic,
+        commit.privilege,
+        commit.xlen.bits(),
+        reg,
+        freg,
+        csrs.join(","),
+        mem.join(",")
+    )
+}
+
+fn binary(record: &Record) -> Vec<u8> {
+    let commit = &record.commit;
+    let rv64 = commit.xlen == Xlen::X64;
+    let mut out = Vec::with_capacity(32);
+    let word = |out: &mut Vec<u8>, value: u64| match rv64 {
+        true => out.extend(value.to_le_bytes()),
+        false => out.extend((value as u32).to_le_bytes()),
+    };
+    out.extend([
+        commit.privilege as u8 | if rv64 { RV64 } else { 0 },
+        commit.reg.is_some() as u8 + commit.freg.is_some() as u8,
+        commit.csrs.len() as u8,
+        commit.mem.len() as u8,
+    ]);
+    out.extend(record.raw.to_le_bytes());
+    word(&mut out, record.pc);
+    if let Some((rd, value)) = commit.reg {
+        out.push(rd as u8);
+        word(&mut out, value);
+    }
+    if let Some((rd, value)) = commit.freg {
+        out.push(rd as u8 | FREG);
+        out.extend(value.to_le_bytes());
+    }
+    for &(csr, value) in &commit.csrs {
+        out.extend(csr.to_le_bytes());
+        word(&mut out, value);
+    }
+    for access in &commit.mem {
+        word(&mut out, access.addr);
+        out.push(access.size as u8 | (access.write as u8) << 7);
+        match access.size {
+            8 => out.extend(access.value.to_le_bytes()),
+            _ => out.extend((access.value as u32).to_le_bytes()),
+        }
+    }
+    out
+}
+
+/// Reads the [`Record`]s of a trace file in either format.
+pub struct Reader<R> {
+    input: R,
+    format: Format,
+    line: String,
+}
+
+/// Opens the trace file at `path`.
+pub fn open(
+    path: impl AsRef<Path>,
+) -> io::Result<Reader<BufReader<File>>> {
+    Reader::new(BufReader::new(File::open(path)?))
+}
+
+impl<R: BufRead> Reader<R> {
+    /// Starts reading a trace, telling the format from its first bytes.
+    pub fn new(mut input: R) -> io::Result<Self> {
+        let start = input.fill_buf()?;
+        let format = if start.starts_with(MAGIC) {
+            input.consume(MAGIC.len());
+            Format::Binary
+        } else if start.starts_with(&MAGIC[..7]) {
+            return Err(invalid(String::from(
+                "unsupported binary trace version",
+            )));
+        } else if start.starts_with(b"core") {
+            Format::Spike
+        } else {
+            Format::Json
+        };
+        Ok(Reader {
+            input,
+            format,
+            line: String::new(),
+        })
+    }
+
+    pub fn format(&self) -> Format {
+        self.format
+    }
+
+    /// Reads the next line `parse` accepts, skipping blank lines and
+    /// those it returns `Some(None)` for.
+    fn read_line(
+        &mut self,
+        parse: fn(&str) -> Option<Option<Record>>,
+    ) -> io::Result<Option<Record>> {
+        loop {
+            self.line.clear();
+            if self.input.read_line(&mut self.line)? == 0 {
+                return Ok(None);
+            }
+            let line = self.line.trim();
+            if line.is_empty() {
+                continue;
+            }
+            match parse(line) {
+                Some(Some(record)) => return Ok(Some(record)),
+                Some(None) => {}
+                None => {
+                    return Err(invalid(format!("bad record: {}", line)))
+                }
+            }
+        }
+    }
+
+    fn read_binary(&mut self) -> io::Result<Option<Record>> {
+        if self.input.fill_buf()?.is_empty() {
+            return Ok(None);
+        }
+        let header = self.u32()?;
+        let raw = self.u32()?;
+        let pc = match header as u8 & RV64 {
+            0 => self.u32()? as u64,
+            _ => self.u64()?,
+        };
+        let [privilege, regs, csrs, mems] = header.to_le_bytes();
+        let mut commit = Commit {
+            privilege: (privilege & !RV64) as u32,
+            xlen: match privilege & RV64 {
+                0 => Xlen::X32,
+                _ => Xlen::X64,
+            },
+            ..Commit::default()
+        };
+        for _ in 0..regs {
+            let rd = self.u8()?;
+            if rd & FREG != 0 {
+                let rd = (rd & !FREG) as usize;
+                commit.freg = Some((rd, self.u64()?));
+            } else {
+                commit.reg = Some((rd as usize, self.word(commit.xlen)?));
+            }
+        }
+        for _ in 0..csrs {
+            let mut csr = [0; 2];
+            self.input.read_exact(&mut csr)?;
+            let value = self.word(commit.xlen)?;
+            commit.csrs.push((u16::from_le_bytes(csr), value));
+        }
+        for _ in 0..mems {
+            let addr = self.word(commit.xlen)?;
+            let size = self.u8()?;
+            let value = match size & 0x7f {
+                8 => self.u64()?,
+                _ => self.u32()? as u64,
+            };
+            commit.mem.push(MemAccess {
+                addr,
+                size: (size & 0x7f) as u32,
+                value,
+                write: size & 0x80 != 0,
+            });
+        }
+        let inst = Cpu::decode(raw, commit.xlen);
+        Ok(Some(Record::new(pc, &inst, commit)))
+    }
+
+    fn u8(&mut self) -> io::Result<u8> {
+        let mut byte = [0];
+        self.input.read_exact(&mut byte)?;
+        Ok(byte[0])
+    }
+
+    fn u32(&mut self) -> io::Result<u32> {
+        let mut word = [0; 4];
+        self.input.read_exact(&mut word)?;
+        Ok(u32::from_le_bytes(word))
+    }
+
+    fn u64(&mut self) -> io::Result<u64> {
+        let mut word = [0; 8];
+        self.input.read_exact(&mut word)?;
+        Ok(u64::from_le_bytes(word))
+    }
+
+    /// Reads an XLEN-wide word.
+    fn word(&mut self, xlen: Xlen) -> io::Result<u64> {
+        match xlen {
+            Xlen::X32 => self.u32().map(u64::from),
+            Xlen::X64 => self.u64(),
+        }
+    }
+}
+
+impl<R: BufRead> Iterator for Reader<R> {
+    type Item = io::Result<Record>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let record = match self.format {
+            Format::Json => self.read_line(|line| {
+                Json::parse(line)
+                    .and_then(|json| json_record(&json))
+                    .map(Some)
+            }),
+            Format::Binary => self.read_binary(),
+            Format::Spike => self.read_line(spike_record),
+        };
+        record.transpose()
+    }
+}
+
+fn invalid(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+/// Parses a Spike commit log line, returning `Some(None)` for lines that
+/// aren't commits.
+fn spike_record(line: &str) -> Option<Option<Record>> {
+    let hex64 = |token: &str| {
+        u64::from_str_radix(token.strip_prefix("0x")?, 16).ok()
+    };
+    let hex = |token: &str| u32::try_from(hex64(token)?).ok();
+    let mut tokens = line.split_whitespace().peekable();
+    // core, hart id, privilege, pc, encoding
+    let privilege = match tokens.nth(2)?.parse() {
+        Ok(privilege @ 0..=3) => privilege,
+        _ => return Some(None),
+    };
+    // RV64 logs print the pc with 16 digits
+    let pc = tokens.next()?;
+    let Some(pc_value) = hex64(pc) else {
+        return Some(None);
+    };
+    let xlen = match pc.len() > 10 {
+        true => Xlen::X64,
+        false => Xlen::X32,
+    };
+    let raw = tokens.next()?.strip_prefix('(')?.strip_suffix(')')?;
+    let mut commit = Commit {
+        privilege,
+        xlen,
+        ..Commit::default()
+    };
+    while let Some(token) = tokens.next() {
+        if token == "mem" {
+            let addr = hex64(tokens.next()?)?;
+            // a store has a value, whose digits give its size
+            let value = tokens.next_if(|token| token.starts_with("0x"));
+            commit.mem.push(match value {
+                Some(value) => MemAccess {
+                    addr,
+                    size: (value.len() as u32 - 2) / 2,
+                    value: hex64(value)?,
+                    write: true,
+                },
+                None => MemAccess {
+                    addr,
+                    size: 0,
+                    value: 0,
+                    write: false,
+                },
+            });
+            continue;
+        }
+        let value = hex64(tokens.next()?)?;
+        if let Some(csr) = token.strip_prefix('c') {
+            let (csr, _name) = csr.split_once('_')?;
+            commit.csrs.push((csr.parse().ok()?, value));
+        } else if let Some(rd) = token.strip_prefix('x') {
+            commit.reg = Some((rd.parse().ok()?, value));
+        } else if let Some(rd) = token.strip_prefix('f') {
+            commit.freg = Some((rd.parse().ok()?, value));
+        }
+        // anything else is a register the emulator doesn't have
+    }
+    let inst = Cpu::decode(hex(raw)?, xlen);
+    Some(Some(Record::new(pc_value, &inst, commit)))
+}
+
+/// Turns a parsed JSON Lines record back into a [`Record`].
+fn json_record(json: &Json) -> Option<Record> {
+    let number64 = |json: &Json| match json {
+        Json::Number(n) => Some(*n),
+        _ => None,
+    };
+    let number = |json: &Json| u32::try_from(number64(json)?).ok();
+    let pair = |json: &Json| match json {
+        Json::Array(pair) if pair.len() == 2 => {
+            Some((number(&pair[0])?, number64(&pair[1])?))
+        }
+        _ => None,
+    };
+    let list = |json: Option<&Json>| match json {
+        Some(Json::Array(items)) => Some(items.clone()),
+        _ => None,
+    };
+    let reg = match json.get("reg")? {
+        Json::Null => None,
+        reg => pair(reg).map(|(rd, value)| (rd as usize, value)),
+    };
+    let freg = match json.get("freg")? {
+        Json::Null => None,
+        freg => pair(freg).map(|(rd, value)| (rd as usize, value)),
+    };
+    let xlen = match number(json.get("xlen")?)? {
+        32 => Xlen::X32,
+        64 => Xlen::X64,
+        _ => return None,
+    };
+    let mut commit = Commit {
+        privilege: number(json.get("priv")?)?,
+        xlen,
+        reg,
+        freg,
+        ..Commit::default()
+    };
+    for csr in list(json.get("csrs"))? {
+        let (csr, value) = pair(&csr)?;
+        commit.csrs.push((u16::try_from(csr).ok()?, value));
+    }
+    for access in list(json.get("mem"))? {
+        commit.mem.push(MemAccess {
+            addr: number64(access.get("addr")?)?,
+            size: number(access.get("size")?)?,
+            value: number64(access.get("value")?)?,
+            write: matches!(access.get("write")?, Json::Bool(true)),
+        });
+    }
+    let mnemonic = match json.get("mnemonic")? {
+        Json::String(mnemonic) => mnemonic.clone(),
+        _ => return None,
+    };
+    Some(Record {
+        pc: number64(json.get("pc")?)?,
+        raw: number(json.get("raw")?)?,
+        mnemonic,
+        commit,
+    })
+}
+
+/// Just enough JSON for trace records: numbers are unsigned integers.
+#[derive(Debug, Clone)]
+enum Json {
+    Null,
+    Bool(bool),
+    Number(u64),
+    String(String),
+    Array(Vec<Json>),
+    Object(Vec<(String, Json)>),
+}
+
+impl Json {
+    fn parse(text: &str) -> Option<Json> {
+        let mut chars = text.trim().chars().peekable();
+        let json = Json::value(&mut chars)?;
+        chars.peek().is_none().then_some(json)
+    }
+
+    fn get(&self, key: &str) -> Option<&Json> {
+        match self {
+            Json::Object(fields) => {
+                fields.iter().find(|(k, _)| k == key).map(|(_, v)| v)
+            }
+            _ => None,
+        }
+    }
+
+    fn value(chars: &mut Chars) -> Option<Json> {
+        skip_space(chars);
+        let json = match *chars.peek()? {
+            'n' => Json::word(chars, "null", Json::Null)?,
+            't' => Json::word(chars, "true", Json::Bool(true))?,
+            'f' => Json::word(chars, "false", Json::Bool(false))?,
+            '"' => Json::String(Json::string(chars)?),
+            '0'..='9' => {
+                let mut n: u64 = 0;
+                while let Some(digit) =
+                    chars.peek().and_then(|c| c.to_digit(10))
+                {
+                    n = n.checked_mul(10)?.checked_add(digit as u64)?;
+                    chars.next();
+                }
+                Json::Number(n)
+            }
+            '[' => {
+                chars.next();
+                let mut items = Vec::new();
+                Json::list(chars, ']', |chars| {
+                    items.push(Json::value(chars)?);
+                    Some(())
+                })?;
+                Json::Array(items)
+            }
+            '{' => {
+                chars.next();
+                let mut fields = Vec::new();
+                Json::list(chars, '}', |chars| {
+                    skip_space(chars);
+                    let key = Json::string(chars)?;
+                    skip_space(chars);
+                    (chars.next()? == ':').then_some(())?;
+                    fields.push((key, Json::value(chars)?));
+                    Some(())
+                })?;
+                Json::Object(fields)
+            }
+            _ => return None,
+        };
+        skip_space(chars);
+        Some(json)
+    }
+
+    fn word(chars: &mut Chars, word: &str, json: Json) -> Option<Json> {
+        for expected in word.chars() {
+            (chars.next()? == expected).then_some(())?;
+        }
+        Some(json)
+    }
+
+    /// Reads comma-separated items up to `end`, the opening bracket
+    /// having been read.
+    fn list(
+        chars: &mut Chars,
+        end: char,
+        mut item: impl FnMut(&mut Chars) -> Option<()>,
+    ) -> Option<()> {
+        skip_space(chars);
+        if chars.next_if_eq(&end).is_some() {
+            return Some(());
+        }
+        loop {
+            item(chars)?;
+            skip_space(chars);
+            match chars.next()? {
+                ',' => {}
+                c if c == end => return Some(()),
+                _ => return None,
+            }
+        }
+    }
+
+    fn string(chars: &mut Chars) -> Option<String> {
+        (chars.next()? == '"').then_some(())?;
+        let mut s = String::new();
+        loop {
+            match chars.next()? {
+                '"' => return Some(s),
+                '\\' => match chars.next()? {
+                    'n' => s.push('\n'),
+                    't' => s.push('\t'),
+                    'r' => s.push('\r'),
+                    'u' => {
+                        let hex: String = chars.by_ref().take(4).collect();
+                        s.push(char::from_u32(
+                            u32::from_str_radix(&hex, 16).ok()?,
+                        )?);
+                    }
+                    c => s.push(c),
+                },
+                c => s.push(c),
+            }
+        }
+    }
+}
+
+type Chars<'a> = std::iter::Peekable<std::str::Chars<'a>>;
+
+fn skip_space(chars: &mut Chars) {
+    while chars.next_if(|c| c.is_whitespace()).is_some() {}
+}
+
+/// How a lockstep run ended.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Lockstep {
+    /// The program stopped, having matched the reference up to there.
+    Stopped { stop: StopReason, matched: u64 },
+    /// The reference trace ran out while the program was running.
+    ReferenceEnded { matched: u64 },
+    /// The program did something other than the reference did.
+    Diverged {
+        /// How many instructions matched before.
+        matched: u64,
+        /// What the reference did.
+        expected: Box<Record>,
+        /// What the program did instead.
+        actual: Box<Record>,
+    },
+}
+
+impl fmt::Display for Lockstep {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Lockstep::Stopped { stop, matched } => write!(
+                f,
+                "{} after matching the reference for {} instructions",
+                stop, matched
+            ),
+            Lockstep::ReferenceEnded { matched } => write!(
+                f,
+                "reference trace ended after {} matching instructions",
+                matched
+            ),
+            Lockstep::Diverged {
+                matched,
+                expected,
+                actual,
+            } => write!(
+                f,
+                "diverged from the reference after {} instructions\n\
+                 reference: {}\n\
+                 emulator:  {}",
+                matched, expected, actual
+            ),
+        }
+    }
+}
+
+/// Runs `machine` in lockstep with a `reference` trace, such as a Spike
+/// commit log, until the program stops or first does something other
+/// than the reference: retires an instruction at another pc, or writes
+/// a different register, value or memory. Loads and CSR writes aren't
+/// compared. Reference records before the first that is at the
+/// program's pc, like those of Spike's boot ROM, are skipped.
+pub fn lockstep<R: BufRead>(
+    machine: &mut Machine,
+    reference: Reader<R>,
+) -> io::Result<Lockstep> {
+    machine.cpu_mut().log_commits = true;
+    let start = machine.cpu().pc();
+    let mut reference = reference
+        .skip_while(|record| matches!(record, Ok(r) if r.pc != start));
+    let mut matched = 0;
+    loop {
+        let step = machine.step();
+        // instructions that trap don't retire, here or in the reference
+        if let Some(commit) = machine.commit() {
+            let Some(expected) = reference.next().transpose()? else {
+                return Ok(Lockstep::ReferenceEnded { matched });
+            };
+            let actual = Record::new(step.pc, &step.inst, commit.clone());
+            if !same(&expected, &actual) {
+                return Ok(Lockstep::Diverged {
+                    matched,
+                    expected: Box::new(expected),
+                    actual: Box::new(actual),
+                });
+            }
+            matched += 1;
+        }
+        if let Some(stop) = step.stop {
+            return Ok(Lockstep::Stopped { stop, matched });
+        }
+    }
+}
+
+/// Whether two records agree on the pc, register writes and stores.
+fn same(expected: &Record, actual: &Record) -> bool {
+    let stores = |record: &Record| {
+        record
+            .commit
+            .mem
+            .iter()
+            .filter(|access| access.write)
+            .map(|access| (access.addr, access.size, access.value))
+            .collect::<Vec<_>>()
+    };
+    expected.pc == actual.pc
+        && expected.commit.reg == actual.commit.reg
+        && expected.commit.freg == actual.commit.freg
+        && stores(expected) == stores(actual)
+}
@@ -0,0 +1,92 @@
+/// Synchronous exceptions, carrying the value written to `mtval` or
+/// `stval`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Exception {
+    InstructionAddressMisaligned(u64),
+    InstructionAccessFault(u64),
+    IllegalInstruction(u32),
+    Breakpoint(u64),
+    LoadAddressMisaligned(u64),
+    LoadAccessFault(u64),
+    StoreAddressMisaligned(u64),
+    StoreAccessFault(u64),
+    EcallFromU,
+    EcallFromS,
+    EcallFromM,
+    InstructionPageFault(u64),
+    LoadPageFault(u64),
+    StorePageFault(u64),
+}
+
+impl Exception {
+    /// The exception code written to `mcause` or `scause`.
+    pub fn cause(&self) -> u32 {
+        match self {
+            Exception::InstructionAddressMisaligned(_) => 0,
+            Exception::InstructionAccessFault(_) => 1,
+            Exception::IllegalInstruction(_) => 2,
+            Exception::Breakpoint(_) => 3,
+            Exception::LoadAddressMisaligned(_) => 4,
+            Exception::LoadAccessFault(_) => 5,
+            Exception::StoreAddressMisaligned(_) => 6,
+            Exception::StoreAccessFault(_) => 7,
+            Exception::EcallFromU => 8,
+            Exception::EcallFromS => 9,
+            Exception::EcallFromM => 11,
+            Exception::InstructionPageFault(_) => 12,
+            Exception::LoadPageFault(_) => 13,
+            Exception::StorePageFault(_) => 15,
+        }
+    }
+
+    /// The faulting address or instruction written to `mtval` or
+    /// `stval`.
+    pub fn value(&self) -> u64 {
+        match *self {
+            Exception::InstructionAddressMisaligned(value)
+            | Exception::InstructionAccessFault(value)
+            | Exception::Breakpoint(value)
+            | Exception::LoadAddressMisaligned(value)
+            | Exception::LoadAccessFault(value)
+            | Exception::StoreAddressMisaligned(value)
+            | Exception::StoreAccessFault(value)
+            | Exception::InstructionPageFault(value)
+            | Exception::LoadPageFault(value)
+            | Exception::StorePageFault(value) => value,
+            Exception::IllegalInstruction(raw) => raw as u64,
+            Exception::EcallFromU
+            | Exception::EcallFromS
+            | Exception::EcallFromM => 0,
+        }
+    }
+}
+
+impl std::fmt::Display for Exception {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let description = match self {
+            Exception::InstructionAddressMisaligned(_) => {
+                "instruction address misaligned"
+            }
+            Exception::InstructionAccessFault(_) => {
+                "instruction access fault"
+            }
+            Exception::IllegalInstruction(_) => "illegal instruction",
+            Exception::Breakpoint(_) => "breakpoint",
+            Exception::LoadAddressMisaligned(_) => {
+                "load address misaligned"
+            }
+            Exception::LoadAccessFault(_) => "load access fault",
+            Exception::StoreAddressMisaligned(_) => {
+                "store/AMO address misaligned"
+            }
+            Exception::StoreAccessFault(_) => "store/AMO access fault",
+            Exception::EcallFromU => "environment call from U-mode",
+            Exception::EcallFromS => "environment call from S-mode",
+            Exception::EcallFromM => "environment call from M-mode",
+            Exception::InstructionPageFault(_) => "instruction page fault",
+            Exception::LoadPageFault(_) => "load page fault",
+            Exception::StorePageFault(_) => "store/AMO page fault",
+        };
+        write!(f, "{} (tval = {:#010x})", description, self.value())
+    }
+}
@@ -0,0 +1,22 @@
+//! RVemu, a RISC-V emulator for the RV32IMAFDC and RV64IMAFDC
+//! instruction sets, with the Zba, Zbb, Zbc and Zbs bit-manipulation
+//! extensions.
+//!
+//! ```no_run
+//! use rvemu::{Machine, StopReason};
+//!
+//! let mut machine = Machine::builder().mem_size(64).load("./program")?;
+//! assert_eq!(machine.run(), StopReason::Exited(0));
+//! # Ok::<(), rvemu::LoadError>(())
+//! ```
+pub mod emulator;
+mod tests;
+
+pub use emulator::cpu::{
+    Commit, Cpu, CpuBuilder, MemAccess, WatchKind, Watchpoint, Xlen,
+};
+pub use emulator::error::LoadError;
+pub use emulator::machine::{
+    FaultKind, Machine, MachineBuilder, Step, StopReason,
+};
+pub use emulator::trap::Exception;
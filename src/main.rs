@@ -1,20 +1,70 @@
-mod emulator;
-mod tests;
-use clap::Parser;
-use emulator::cpu::Cpu;
+use clap::{Parser, Subcommand, ValueEnum};
+use rvemu::emulator::disasm::{self, Syntax};
+use rvemu::emulator::gdb::{self, Session};
+use rvemu::emulator::trace::{self, Format, Lockstep};
+use rvemu::{Cpu, Machine, Step, StopReason, WatchKind, Watchpoint, Xlen};
+use std::io::Write;
+use std::net::TcpListener;
+use std::path::{Path, PathBuf};
 
-///  A RISC-V emulator, specifically the RV32I base integer instruction set.
+///  A RISC-V emulator for the RV32 and RV64 IMAFDC instruction sets.
 #[derive(Parser, Clone)]
-#[clap(author, version, about, long_about = None)]
+#[clap(
+    author,
+    version,
+    about,
+    long_about = None,
+    trailing_var_arg = true,
+    args_conflicts_with_subcommands = true,
+    subcommand_negates_reqs = true
+)]
 pub struct Args {
+    #[clap(subcommand)]
+    command: Option<Command>,
+
     /// The path of the file to be executed
-    #[clap(parse(from_os_str), value_name = "FILE")]
-    file: std::path::PathBuf,
+    #[clap(parse(from_os_str), value_name = "FILE", required = true)]
+    file: Option<PathBuf>,
+
+    /// Arguments passed on to the program. Implies `--stack`
+    #[clap(value_name = "ARGS")]
+    pub guest_args: Vec<String>,
 
     /// Print instructions as they are executed
     #[clap(short, long)]
     pub debug: bool,
 
+    /// How `--debug` prints instructions: like objdump, or as a commit
+    /// log like Spike's `--log-commits`. Implies `--debug`
+    #[clap(long, value_enum, value_name = "format")]
+    pub trace_format: Option<TraceFormat>,
+
+    /// Record each instruction that retires, with the registers and
+    /// memory it wrote and the memory it read, in this file
+    #[clap(long, parse(from_os_str), value_name = "path")]
+    pub trace_file: Option<PathBuf>,
+
+    /// The format of the `--trace-file`: JSON Lines, or a compact binary
+    /// format
+    #[clap(
+        long,
+        value_enum,
+        value_name = "format",
+        default_value = "json"
+    )]
+    pub trace_file_format: TraceFileFormat,
+
+    /// Run in lockstep with a reference trace, a Spike commit log or a
+    /// `--trace-file`, and stop where the program first does something
+    /// else
+    #[clap(
+        long,
+        parse(from_os_str),
+        value_name = "path",
+        conflicts_with_all = &["interactive", "gdb"]
+    )]
+    pub lockstep: Option<PathBuf>,
+
     /// Show register values after each instruction
     #[clap(short, long)]
     pub registers: bool,
@@ -32,30 +82,816 @@ pub struct Args {
     #[clap(long, value_name = "address")]
     pub pc: Option<String>,
 
-    /// Provide a stack of "infinite" size.
-    /// This sets the stack pointer before execution, so it might cause undefined behaviour.
+    /// Run as RV32 or RV64 instead of going by the ELF class
+    #[clap(long, value_enum, value_name = "bits")]
+    pub xlen: Option<XlenArg>,
+
+    /// Set up the stack like Linux does, with the program's arguments,
+    /// environment and auxiliary vector on it
     #[clap(short, long)]
     pub stack: bool,
 
+    /// Put the top of the stack at this address instead of the end of memory
+    #[clap(long, value_name = "address")]
+    pub stack_top: Option<String>,
+
+    /// Add a variable to the program's environment. Implies `--stack`
+    #[clap(long, value_name = "KEY=VAL")]
+    pub env: Vec<String>,
+
     /// Set memory size in KiB (default = 16)
     #[clap(long, value_name = "size")]
     pub mem: Option<String>,
+
+    /// Stop after executing this many instructions
+    #[clap(long, value_name = "count")]
+    pub max_steps: Option<u64>,
+
+    /// Wait for GDB to connect on this local TCP port
+    #[clap(long, value_name = "port")]
+    pub gdb: Option<u16>,
+
+    /// Exit with 1 rather than the program's exit code when that isn't 0,
+    /// so that it can't be mistaken for the emulator's own statuses
+    #[clap(long)]
+    pub stop_status: bool,
 }
+
+#[derive(Subcommand, Clone)]
+enum Command {
+    /// List the instructions in an ELF file's code without running it
+    Disasm {
+        /// The ELF file to disassemble
+        #[clap(parse(from_os_str), value_name = "FILE")]
+        file: PathBuf,
+
+        /// Show register ABI names instead of numeric ones (x0-x31)
+        #[clap(short, long)]
+        aliases: bool,
+
+        /// Print the base instruction behind every pseudo-instruction
+        #[clap(long)]
+        no_pseudo: bool,
+    },
+}
+
+#[derive(ValueEnum, Clone, Copy, PartialEq, Eq)]
+pub enum TraceFormat {
+    Objdump,
+    Spike,
+}
+
+#[derive(ValueEnum, Clone, Copy, PartialEq, Eq)]
+pub enum XlenArg {
+    #[clap(name = "32")]
+    X32,
+    #[clap(name = "64")]
+    X64,
+}
+
+#[derive(ValueEnum, Clone, Copy, PartialEq, Eq)]
+pub enum TraceFileFormat {
+    Json,
+    Binary,
+    Spike,
+}
+
+// Exit statuses for runs that didn't end with the program exiting. A
+// program that exits on its own gets its own exit code as the status,
+// unless `--stop-status` maps it to 0 or 1. They stay clear of the
+// shell's 126 and 127 and of 128 and up for signals.
+const EXIT_LOAD_ERROR: i32 = 112;
+const EXIT_UNKNOWN_SYSCALL: i32 = 113;
+const EXIT_ILLEGAL_INSTRUCTION: i32 = 114;
+const EXIT_MEMORY_FAULT: i32 = 115;
+const EXIT_BREAKPOINT: i32 = 116;
+const EXIT_STEP_LIMIT: i32 = 117;
+const EXIT_GDB_ERROR: i32 = 118;
+const EXIT_TRACE_ERROR: i32 = 119;
+const EXIT_DIVERGED: i32 = 120;
+
 fn main() {
-    let args = Args::parse();
+    let mut args = Args::parse();
+    args.debug |= args.trace_format.is_some();
+    if let Some(Command::Disasm {
+        file,
+        aliases,
+        no_pseudo,
+    }) = &args.command
+    {
+        let syntax = Syntax {
+            aliases: *aliases,
+            pseudo: !no_pseudo,
+        };
+        disassemble(file, syntax);
+        return;
+    }
+    let file = args.file.as_ref().unwrap();
 
-    let mem = args.mem.clone();
-    let mut cpu = match mem {
-        Some(mem) => Cpu::new(str::parse(&*mem).unwrap_or(16)),
-        None => Cpu::new(16),
+    let stack =
+        args.stack || !args.guest_args.is_empty() || !args.env.is_empty();
+    let mut builder = Machine::builder()
+        .stack(stack)
+        .args(&args.guest_args)
+        .log_commits(args.trace_format == Some(TraceFormat::Spike));
+    for var in &args.env {
+        builder = builder.env(var);
+    }
+    if let Some(mem) = &args.mem {
+        builder = builder.mem_size(str::parse(mem).unwrap_or(16));
+    }
+    if let Some(pc) = args
+        .pc
+        .as_ref()
+        .and_then(|pc| u64::from_str_radix(pc, 16).ok())
+    {
+        builder = builder.pc(pc);
+    }
+    if let Some(top) = args
+        .stack_top
+        .as_ref()
+        .and_then(|top| u64::from_str_radix(top, 16).ok())
+    {
+        builder = builder.stack_top(top);
+    }
+    if let Some(xlen) = args.xlen {
+        builder = builder.xlen(match xlen {
+            XlenArg::X32 => Xlen::X32,
+            XlenArg::X64 => Xlen::X64,
+        });
+    }
+    if let Some(steps) = args.max_steps {
+        builder = builder.step_limit(steps);
+    }
+    if let Some(path) = &args.trace_file {
+        let format = match args.trace_file_format {
+            TraceFileFormat::Json => Format::Json,
+            TraceFileFormat::Binary => Format::Binary,
+            TraceFileFormat::Spike => Format::Spike,
+        };
+        match trace::Writer::create(path, format) {
+            Ok(trace) => builder = builder.trace(trace),
+            Err(err) => {
+                eprintln!("{}: {}", path.display(), err);
+                std::process::exit(EXIT_TRACE_ERROR);
+            }
+        }
+    }
+    let mut machine = match builder.load(file) {
+        Ok(machine) => machine,
+        Err(err) => {
+            eprintln!("{}: {}", file.display(), err);
+            std::process::exit(EXIT_LOAD_ERROR);
+        }
     };
-    cpu.load(
-        args.file
-            .clone()
-            .into_os_string()
-            .to_str()
-            .expect("not valid unicode"),
-    );
-
-    cpu.run(args);
+
+    if let Some(path) = &args.lockstep {
+        let status = run_lockstep(&mut machine, &args, path);
+        exit(&mut machine, &args, status)
+    }
+
+    if let Some(port) = args.gdb {
+        match debug(&mut machine, port) {
+            Ok(Session::Exited(code)) => {
+                let status =
+                    exit_status(&machine, &args, StopReason::Exited(code));
+                exit(&mut machine, &args, status)
+            }
+            Ok(Session::Killed) => exit(&mut machine, &args, 0),
+            // let the program carry on by itself
+            Ok(Session::Detached) => {}
+            Err(err) => {
+                eprintln!("gdb: {}", err);
+                std::process::exit(EXIT_GDB_ERROR);
+            }
+        }
+    }
+
+    let stop = if args.interactive {
+        run_interactive(&mut machine, &args)
+    } else {
+        run(&mut machine, &args)
+    };
+    let status = exit_status(&machine, &args, stop);
+    exit(&mut machine, &args, status)
+}
+
+/// Finishes writing the `--trace-file` and exits with `status`, unless
+/// the trace couldn't be written.
+fn exit(machine: &mut Machine, args: &Args, status: i32) -> ! {
+    if let Err(err) = machine.finish_trace() {
+        let path = args.trace_file.as_ref().unwrap();
+        eprintln!("{}: {}", path.display(), err);
+        std::process::exit(EXIT_TRACE_ERROR);
+    }
+    std::process::exit(status)
+}
+
+/// Prints a listing of `file`'s code.
+fn disassemble(file: &Path, syntax: Syntax) {
+    match disasm::listing(file, syntax) {
+        Ok(listing) => {
+            // a closed pipe, e.g. into `head`, isn't worth reporting
+            let _ = std::io::stdout().write_all(listing.as_bytes());
+        }
+        Err(err) => {
+            eprintln!("{}: {}", file.display(), err);
+            std::process::exit(EXIT_LOAD_ERROR);
+        }
+    }
+}
+
+/// Waits for GDB to connect on `port` and lets it drive the machine.
+fn debug(machine: &mut Machine, port: u16) -> std::io::Result<Session> {
+    let listener = TcpListener::bind(("127.0.0.1", port))?;
+    println!("Waiting for GDB on {}", listener.local_addr()?);
+    let (stream, _) = listener.accept()?;
+    gdb::serve(machine, stream)
+}
+
+/// Prints `step` like objdump does, followed by where the instruction
+/// is in the program if it has symbols or line information, or as a
+/// Spike commit log line.
+fn print_step(machine: &Machine, args: &Args, step: &Step) {
+    if args.trace_format == Some(TraceFormat::Spike) {
+        // instructions that trapped didn't retire, so Spike skips them
+        if let Some(commit) = machine.commit() {
+            println!("{}", trace::spike(step, commit));
+        }
+        return;
+    }
+    let text = disasm::disassemble(&step.inst, step.pc);
+    let location = machine.cpu().location(step.pc);
+    if location.is_empty() {
+        println!(
+            "{:<08x}:   {:08x}          \t{}",
+            step.pc, step.raw, text
+        );
+    } else {
+        println!(
+            "{:<08x}:   {:08x}          \t{:<28} # {}",
+            step.pc, step.raw, text, location
+        );
+    }
+}
+
+/// Reports why the program stopped, and where for anything but an exit,
+/// and picks the process exit status.
+fn exit_status(machine: &Machine, args: &Args, stop: StopReason) -> i32 {
+    let status = match stop {
+        StopReason::Exited(code) => {
+            println!("Program exited with exit code: {}", code);
+            return match args.stop_status {
+                true => (code != 0) as i32,
+                false => code,
+            };
+        }
+        StopReason::UnknownSyscall(_) => EXIT_UNKNOWN_SYSCALL,
+        StopReason::IllegalInstruction { .. } => EXIT_ILLEGAL_INSTRUCTION,
+        StopReason::MemoryFault { .. } => EXIT_MEMORY_FAULT,
+        StopReason::Breakpoint { .. } | StopReason::Watchpoint { .. } => {
+            EXIT_BREAKPOINT
+        }
+        StopReason::StepLimit => EXIT_STEP_LIMIT,
+    };
+    let location = machine.cpu().location(machine.cpu().pc());
+    if location.is_empty() || stop == StopReason::StepLimit {
+        eprintln!("{}", stop);
+    } else {
+        eprintln!("{} in {}", stop, location);
+    }
+    status
+}
+
+fn run(machine: &mut Machine, args: &Args) -> StopReason {
+    loop {
+        if args.registers {
+            machine.cpu().print_registers(args.aliases);
+        }
+        let step = machine.step();
+        if args.debug {
+            print_step(machine, args, &step);
+        }
+        if let Some(stop) = step.stop {
+            return stop;
+        }
+    }
+}
+
+/// Runs the program in lockstep with the reference trace at `path`,
+/// reporting both sides of a divergence along with the registers.
+fn run_lockstep(machine: &mut Machine, args: &Args, path: &Path) -> i32 {
+    let result = trace::open(path)
+        .and_then(|reference| trace::lockstep(machine, reference));
+    match result {
+        Ok(Lockstep::Stopped { stop, matched }) => {
+            println!("Matched the reference for {} instructions", matched);
+            exit_status(machine, args, stop)
+        }
+        Ok(ended @ Lockstep::ReferenceEnded { .. }) => {
+            println!("{}", ended);
+            0
+        }
+        Ok(diverged) => {
+            eprintln!("{}", diverged);
+            if let Lockstep::Diverged { actual, .. } = &diverged {
+                let location = machine.cpu().location(actual.pc);
+                if !location.is_empty() {
+                    eprintln!("in {}", location);
+                }
+            }
+            machine.cpu().print_registers(args.aliases);
+            EXIT_DIVERGED
+        }
+        Err(err) => {
+            eprintln!("{}: {}", path.display(), err);
+            EXIT_TRACE_ERROR
+        }
+    }
+}
+
+fn run_interactive(machine: &mut Machine, args: &Args) -> StopReason {
+    let mut buf = String::new();
+    loop {
+        buf.clear();
+        let location = machine.cpu().location(machine.cpu().pc());
+        if location.is_empty() {
+            print!("> ");
+        } else {
+            print!("{} > ", location);
+        }
+        std::io::stdout().flush().unwrap();
+        std::io::stdin().read_line(&mut buf).unwrap();
+        buf.pop();
+        if let Some(stop) = command_handler(machine, args, &buf) {
+            return stop;
+        }
+    }
+}
+
+/// Runs a command from the interactive prompt, where an empty command
+/// steps a single instruction. Returns the reason the program stopped
+/// if that ends the session.
+fn command_handler(
+    machine: &mut Machine,
+    args: &Args,
+    com: &str,
+) -> Option<StopReason> {
+    let tokens: Vec<&str> = com.split_whitespace().collect();
+    if tokens.is_empty() {
+        return step(machine, args, 1);
+    }
+    let cpu = machine.cpu();
+    match tokens[0] {
+        "mem" => match location(machine, tokens.get(1)) {
+            Ok(addr) => match cpu.read_memory(addr, 4) {
+                Some(bytes) => {
+                    let chunk =
+                        u32::from_le_bytes(bytes.try_into().unwrap());
+                    println!("{:#010x}", chunk)
+                }
+                None => println!("bad argument: memory out of bounds"),
+            },
+            Err(err) => println!("bad argument: {}", err),
+        },
+        "reg" => match tokens.get(1) {
+            None => cpu.print_registers(args.aliases),
+            Some(&"pc") => println!("{:#x}", cpu.pc()),
+            Some(name) => match register(name) {
+                Some(reg) => println!("{:#x}", cpu.register(reg)),
+                None => match Cpu::fp_register_number(name) {
+                    Some(reg) => println!("{:#x}", cpu.fp_register(reg)),
+                    None => println!("bad argument: no such register"),
+                },
+            },
+        },
+        "set" => {
+            let (target, expr) = match tokens.get(1) {
+                Some(&"reg") => (tokens.get(2).copied(), tokens.get(3)),
+                Some(&"pc") => (Some("pc"), tokens.get(2)),
+                _ => (None, None),
+            };
+            let value = match (target, location(machine, expr)) {
+                (Some(_), Ok(value)) => value,
+                (Some(_), Err(err)) => {
+                    println!("bad argument: {}", err);
+                    return None;
+                }
+                (None, _) => {
+                    println!("usage: set reg <register> <value> | set pc <value>");
+                    return None;
+                }
+            };
+            match target {
+                Some("pc") => machine.cpu_mut().set_pc(value),
+                Some(name) => match register(name) {
+                    Some(reg) => {
+                        machine.cpu_mut().set_register(reg, value)
+                    }
+                    None => println!("bad argument: no such register"),
+                },
+                None => unreachable!(),
+            }
+        }
+        "write" => {
+            if tokens.get(1) != Some(&"mem") || tokens.len() < 4 {
+                println!(
+                    "usage: write mem <address> <word> | <byte> <byte>..."
+                );
+                return None;
+            }
+            let addr = location(machine, tokens.get(2));
+            let values: Result<Vec<u64>, String> = tokens[3..]
+                .iter()
+                .map(|value| machine.eval(value))
+                .collect();
+            // a single value is a word, several are a string of bytes
+            let bytes = match values {
+                Ok(values) if values.len() == 1 => {
+                    Ok((values[0] as u32).to_le_bytes().to_vec())
+                }
+                Ok(values) => values
+                    .iter()
+                    .map(|&value| u8::try_from(value))
+                    .collect::<Result<Vec<u8>, _>>()
+                    .map_err(|_| String::from("bytes must be at most ff")),
+                Err(err) => Err(err),
+            };
+            match addr.and_then(|addr| Ok((addr, bytes?))) {
+                Ok((addr, bytes)) => {
+                    if machine
+                        .cpu_mut()
+                        .write_memory(addr, &bytes)
+                        .is_none()
+                    {
+                        println!("bad argument: memory out of bounds");
+                    }
+                }
+                Err(err) => println!("bad argument: {}", err),
+            }
+        }
+        dump if dump == "x" || dump.starts_with("x/") => {
+            match (dump_format(dump), location(machine, tokens.get(1))) {
+                (Some((count, format)), Ok(addr)) => {
+                    examine(machine, addr, count, format)
+                }
+                (None, _) => {
+                    println!("usage: x/<count><format> <address>, where the format is x, b, s or i")
+                }
+                (_, Err(err)) => println!("bad argument: {}", err),
+            }
+        }
+        "break" | "b" => match location(machine, tokens.get(1)) {
+            Ok(addr) => {
+                machine.add_breakpoint(addr);
+                println!("Breakpoint at {}", describe(machine, addr));
+            }
+            Err(err) => println!("bad argument: {}", err),
+        },
+        "watch" | "rwatch" | "awatch" => {
+            let kind = match tokens[0] {
+                "watch" => WatchKind::Write,
+                "rwatch" => WatchKind::Read,
+                _ => WatchKind::Access,
+            };
+            let len = match tokens.get(2).map(|len| len.parse::<u64>()) {
+                None => Ok(4),
+                Some(len) => len,
+            };
+            match (location(machine, tokens.get(1)), len) {
+                (Ok(addr), Ok(len)) if len > 0 => {
+                    let watchpoint = Watchpoint { addr, len, kind };
+                    machine.add_watchpoint(watchpoint);
+                    println!(
+                        "Watchpoint on {}",
+                        describe_watch(machine, &watchpoint)
+                    );
+                }
+                (Err(err), _) => println!("bad argument: {}", err),
+                _ => println!("bad argument: expected a length in bytes"),
+            }
+        }
+        "delete" | "d" => match tokens.get(1) {
+            None => {
+                let all: Vec<u64> = machine.breakpoints().collect();
+                for addr in all {
+                    machine.remove_breakpoint(addr);
+                }
+                let all = machine.watchpoints().to_vec();
+                for watchpoint in all {
+                    machine.remove_watchpoint(watchpoint);
+                }
+            }
+            Some(_) => match location(machine, tokens.get(1)) {
+                Ok(addr) => {
+                    let watchpoints: Vec<_> = machine
+                        .watchpoints()
+                        .iter()
+                        .filter(|w| w.addr == addr)
+                        .copied()
+                        .collect();
+                    let breakpoint = machine.remove_breakpoint(addr);
+                    for watchpoint in &watchpoints {
+                        machine.remove_watchpoint(*watchpoint);
+                    }
+                    if !breakpoint && watchpoints.is_empty() {
+                        println!("bad argument: nothing to delete there");
+                    }
+                }
+                Err(err) => println!("bad argument: {}", err),
+            },
+        },
+        "info" => match tokens.get(1) {
+            Some(&"breakpoints") | Some(&"b") => {
+                if machine.breakpoints().next().is_none() {
+                    println!("No breakpoints");
+                }
+                for addr in machine.breakpoints() {
+                    println!("{}", describe(machine, addr));
+                }
+            }
+            Some(&"watchpoints") | Some(&"w") => {
+                if machine.watchpoints().is_empty() {
+                    println!("No watchpoints");
+                }
+                for watchpoint in machine.watchpoints() {
+                    println!("{}", describe_watch(machine, watchpoint));
+                }
+            }
+            _ => println!("Unknown command: {}", com),
+        },
+        "continue" | "c" => return resume(machine, args, |_| false),
+        "step" | "s" => match tokens.get(1).map(|n| n.parse::<u64>()) {
+            None => return step(machine, args, 1),
+            Some(Ok(n)) => return step(machine, args, n),
+            Some(Err(err)) => println!("bad argument: {}", err),
+        },
+        "until" | "u" => match location(machine, tokens.get(1)) {
+            Ok(addr) => {
+                return resume(machine, args, |cpu| cpu.pc() == addr)
+            }
+            Err(err) => println!("bad argument: {}", err),
+        },
+        "finish" | "fin" => {
+            // the stack pointer tells a return from this call apart from
+            // returns to the same address from deeper recursive calls
+            let (ra, sp) = (cpu.register(1), cpu.register(2));
+            return resume(machine, args, |cpu| {
+                cpu.pc() == ra && cpu.register(2) >= sp
+            });
+        }
+        _ => {
+            println!("Unknown command: {}", tokens[0])
+        }
+    }
+    None
+}
+
+/// Executes `n` instructions, printing each one, unless the program
+/// stops first.
+fn step(machine: &mut Machine, args: &Args, n: u64) -> Option<StopReason> {
+    for _ in 0..n {
+        let step = machine.step();
+        if args.registers {
+            machine.cpu().print_registers(args.aliases);
+        }
+        print_step(machine, args, &step);
+        if let Some(stop) = step.stop {
+            return pause(machine, stop);
+        }
+    }
+    None
+}
+
+/// Runs without printing anything until `until` is true of the hart or
+/// the program stops. The first instruction always runs, so this moves
+/// past a breakpoint the program is sitting on.
+fn resume(
+    machine: &mut Machine,
+    args: &Args,
+    mut until: impl FnMut(&Cpu) -> bool,
+) -> Option<StopReason> {
+    let stop = match machine.step().stop {
+        Some(stop) => Some(stop),
+        None => machine.run_until(&mut until),
+    };
+    match stop {
+        Some(stop) => {
+            let stop = pause(machine, stop);
+            if stop.is_some() {
+                return stop;
+            }
+        }
+        None => println!(
+            "Stopped at {}",
+            describe(machine, machine.cpu().pc())
+        ),
+    }
+    if args.registers {
+        machine.cpu().print_registers(args.aliases);
+    }
+    None
+}
+
+/// Reaching one of the breakpoints set at the prompt only pauses the
+/// program. Any other stop ends the session and is passed on.
+fn pause(machine: &Machine, stop: StopReason) -> Option<StopReason> {
+    match stop {
+        StopReason::Breakpoint { pc }
+            if machine.breakpoints().any(|addr| addr == pc) =>
+        {
+            println!("Breakpoint at {}", describe(machine, pc));
+            None
+        }
+        StopReason::Watchpoint {
+            addr,
+            kind,
+            pc,
+            raw,
+            old,
+            new,
+        } => {
+            let watchpoint = Watchpoint { addr, kind, len: 0 };
+            let inst = Cpu::decode(raw, machine.cpu().xlen());
+            println!(
+                "Watchpoint on {} hit by {}: {}",
+                describe_watch(machine, &watchpoint),
+                describe(machine, pc),
+                disasm::disassemble(&inst, pc)
+            );
+            if old == new {
+                println!("Value = {:#010x}", old);
+            } else {
+                println!("Old value = {:#010x}", old);
+                println!("New value = {:#010x}", new);
+            }
+            None
+        }
+        stop => Some(stop),
+    }
+}
+
+/// Evaluates a command argument that gives an address or a value.
+fn location(machine: &Machine, arg: Option<&&str>) -> Result<u64, String> {
+    match arg {
+        Some(arg) => machine.eval(arg),
+        None => Err(String::from("expected an address or value")),
+    }
+}
+
+/// The number of the register called `name`, which may also be given as
+/// a plain number for `reg 5`.
+fn register(name: &str) -> Option<usize> {
+    match name.parse::<usize>() {
+        Ok(n) => (n < 32).then_some(n),
+        Err(_) => Cpu::register_number(name),
+    }
+}
+
+/// Splits the `/<count><format>` suffix of an `x` command. Both parts are
+/// optional, dumping one hex word by default.
+fn dump_format(command: &str) -> Option<(usize, char)> {
+    let spec = command.strip_prefix("x").unwrap_or_default();
+    let spec = spec.strip_prefix('/').unwrap_or(spec);
+    let digits = spec.len()
+        - spec.trim_start_matches(|c: char| c.is_ascii_digit()).len();
+    let count = match digits {
+        0 => 1,
+        _ => spec[..digits].parse().ok()?,
+    };
+    match &spec[digits..] {
+        "" => Some((count, 'x')),
+        format @ ("x" | "b" | "s" | "i") => {
+            Some((count, format.chars().next()?))
+        }
+        _ => None,
+    }
+}
+
+/// Prints `count` items of memory from `addr`: hex words (`x`), bytes
+/// with their ASCII (`b`), NUL-terminated strings (`s`) or instructions
+/// (`i`).
+fn examine(machine: &Machine, addr: u64, count: usize, format: char) {
+    let cpu = machine.cpu();
+    let mut addr = addr;
+    match format {
+        'x' => {
+            for row in 0..count.div_ceil(4) {
+                let words = (count - row * 4).min(4);
+                let Some(bytes) = cpu.read_memory(addr, words * 4) else {
+                    println!("{:#010x}: memory out of bounds", addr);
+                    return;
+                };
+                let words: Vec<String> = bytes
+                    .chunks_exact(4)
+                    .map(|w| {
+                        format!(
+                            "{:#010x}",
+                            u32::from_le_bytes(w.try_into().unwrap())
+                        )
+                    })
+                    .collect();
+                println!(
+                    "{}:  {}",
+                    describe(machine, addr),
+                    words.join(" ")
+                );
+                addr = addr.wrapping_add(16);
+            }
+        }
+        'b' => {
+            for row in 0..count.div_ceil(16) {
+                let len = (count - row * 16).min(16);
+                let Some(bytes) = cpu.read_memory(addr, len) else {
+                    println!("{:#010x}: memory out of bounds", addr);
+                    return;
+                };
+                let hex: Vec<String> =
+                    bytes.iter().map(|b| format!("{:02x}", b)).collect();
+                let ascii: String = bytes
+                    .iter()
+                    .map(|&b| match b.is_ascii_graphic() || b == b' ' {
+                        true => b as char,
+                        false => '.',
+                    })
+                    .collect();
+                println!(
+                    "{:08x}:  {:<47}  |{}|",
+                    addr,
+                    hex.join(" "),
+                    ascii
+                );
+                addr = addr.wrapping_add(16);
+            }
+        }
+        's' => {
+            for _ in 0..count {
+                let start = addr;
+                let mut string = Vec::new();
+                while let Some(&[byte]) = cpu.read_memory(addr, 1) {
+                    addr = addr.wrapping_add(1);
+                    if byte == 0 {
+                        break;
+                    }
+                    string.push(byte);
+                }
+                println!(
+                    "{}:  {:?}",
+                    describe(machine, start),
+                    String::from_utf8_lossy(&string)
+                );
+            }
+        }
+        _ => {
+            for _ in 0..count {
+                let raw = match cpu.read_memory(addr, 2) {
+                    Some(half) if half[0] & 0b11 != 0b11 => {
+                        u16::from_le_bytes([half[0], half[1]]) as u32
+                    }
+                    Some(_) => match cpu.read_memory(addr, 4) {
+                        Some(word) => {
+                            u32::from_le_bytes(word.try_into().unwrap())
+                        }
+                        None => break,
+                    },
+                    None => break,
+                };
+                let inst = Cpu::decode(raw, cpu.xlen());
+                println!(
+                    "{}:  {}",
+                    describe(machine, addr),
+                    disasm::disassemble(&inst, addr)
+                );
+                addr = addr.wrapping_add(inst.size as u64);
+            }
+        }
+    }
+}
+
+/// Formats a watchpoint's kind and address, and its length if known.
+fn describe_watch(machine: &Machine, watchpoint: &Watchpoint) -> String {
+    let kind = match watchpoint.kind {
+        WatchKind::Read => "read",
+        WatchKind::Write => "write",
+        WatchKind::Access => "access",
+    };
+    match watchpoint.len {
+        0 => format!("{} ({})", describe(machine, watchpoint.addr), kind),
+        len => format!(
+            "{} ({}, {} bytes)",
+            describe(machine, watchpoint.addr),
+            kind,
+            len
+        ),
+    }
+}
+
+/// Formats `addr` along with the symbol it's in, if any.
+fn describe(machine: &Machine, addr: u64) -> String {
+    let location = machine.cpu().location(addr);
+    if location.is_empty() {
+        format!("{:#010x}", addr)
+    } else {
+        format!("{:#010x} {}", addr, location)
+    }
 }
@@ -1,705 +1,1234 @@
 #[allow(unused_imports)]
-use crate::Args;
+use crate::emulator::gdb::{self, Session};
 #[allow(unused_imports)]
-use crate::Cpu;
+use crate::{
+    FaultKind, LoadError, Machine, StopReason, WatchKind, Watchpoint,
+};
+#[test]
+fn access() {
+    let mut machine = Machine::builder().load("./tests/access").unwrap();
+    assert_eq!(machine.run(), StopReason::Exited(0));
+}
+
 #[test]
 fn add() {
-    let mut cpu = Cpu::new(16);
-    let args = Args {
-        file: std::path::PathBuf::new(),
-        debug: false,
-        registers: false,
-        aliases: false,
-        interactive: false,
-        pc: None,
-        stack: false,
-        mem: None,
-    };
-    cpu.load("./tests/add");
-    let ret = cpu.run(args);
-    assert_eq!(ret, 0);
+    let mut machine = Machine::builder().load("./tests/add").unwrap();
+    assert_eq!(machine.run(), StopReason::Exited(0));
 }
 
 #[test]
 fn addi() {
-    let mut cpu = Cpu::new(16);
-    let args = Args {
-        file: std::path::PathBuf::new(),
-        debug: false,
-        registers: false,
-        aliases: false,
-        interactive: false,
-        pc: None,
-        stack: false,
-        mem: None,
-    };
-    cpu.load("./tests/addi");
-    let ret = cpu.run(args);
-    assert_eq!(ret, 0);
+    let mut machine = Machine::builder().load("./tests/addi").unwrap();
+    assert_eq!(machine.run(), StopReason::Exited(0));
+}
+
+#[test]
+fn amoadd_w() {
+    let mut machine = Machine::builder().load("./tests/amoadd_w").unwrap();
+    assert_eq!(machine.run(), StopReason::Exited(0));
+}
+
+#[test]
+fn amoand_w() {
+    let mut machine = Machine::builder().load("./tests/amoand_w").unwrap();
+    assert_eq!(machine.run(), StopReason::Exited(0));
+}
+
+#[test]
+fn amomax_w() {
+    let mut machine = Machine::builder().load("./tests/amomax_w").unwrap();
+    assert_eq!(machine.run(), StopReason::Exited(0));
+}
+
+#[test]
+fn amomaxu_w() {
+    let mut machine =
+        Machine::builder().load("./tests/amomaxu_w").unwrap();
+    assert_eq!(machine.run(), StopReason::Exited(0));
+}
+
+#[test]
+fn amomin_w() {
+    let mut machine = Machine::builder().load("./tests/amomin_w").unwrap();
+    assert_eq!(machine.run(), StopReason::Exited(0));
+}
+
+#[test]
+fn amominu_w() {
+    let mut machine =
+        Machine::builder().load("./tests/amominu_w").unwrap();
+    assert_eq!(machine.run(), StopReason::Exited(0));
+}
+
+#[test]
+fn amoor_w() {
+    let mut machine = Machine::builder().load("./tests/amoor_w").unwrap();
+    assert_eq!(machine.run(), StopReason::Exited(0));
+}
+
+#[test]
+fn amoswap_w() {
+    let mut machine =
+        Machine::builder().load("./tests/amoswap_w").unwrap();
+    assert_eq!(machine.run(), StopReason::Exited(0));
+}
+
+#[test]
+fn amoxor_w() {
+    let mut machine = Machine::builder().load("./tests/amoxor_w").unwrap();
+    assert_eq!(machine.run(), StopReason::Exited(0));
 }
 
 #[test]
 fn and() {
-    let mut cpu = Cpu::new(16);
-    let args = Args {
-        file: std::path::PathBuf::new(),
-        debug: false,
-        registers: false,
-        aliases: false,
-        interactive: false,
-        pc: None,
-        stack: false,
-        mem: None,
-    };
-    cpu.load("./tests/and");
-    let ret = cpu.run(args);
-    assert_eq!(ret, 0);
+    let mut machine = Machine::builder().load("./tests/and").unwrap();
+    assert_eq!(machine.run(), StopReason::Exited(0));
 }
 
 #[test]
 fn andi() {
-    let mut cpu = Cpu::new(16);
-    let args = Args {
-        file: std::path::PathBuf::new(),
-        debug: false,
-        registers: false,
-        aliases: false,
-        interactive: false,
-        pc: None,
-        stack: false,
-        mem: None,
-    };
-    cpu.load("./tests/andi");
-    let ret = cpu.run(args);
-    assert_eq!(ret, 0);
+    let mut machine = Machine::builder().load("./tests/andi").unwrap();
+    assert_eq!(machine.run(), StopReason::Exited(0));
+}
+
+#[test]
+fn andn() {
+    let mut machine = Machine::builder().load("./tests/andn").unwrap();
+    assert_eq!(machine.run(), StopReason::Exited(0));
+}
+
+#[test]
+fn args() {
+    let mut machine = Machine::builder()
+        .stack(true)
+        .args(["one", "-two"])
+        .env("A=1")
+        .load("./tests/args")
+        .unwrap();
+    assert_eq!(machine.run(), StopReason::Exited(0));
 }
 
 #[test]
 fn auipc() {
-    let mut cpu = Cpu::new(16);
-    let args = Args {
-        file: std::path::PathBuf::new(),
-        debug: false,
-        registers: false,
-        aliases: false,
-        interactive: false,
-        pc: None,
-        stack: false,
-        mem: None,
-    };
-    cpu.load("./tests/auipc");
-    let ret = cpu.run(args);
-    assert_eq!(ret, 0);
+    let mut machine = Machine::builder().load("./tests/auipc").unwrap();
+    assert_eq!(machine.run(), StopReason::Exited(0));
+}
+
+#[test]
+fn bclr() {
+    let mut machine = Machine::builder().load("./tests/bclr").unwrap();
+    assert_eq!(machine.run(), StopReason::Exited(0));
+}
+
+#[test]
+fn bclri() {
+    let mut machine = Machine::builder().load("./tests/bclri").unwrap();
+    assert_eq!(machine.run(), StopReason::Exited(0));
 }
 
 #[test]
 fn beq() {
-    let mut cpu = Cpu::new(16);
-    let args = Args {
-        file: std::path::PathBuf::new(),
-        debug: false,
-        registers: false,
-        aliases: false,
-        interactive: false,
-        pc: None,
-        stack: false,
-        mem: None,
-    };
-    cpu.load("./tests/beq");
-    let ret = cpu.run(args);
-    assert_eq!(ret, 0);
+    let mut machine = Machine::builder().load("./tests/beq").unwrap();
+    assert_eq!(machine.run(), StopReason::Exited(0));
+}
+
+#[test]
+fn bext() {
+    let mut machine = Machine::builder().load("./tests/bext").unwrap();
+    assert_eq!(machine.run(), StopReason::Exited(0));
+}
+
+#[test]
+fn bexti() {
+    let mut machine = Machine::builder().load("./tests/bexti").unwrap();
+    assert_eq!(machine.run(), StopReason::Exited(0));
 }
 
 #[test]
 fn bge() {
-    let mut cpu = Cpu::new(16);
-    let args = Args {
-        file: std::path::PathBuf::new(),
-        debug: false,
-        registers: false,
-        aliases: false,
-        interactive: false,
-        pc: None,
-        stack: false,
-        mem: None,
-    };
-    cpu.load("./tests/bge");
-    let ret = cpu.run(args);
-    assert_eq!(ret, 0);
+    let mut machine = Machine::builder().load("./tests/bge").unwrap();
+    assert_eq!(machine.run(), StopReason::Exited(0));
 }
 
 #[test]
 fn bgeu() {
-    let mut cpu = Cpu::new(16);
-    let args = Args {
-        file: std::path::PathBuf::new(),
-        debug: false,
-        registers: false,
-        aliases: false,
-        interactive: false,
-        pc: None,
-        stack: false,
-        mem: None,
-    };
-    cpu.load("./tests/bgeu");
-    let ret = cpu.run(args);
-    assert_eq!(ret, 0);
+    let mut machine = Machine::builder().load("./tests/bgeu").unwrap();
+    assert_eq!(machine.run(), StopReason::Exited(0));
+}
+
+#[test]
+fn binv() {
+    let mut machine = Machine::builder().load("./tests/binv").unwrap();
+    assert_eq!(machine.run(), StopReason::Exited(0));
+}
+
+#[test]
+fn binvi() {
+    let mut machine = Machine::builder().load("./tests/binvi").unwrap();
+    assert_eq!(machine.run(), StopReason::Exited(0));
 }
 
 #[test]
 fn blt() {
-    let mut cpu = Cpu::new(16);
-    let args = Args {
-        file: std::path::PathBuf::new(),
-        debug: false,
-        registers: false,
-        aliases: false,
-        interactive: false,
-        pc: None,
-        stack: false,
-        mem: None,
-    };
-    cpu.load("./tests/blt");
-    let ret = cpu.run(args);
-    assert_eq!(ret, 0);
+    let mut machine = Machine::builder().load("./tests/blt").unwrap();
+    assert_eq!(machine.run(), StopReason::Exited(0));
 }
 
 #[test]
 fn bltu() {
-    let mut cpu = Cpu::new(16);
-    let args = Args {
-        file: std::path::PathBuf::new(),
-        debug: false,
-        registers: false,
-        aliases: false,
-        interactive: false,
-        pc: None,
-        stack: false,
-        mem: None,
-    };
-    cpu.load("./tests/bltu");
-    let ret = cpu.run(args);
-    assert_eq!(ret, 0);
+    let mut machine = Machine::builder().load("./tests/bltu").unwrap();
+    assert_eq!(machine.run(), StopReason::Exited(0));
 }
 
 #[test]
 fn bne() {
-    let mut cpu = Cpu::new(16);
-    let args = Args {
-        file: std::path::PathBuf::new(),
-        debug: false,
-        registers: false,
-        aliases: false,
-        interactive: false,
-        pc: None,
-        stack: false,
-        mem: None,
+    let mut machine = Machine::builder().load("./tests/bne").unwrap();
+    assert_eq!(machine.run(), StopReason::Exited(0));
+}
+
+#[test]
+fn bset() {
+    let mut machine = Machine::builder().load("./tests/bset").unwrap();
+    assert_eq!(machine.run(), StopReason::Exited(0));
+}
+
+#[test]
+fn bseti() {
+    let mut machine = Machine::builder().load("./tests/bseti").unwrap();
+    assert_eq!(machine.run(), StopReason::Exited(0));
+}
+
+#[test]
+fn c_jr_x0() {
+    // reserved, rather than an `add x0, x0, x0`
+    let mut machine = Machine::builder().load("./tests/add").unwrap();
+    let pc = machine.cpu().pc();
+    machine.cpu_mut().write_memory(pc, &0x8002u16.to_le_bytes());
+    assert_eq!(
+        machine.run(),
+        StopReason::IllegalInstruction { pc, raw: 0x8002 }
+    );
+}
+
+#[test]
+fn clmul() {
+    let mut machine = Machine::builder().load("./tests/clmul").unwrap();
+    assert_eq!(machine.run(), StopReason::Exited(0));
+}
+
+#[test]
+fn clmulh() {
+    let mut machine = Machine::builder().load("./tests/clmulh").unwrap();
+    assert_eq!(machine.run(), StopReason::Exited(0));
+}
+
+#[test]
+fn clmulr() {
+    let mut machine = Machine::builder().load("./tests/clmulr").unwrap();
+    assert_eq!(machine.run(), StopReason::Exited(0));
+}
+
+#[test]
+fn clz() {
+    let mut machine = Machine::builder().load("./tests/clz").unwrap();
+    assert_eq!(machine.run(), StopReason::Exited(0));
+}
+
+#[test]
+fn cpop() {
+    let mut machine = Machine::builder().load("./tests/cpop").unwrap();
+    assert_eq!(machine.run(), StopReason::Exited(0));
+}
+
+#[test]
+fn csr() {
+    let mut machine = Machine::builder().load("./tests/csr").unwrap();
+    assert_eq!(machine.run(), StopReason::Exited(0));
+}
+
+#[test]
+fn ctz() {
+    let mut machine = Machine::builder().load("./tests/ctz").unwrap();
+    assert_eq!(machine.run(), StopReason::Exited(0));
+}
+
+#[test]
+fn disasm() {
+    use crate::emulator::disasm::{self, Syntax};
+    let syntax = Syntax {
+        aliases: true,
+        pseudo: true,
     };
-    cpu.load("./tests/bne");
-    let ret = cpu.run(args);
-    assert_eq!(ret, 0);
+    let listing = disasm::listing("./benches/fib", syntax).unwrap();
+    for line in [
+        "80000000 <_start>:",
+        "80000000:\t4579              \tli      a0,30",
+        "80000022 <fib>:",
+        "80000024:\t00a2c363          \tblt     t0,a0,8000002a <fib+0x8>",
+        "80000028:\t8082              \tret",
+        "80000032:\t842a              \tmv      s0,a0",
+    ] {
+        assert!(listing.lines().any(|l| l == line), "{}", line);
+    }
+
+    let listing =
+        disasm::listing("./tests/add", Syntax::default()).unwrap();
+    assert!(listing.lines().any(|l| l
+        == "80000000:\t0480006f          \tjal     x0,80000048 <reset_vector>"));
+
+    let listing = disasm::listing("./tests/rv64ui/addiw", syntax).unwrap();
+    assert!(listing.starts_with(
+        "./tests/rv64ui/addiw:     file format elf64-littleriscv\n"
+    ));
+    assert!(listing.lines().any(|l| l
+        == "80000178:\t0000871b          \tsext.w  a4,ra"));
+
+    let listing =
+        disasm::listing("./tests/rev8", Syntax::default()).unwrap();
+    assert!(listing
+        .lines()
+        .any(|l| l == "80000178:\t6980d713          \trev8    x14,x1"));
+    let listing =
+        disasm::listing("./tests/rv64uzb/add_uw", syntax).unwrap();
+    assert!(listing
+        .lines()
+        .any(|l| l == "8000030c:\t080383bb          \tzext.w  t2,t2"));
+    assert!(matches!(
+        disasm::listing("./tests/add.dump", syntax),
+        Err(LoadError::NotElf)
+    ));
+}
+
+#[test]
+fn div() {
+    let mut machine = Machine::builder().load("./tests/div").unwrap();
+    assert_eq!(machine.run(), StopReason::Exited(0));
+}
+
+#[test]
+fn divu() {
+    let mut machine = Machine::builder().load("./tests/divu").unwrap();
+    assert_eq!(machine.run(), StopReason::Exited(0));
+}
+
+#[test]
+fn eval() {
+    let mut machine = Machine::builder().load("./tests/add").unwrap();
+    machine.cpu_mut().set_register(2, 0x2000);
+    machine.cpu_mut().set_register(10, 0x1000);
+    let pc = machine.cpu().pc();
+    // register names win over hex, which can have a 0x to tell them apart
+    assert_eq!(machine.eval("a0"), Ok(0x1000));
+    assert_eq!(machine.eval("0xa0"), Ok(0xa0));
+    assert_eq!(machine.eval("0123abcd"), Ok(0x0123abcd));
+    assert_eq!(machine.eval("$a0"), Ok(0x1000));
+    assert_eq!(machine.eval("sp+8"), Ok(0x2008));
+    assert_eq!(machine.eval("$x10+8"), Ok(0x1008));
+    assert_eq!(machine.eval("pc-0x10"), Ok(pc - 0x10));
+    assert_eq!(machine.eval("$pc"), Ok(pc));
+    assert_eq!(machine.eval("-4"), Ok(4u64.wrapping_neg()));
+    assert!(machine.eval("$a8").is_err());
+    assert!(machine.eval("nowhere").is_err());
+}
+
+#[test]
+fn fadd_d() {
+    let mut machine = Machine::builder().load("./tests/fadd_d").unwrap();
+    assert_eq!(machine.run(), StopReason::Exited(0));
+}
+
+#[test]
+fn fadd_s() {
+    let mut machine = Machine::builder().load("./tests/fadd_s").unwrap();
+    assert_eq!(machine.run(), StopReason::Exited(0));
+}
+
+#[test]
+fn fclass_d() {
+    let mut machine = Machine::builder().load("./tests/fclass_d").unwrap();
+    assert_eq!(machine.run(), StopReason::Exited(0));
+}
+
+#[test]
+fn fclass_s() {
+    let mut machine = Machine::builder().load("./tests/fclass_s").unwrap();
+    assert_eq!(machine.run(), StopReason::Exited(0));
+}
+
+#[test]
+fn fcmp_d() {
+    let mut machine = Machine::builder().load("./tests/fcmp_d").unwrap();
+    assert_eq!(machine.run(), StopReason::Exited(0));
+}
+
+#[test]
+fn fcmp_s() {
+    let mut machine = Machine::builder().load("./tests/fcmp_s").unwrap();
+    assert_eq!(machine.run(), StopReason::Exited(0));
+}
+
+#[test]
+fn fcvt_d() {
+    let mut machine = Machine::builder().load("./tests/fcvt_d").unwrap();
+    assert_eq!(machine.run(), StopReason::Exited(0));
+}
+
+#[test]
+fn fcvt_s() {
+    let mut machine = Machine::builder().load("./tests/fcvt_s").unwrap();
+    assert_eq!(machine.run(), StopReason::Exited(0));
+}
+
+#[test]
+fn fcvt_w_d() {
+    let mut machine = Machine::builder().load("./tests/fcvt_w_d").unwrap();
+    assert_eq!(machine.run(), StopReason::Exited(0));
+}
+
+#[test]
+fn fcvt_w_s() {
+    let mut machine = Machine::builder().load("./tests/fcvt_w_s").unwrap();
+    assert_eq!(machine.run(), StopReason::Exited(0));
+}
+
+#[test]
+fn fdiv_d() {
+    let mut machine = Machine::builder().load("./tests/fdiv_d").unwrap();
+    assert_eq!(machine.run(), StopReason::Exited(0));
+}
+
+#[test]
+fn fdiv_s() {
+    let mut machine = Machine::builder().load("./tests/fdiv_s").unwrap();
+    assert_eq!(machine.run(), StopReason::Exited(0));
 }
 
 #[test]
 fn fence_i() {
-    let mut cpu = Cpu::new(16);
-    let args = Args {
-        file: std::path::PathBuf::new(),
-        debug: false,
-        registers: false,
-        aliases: false,
-        interactive: false,
-        pc: None,
-        stack: false,
-        mem: None,
+    let mut machine = Machine::builder().load("./tests/fence_i").unwrap();
+    assert_eq!(machine.run(), StopReason::Exited(0));
+}
+
+#[test]
+fn fmadd_d() {
+    let mut machine = Machine::builder().load("./tests/fmadd_d").unwrap();
+    assert_eq!(machine.run(), StopReason::Exited(0));
+}
+
+#[test]
+fn fmadd_s() {
+    let mut machine = Machine::builder().load("./tests/fmadd_s").unwrap();
+    assert_eq!(machine.run(), StopReason::Exited(0));
+}
+
+#[test]
+fn fmin_d() {
+    let mut machine = Machine::builder().load("./tests/fmin_d").unwrap();
+    assert_eq!(machine.run(), StopReason::Exited(0));
+}
+
+#[test]
+fn fmin_s() {
+    let mut machine = Machine::builder().load("./tests/fmin_s").unwrap();
+    assert_eq!(machine.run(), StopReason::Exited(0));
+}
+
+#[test]
+fn gdb() {
+    use std::io::{Read, Write};
+    use std::net::{TcpListener, TcpStream};
+
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let server = std::thread::spawn(move || {
+        let mut machine = Machine::builder().load("./tests/lw").unwrap();
+        let (stream, _) = listener.accept().unwrap();
+        gdb::serve(&mut machine, stream).unwrap()
+    });
+    let mut client = TcpStream::connect(addr).unwrap();
+    let mut packet = |data: &str| {
+        let checksum = data.bytes().fold(0u8, |sum, b| sum.wrapping_add(b));
+        write!(client, "${}#{:02x}", data, checksum).unwrap();
+        let mut reply = Vec::new();
+        let mut byte = [0];
+        while reply.len() < 3 || reply[reply.len() - 3] != b'#' {
+            client.read_exact(&mut byte).unwrap();
+            reply.push(byte[0]);
+        }
+        // strip the ack, the '$' and the checksum
+        String::from_utf8(reply[2..reply.len() - 3].to_vec()).unwrap()
     };
-    cpu.load("./tests/fence_i");
-    let ret = cpu.run(args);
-    assert_eq!(ret, 0);
+
+    assert_eq!(packet("?"), "S05");
+    assert_eq!(packet("p20"), "00000080");
+    assert_eq!(packet("m80000000,4"), "6f008004");
+    assert_eq!(packet("Z0,80000048,4"), "OK");
+    assert_eq!(packet("c"), "S05");
+    assert_eq!(packet("p20"), "48000080");
+    assert_eq!(packet("z0,80000048,4"), "OK");
+    assert_eq!(packet("s"), "S05");
+    assert_eq!(packet("p20"), "4c000080");
+    assert_eq!(packet("P1=efbeadde"), "OK");
+    assert_eq!(packet("p1"), "efbeadde");
+    assert_eq!(packet("M80003000,2:1234"), "OK");
+    assert_eq!(packet("m80003000,2"), "1234");
+    assert_eq!(packet("m7ffffffc,4"), "E14");
+    assert_eq!(packet("Z3,8000200c,4"), "OK");
+    assert_eq!(packet("c"), "T05rwatch:8000200c;");
+    assert_eq!(packet("z3,8000200c,4"), "OK");
+    assert_eq!(packet("c"), "W00");
+    assert_eq!(server.join().unwrap(), Session::Exited(0));
+}
+
+#[test]
+fn illegal() {
+    let mut machine = Machine::builder().load("./tests/illegal").unwrap();
+    assert_eq!(machine.run(), StopReason::Exited(0));
 }
 
 #[test]
 fn jal() {
-    let mut cpu = Cpu::new(16);
-    let args = Args {
-        file: std::path::PathBuf::new(),
-        debug: false,
-        registers: false,
-        aliases: false,
-        interactive: false,
-        pc: None,
-        stack: false,
-        mem: None,
-    };
-    cpu.load("./tests/jal");
-    let ret = cpu.run(args);
-    assert_eq!(ret, 0);
+    let mut machine = Machine::builder().load("./tests/jal").unwrap();
+    assert_eq!(machine.run(), StopReason::Exited(0));
 }
 
 #[test]
 fn jalr() {
-    let mut cpu = Cpu::new(16);
-    let args = Args {
-        file: std::path::PathBuf::new(),
-        debug: false,
-        registers: false,
-        aliases: false,
-        interactive: false,
-        pc: None,
-        stack: false,
-        mem: None,
-    };
-    cpu.load("./tests/jalr");
-    let ret = cpu.run(args);
-    assert_eq!(ret, 0);
+    let mut machine = Machine::builder().load("./tests/jalr").unwrap();
+    assert_eq!(machine.run(), StopReason::Exited(0));
 }
 
 #[test]
 fn lb() {
-    let mut cpu = Cpu::new(16);
-    let args = Args {
-        file: std::path::PathBuf::new(),
-        debug: false,
-        registers: false,
-        aliases: false,
-        interactive: false,
-        pc: None,
-        stack: false,
-        mem: None,
-    };
-    cpu.load("./tests/lb");
-    let ret = cpu.run(args);
-    assert_eq!(ret, 0);
+    let mut machine = Machine::builder().load("./tests/lb").unwrap();
+    assert_eq!(machine.run(), StopReason::Exited(0));
 }
 
 #[test]
 fn lbu() {
-    let mut cpu = Cpu::new(16);
-    let args = Args {
-        file: std::path::PathBuf::new(),
-        debug: false,
-        registers: false,
-        aliases: false,
-        interactive: false,
-        pc: None,
-        stack: false,
-        mem: None,
-    };
-    cpu.load("./tests/lbu");
-    let ret = cpu.run(args);
-    assert_eq!(ret, 0);
+    let mut machine = Machine::builder().load("./tests/lbu").unwrap();
+    assert_eq!(machine.run(), StopReason::Exited(0));
+}
+
+#[test]
+fn ldst_d() {
+    let mut machine = Machine::builder().load("./tests/ldst_d").unwrap();
+    assert_eq!(machine.run(), StopReason::Exited(0));
+}
+
+#[test]
+fn ldst_s() {
+    let mut machine = Machine::builder().load("./tests/ldst_s").unwrap();
+    assert_eq!(machine.run(), StopReason::Exited(0));
 }
 
 #[test]
 fn lh() {
-    let mut cpu = Cpu::new(16);
-    let args = Args {
-        file: std::path::PathBuf::new(),
-        debug: false,
-        registers: false,
-        aliases: false,
-        interactive: false,
-        pc: None,
-        stack: false,
-        mem: None,
-    };
-    cpu.load("./tests/lh");
-    let ret = cpu.run(args);
-    assert_eq!(ret, 0);
+    let mut machine = Machine::builder().load("./tests/lh").unwrap();
+    assert_eq!(machine.run(), StopReason::Exited(0));
 }
 
 #[test]
 fn lhu() {
-    let mut cpu = Cpu::new(16);
-    let args = Args {
-        file: std::path::PathBuf::new(),
-        debug: false,
-        registers: false,
-        aliases: false,
-        interactive: false,
-        pc: None,
-        stack: false,
-        mem: None,
-    };
-    cpu.load("./tests/lhu");
-    let ret = cpu.run(args);
-    assert_eq!(ret, 0);
+    let mut machine = Machine::builder().load("./tests/lhu").unwrap();
+    assert_eq!(machine.run(), StopReason::Exited(0));
+}
+
+#[test]
+fn lines() {
+    let machine = Machine::builder().load("./benches/fib").unwrap();
+    let lines = machine.cpu().lines();
+    assert_eq!(lines.lookup(0x80000000), Some(("fib.S", 10)));
+    // the two halves of a call share a line
+    assert_eq!(lines.lookup(0x80000002), Some(("fib.S", 11)));
+    assert_eq!(lines.lookup(0x80000006), Some(("fib.S", 11)));
+    assert_eq!(lines.lookup(0x8000002a), Some(("fib.S", 22)));
+    assert_eq!(lines.lookup(0x80000054), Some(("fib.S", 36)));
+    // nothing before the code or after its last instruction
+    assert_eq!(lines.lookup(0x7ffffffe), None);
+    assert_eq!(lines.lookup(0x80000058), None);
+    assert_eq!(machine.cpu().location(0x8000002c), "<fib+0xa> fib.S:23");
+
+    // the riscv-tests binaries have symbols but no debug information
+    let machine = Machine::builder().load("./tests/simple").unwrap();
+    assert_eq!(machine.cpu().lines().lookup(0x80000000), None);
+    assert_eq!(machine.cpu().location(0x80000008), "<trap_vector+0x4>");
+}
+
+#[test]
+fn load_error() {
+    assert!(matches!(
+        Machine::builder().load("./tests/missing"),
+        Err(LoadError::Io(_))
+    ));
+    assert!(matches!(
+        Machine::builder().load("./tests/add.dump"),
+        Err(LoadError::NotElf)
+    ));
+    assert!(matches!(
+        Machine::builder().mem_size(1).load("./tests/add"),
+        Err(LoadError::SegmentOutOfMemory { .. })
+    ));
+    assert!(matches!(
+        Machine::builder()
+            .stack(true)
+            .stack_top(0x80000010)
+            .load("./tests/add"),
+        Err(LoadError::StackOutOfMemory { top: 0x80000010 })
+    ));
+}
+
+#[test]
+fn lockstep() {
+    use crate::emulator::trace::{self, Format, Lockstep, Reader, Record};
+    use std::fmt::Write;
+
+    // a Spike log of tests/sw, after a boot ROM and with a trap reported
+    let mut log = String::from(
+        "core   0: 3 0x00001000 (0x00000297) x5  0x00001000\n\
+         core   0: exception trap_illegal_instruction, epc 0x00001004\n",
+    );
+    let mut machine =
+        Machine::builder().log_commits(true).load("./tests/sw").unwrap();
+    loop {
+        let step = machine.step();
+        if let Some(commit) = machine.commit() {
+            let record = Record::new(step.pc, &step.inst, commit.clone());
+            writeln!(log, "{}", record).unwrap();
+        }
+        if step.stop.is_some() {
+            break;
+        }
+    }
+    let reader = Reader::new(log.as_bytes()).unwrap();
+    assert_eq!(reader.format(), Format::Spike);
+    let mut machine = Machine::builder().load("./tests/sw").unwrap();
+    assert_eq!(
+        trace::lockstep(&mut machine, reader).unwrap(),
+        Lockstep::Stopped {
+            stop: StopReason::Exited(0),
+            matched: 525,
+        }
+    );
+
+    // the first store, with another value
+    let tampered = log.replacen(
+        "mem 0x80002000 0x00aa00aa",
+        "mem 0x80002000 0x00aa00ab",
+        1,
+    );
+    let reader = Reader::new(tampered.as_bytes()).unwrap();
+    let mut machine = Machine::builder().load("./tests/sw").unwrap();
+    match trace::lockstep(&mut machine, reader).unwrap() {
+        Lockstep::Diverged {
+            expected, actual, ..
+        } => {
+            assert_eq!(expected.pc, 0x80000184);
+            assert_eq!(actual.pc, 0x80000184);
+            assert_eq!(expected.commit.mem[0].value, 0x00aa00ab);
+            assert_eq!(actual.commit.mem[0].value, 0x00aa00aa);
+        }
+        other => panic!("expected a divergence, got {:?}", other),
+    }
+
+    // a reference that stops short
+    let short: String = log.lines().take(12).map(|l| l.to_owned() + "\n").collect();
+    let reader = Reader::new(short.as_bytes()).unwrap();
+    let mut machine = Machine::builder().load("./tests/sw").unwrap();
+    assert_eq!(
+        trace::lockstep(&mut machine, reader).unwrap(),
+        Lockstep::ReferenceEnded { matched: 10 }
+    );
+}
+
+#[test]
+fn lrsc() {
+    let mut machine = Machine::builder().load("./tests/lrsc").unwrap();
+    assert_eq!(machine.run(), StopReason::Exited(0));
 }
 
 #[test]
 fn lui() {
-    let mut cpu = Cpu::new(16);
-    let args = Args {
-        file: std::path::PathBuf::new(),
-        debug: false,
-        registers: false,
-        aliases: false,
-        interactive: false,
-        pc: None,
-        stack: false,
-        mem: None,
-    };
-    cpu.load("./tests/lui");
-    let ret = cpu.run(args);
-    assert_eq!(ret, 0);
+    let mut machine = Machine::builder().load("./tests/lui").unwrap();
+    assert_eq!(machine.run(), StopReason::Exited(0));
 }
 
 #[test]
 fn lw() {
-    let mut cpu = Cpu::new(16);
-    let args = Args {
-        file: std::path::PathBuf::new(),
-        debug: false,
-        registers: false,
-        aliases: false,
-        interactive: false,
-        pc: None,
-        stack: false,
-        mem: None,
-    };
-    cpu.load("./tests/lw");
-    let ret = cpu.run(args);
-    assert_eq!(ret, 0);
+    let mut machine = Machine::builder().load("./tests/lw").unwrap();
+    assert_eq!(machine.run(), StopReason::Exited(0));
+}
+
+#[test]
+fn ma_addr() {
+    let mut machine = Machine::builder().load("./tests/ma_addr").unwrap();
+    assert_eq!(machine.run(), StopReason::Exited(0));
+}
+
+#[test]
+fn max() {
+    let mut machine = Machine::builder().load("./tests/max").unwrap();
+    assert_eq!(machine.run(), StopReason::Exited(0));
+}
+
+#[test]
+fn maxu() {
+    let mut machine = Machine::builder().load("./tests/maxu").unwrap();
+    assert_eq!(machine.run(), StopReason::Exited(0));
+}
+
+#[test]
+fn min() {
+    let mut machine = Machine::builder().load("./tests/min").unwrap();
+    assert_eq!(machine.run(), StopReason::Exited(0));
+}
+
+#[test]
+fn minu() {
+    let mut machine = Machine::builder().load("./tests/minu").unwrap();
+    assert_eq!(machine.run(), StopReason::Exited(0));
+}
+
+#[test]
+fn move_d() {
+    let mut machine = Machine::builder().load("./tests/move_d").unwrap();
+    assert_eq!(machine.run(), StopReason::Exited(0));
+}
+
+#[test]
+fn move_s() {
+    let mut machine = Machine::builder().load("./tests/move_s").unwrap();
+    assert_eq!(machine.run(), StopReason::Exited(0));
+}
+
+#[test]
+fn mul() {
+    let mut machine = Machine::builder().load("./tests/mul").unwrap();
+    assert_eq!(machine.run(), StopReason::Exited(0));
+}
+
+#[test]
+fn mulh() {
+    let mut machine = Machine::builder().load("./tests/mulh").unwrap();
+    assert_eq!(machine.run(), StopReason::Exited(0));
+}
+
+#[test]
+fn mulhsu() {
+    let mut machine = Machine::builder().load("./tests/mulhsu").unwrap();
+    assert_eq!(machine.run(), StopReason::Exited(0));
+}
+
+#[test]
+fn mulhu() {
+    let mut machine = Machine::builder().load("./tests/mulhu").unwrap();
+    assert_eq!(machine.run(), StopReason::Exited(0));
 }
 
 #[test]
 fn or() {
-    let mut cpu = Cpu::new(16);
-    let args = Args {
-        file: std::path::PathBuf::new(),
-        debug: false,
-        registers: false,
-        aliases: false,
-        interactive: false,
-        pc: None,
-        stack: false,
-        mem: None,
-    };
-    cpu.load("./tests/or");
-    let ret = cpu.run(args);
-    assert_eq!(ret, 0);
+    let mut machine = Machine::builder().load("./tests/or").unwrap();
+    assert_eq!(machine.run(), StopReason::Exited(0));
+}
+
+#[test]
+fn orc_b() {
+    let mut machine = Machine::builder().load("./tests/orc_b").unwrap();
+    assert_eq!(machine.run(), StopReason::Exited(0));
 }
 
 #[test]
 fn ori() {
-    let mut cpu = Cpu::new(16);
-    let args = Args {
-        file: std::path::PathBuf::new(),
-        debug: false,
-        registers: false,
-        aliases: false,
-        interactive: false,
-        pc: None,
-        stack: false,
-        mem: None,
-    };
-    cpu.load("./tests/ori");
-    let ret = cpu.run(args);
-    assert_eq!(ret, 0);
+    let mut machine = Machine::builder().load("./tests/ori").unwrap();
+    assert_eq!(machine.run(), StopReason::Exited(0));
+}
+
+#[test]
+fn orn() {
+    let mut machine = Machine::builder().load("./tests/orn").unwrap();
+    assert_eq!(machine.run(), StopReason::Exited(0));
+}
+
+#[test]
+fn pmp() {
+    let mut machine = Machine::builder().load("./tests/pmp").unwrap();
+    assert_eq!(machine.run(), StopReason::Exited(0));
+}
+
+#[test]
+fn recoding_d() {
+    let mut machine = Machine::builder().load("./tests/recoding_d").unwrap();
+    assert_eq!(machine.run(), StopReason::Exited(0));
+}
+
+#[test]
+fn recoding_s() {
+    let mut machine = Machine::builder().load("./tests/recoding_s").unwrap();
+    assert_eq!(machine.run(), StopReason::Exited(0));
+}
+
+#[test]
+fn register_number() {
+    use crate::Cpu;
+    assert_eq!(Cpu::register_number("x0"), Some(0));
+    assert_eq!(Cpu::register_number("x31"), Some(31));
+    assert_eq!(Cpu::register_number("x32"), None);
+    assert_eq!(Cpu::register_number("sp"), Some(2));
+    assert_eq!(Cpu::register_number("a0"), Some(10));
+    assert_eq!(Cpu::register_number("fp"), Some(8));
+    assert_eq!(Cpu::register_number("s0"), Some(8));
+    assert_eq!(Cpu::register_number("t6"), Some(31));
+    assert_eq!(Cpu::register_number("pc"), None);
+}
+
+#[test]
+fn rem() {
+    let mut machine = Machine::builder().load("./tests/rem").unwrap();
+    assert_eq!(machine.run(), StopReason::Exited(0));
+}
+
+#[test]
+fn remu() {
+    let mut machine = Machine::builder().load("./tests/remu").unwrap();
+    assert_eq!(machine.run(), StopReason::Exited(0));
+}
+
+#[test]
+fn rev8() {
+    let mut machine = Machine::builder().load("./tests/rev8").unwrap();
+    assert_eq!(machine.run(), StopReason::Exited(0));
+}
+
+#[test]
+fn rol() {
+    let mut machine = Machine::builder().load("./tests/rol").unwrap();
+    assert_eq!(machine.run(), StopReason::Exited(0));
+}
+
+#[test]
+fn ror() {
+    let mut machine = Machine::builder().load("./tests/ror").unwrap();
+    assert_eq!(machine.run(), StopReason::Exited(0));
+}
+
+#[test]
+fn rori() {
+    let mut machine = Machine::builder().load("./tests/rori").unwrap();
+    assert_eq!(machine.run(), StopReason::Exited(0));
+}
+
+#[test]
+fn run_until() {
+    let mut machine = Machine::builder().load("./tests/add").unwrap();
+    // gp holds the number of the test case being run
+    assert_eq!(machine.run_until(|cpu| cpu.register(3) == 2), None);
+    assert_eq!(machine.cpu().register(3), 2);
+    assert_eq!(machine.run(), StopReason::Exited(0));
+}
+
+#[test]
+fn rv32si() {
+    for entry in std::fs::read_dir("./tests/rv32si").unwrap() {
+        let path = entry.unwrap().path();
+        if path.extension().is_some() {
+            continue;
+        }
+        // the page tables need more than the default 16 KiB
+        let mut machine =
+            Machine::builder().mem_size(64).load(&path).unwrap();
+        assert_eq!(machine.run(), StopReason::Exited(0), "{:?}", path);
+    }
+}
+
+#[test]
+fn rv64ui() {
+    for entry in std::fs::read_dir("./tests/rv64ui").unwrap() {
+        let path = entry.unwrap().path();
+        if path.extension().is_some() {
+            continue;
+        }
+        let mut machine = Machine::builder().load(&path).unwrap();
+        assert_eq!(machine.run(), StopReason::Exited(0), "{:?}", path);
+    }
+}
+
+#[test]
+fn rv64uzb() {
+    for entry in std::fs::read_dir("./tests/rv64uzb").unwrap() {
+        let path = entry.unwrap().path();
+        if path.extension().is_some() {
+            continue;
+        }
+        let mut machine = Machine::builder().load(&path).unwrap();
+        assert_eq!(machine.run(), StopReason::Exited(0), "{:?}", path);
+    }
+}
+
+#[test]
+fn rvc() {
+    let mut machine = Machine::builder().load("./tests/rvc").unwrap();
+    assert_eq!(machine.run(), StopReason::Exited(0));
 }
 
 #[test]
 fn sb() {
-    let mut cpu = Cpu::new(16);
-    let args = Args {
-        file: std::path::PathBuf::new(),
-        debug: false,
-        registers: false,
-        aliases: false,
-        interactive: false,
-        pc: None,
-        stack: false,
-        mem: None,
-    };
-    cpu.load("./tests/sb");
-    let ret = cpu.run(args);
-    assert_eq!(ret, 0);
+    let mut machine = Machine::builder().load("./tests/sb").unwrap();
+    assert_eq!(machine.run(), StopReason::Exited(0));
+}
+
+#[test]
+fn sbreak() {
+    let mut machine = Machine::builder().load("./tests/sbreak").unwrap();
+    assert_eq!(machine.run(), StopReason::Exited(0));
+}
+
+#[test]
+fn scall() {
+    let mut machine = Machine::builder().load("./tests/scall").unwrap();
+    assert_eq!(machine.run(), StopReason::Exited(0));
+}
+
+#[test]
+fn sext_b() {
+    let mut machine = Machine::builder().load("./tests/sext_b").unwrap();
+    assert_eq!(machine.run(), StopReason::Exited(0));
+}
+
+#[test]
+fn sext_h() {
+    let mut machine = Machine::builder().load("./tests/sext_h").unwrap();
+    assert_eq!(machine.run(), StopReason::Exited(0));
 }
 
 #[test]
 fn sh() {
-    let mut cpu = Cpu::new(16);
-    let args = Args {
-        file: std::path::PathBuf::new(),
-        debug: false,
-        registers: false,
-        aliases: false,
-        interactive: false,
-        pc: None,
-        stack: false,
-        mem: None,
-    };
-    cpu.load("./tests/sh");
-    let ret = cpu.run(args);
-    assert_eq!(ret, 0);
+    let mut machine = Machine::builder().load("./tests/sh").unwrap();
+    assert_eq!(machine.run(), StopReason::Exited(0));
+}
+
+#[test]
+fn sh1add() {
+    let mut machine = Machine::builder().load("./tests/sh1add").unwrap();
+    assert_eq!(machine.run(), StopReason::Exited(0));
+}
+
+#[test]
+fn sh2add() {
+    let mut machine = Machine::builder().load("./tests/sh2add").unwrap();
+    assert_eq!(machine.run(), StopReason::Exited(0));
+}
+
+#[test]
+fn sh3add() {
+    let mut machine = Machine::builder().load("./tests/sh3add").unwrap();
+    assert_eq!(machine.run(), StopReason::Exited(0));
 }
 
 #[test]
 fn simple() {
-    let mut cpu = Cpu::new(16);
-    let args = Args {
-        file: std::path::PathBuf::new(),
-        debug: false,
-        registers: false,
-        aliases: false,
-        interactive: false,
-        pc: None,
-        stack: false,
-        mem: None,
-    };
-    cpu.load("./tests/simple");
-    let ret = cpu.run(args);
-    assert_eq!(ret, 0);
+    let mut machine = Machine::builder().load("./tests/simple").unwrap();
+    assert_eq!(machine.run(), StopReason::Exited(0));
 }
 
 #[test]
 fn sll() {
-    let mut cpu = Cpu::new(16);
-    let args = Args {
-        file: std::path::PathBuf::new(),
-        debug: false,
-        registers: false,
-        aliases: false,
-        interactive: false,
-        pc: None,
-        stack: false,
-        mem: None,
-    };
-    cpu.load("./tests/sll");
-    let ret = cpu.run(args);
-    assert_eq!(ret, 0);
+    let mut machine = Machine::builder().load("./tests/sll").unwrap();
+    assert_eq!(machine.run(), StopReason::Exited(0));
 }
 
 #[test]
 fn slli() {
-    let mut cpu = Cpu::new(16);
-    let args = Args {
-        file: std::path::PathBuf::new(),
-        debug: false,
-        registers: false,
-        aliases: false,
-        interactive: false,
-        pc: None,
-        stack: false,
-        mem: None,
-    };
-    cpu.load("./tests/slli");
-    let ret = cpu.run(args);
-    assert_eq!(ret, 0);
+    let mut machine = Machine::builder().load("./tests/slli").unwrap();
+    assert_eq!(machine.run(), StopReason::Exited(0));
 }
 
 #[test]
 fn slt() {
-    let mut cpu = Cpu::new(16);
-    let args = Args {
-        file: std::path::PathBuf::new(),
-        debug: false,
-        registers: false,
-        aliases: false,
-        interactive: false,
-        pc: None,
-        stack: false,
-        mem: None,
-    };
-    cpu.load("./tests/slt");
-    let ret = cpu.run(args);
-    assert_eq!(ret, 0);
+    let mut machine = Machine::builder().load("./tests/slt").unwrap();
+    assert_eq!(machine.run(), StopReason::Exited(0));
 }
 
 #[test]
 fn slti() {
-    let mut cpu = Cpu::new(16);
-    let args = Args {
-        file: std::path::PathBuf::new(),
-        debug: false,
-        registers: false,
-        aliases: false,
-        interactive: false,
-        pc: None,
-        stack: false,
-        mem: None,
-    };
-    cpu.load("./tests/slti");
-    let ret = cpu.run(args);
-    assert_eq!(ret, 0);
+    let mut machine = Machine::builder().load("./tests/slti").unwrap();
+    assert_eq!(machine.run(), StopReason::Exited(0));
 }
 
 #[test]
 fn sltiu() {
-    let mut cpu = Cpu::new(16);
-    let args = Args {
-        file: std::path::PathBuf::new(),
-        debug: false,
-        registers: false,
-        aliases: false,
-        interactive: false,
-        pc: None,
-        stack: false,
-        mem: None,
-    };
-    cpu.load("./tests/sltiu");
-    let ret = cpu.run(args);
-    assert_eq!(ret, 0);
+    let mut machine = Machine::builder().load("./tests/sltiu").unwrap();
+    assert_eq!(machine.run(), StopReason::Exited(0));
 }
 
 #[test]
 fn sltu() {
-    let mut cpu = Cpu::new(16);
-    let args = Args {
-        file: std::path::PathBuf::new(),
-        debug: false,
-        registers: false,
-        aliases: false,
-        interactive: false,
-        pc: None,
-        stack: false,
-        mem: None,
+    let mut machine = Machine::builder().load("./tests/sltu").unwrap();
+    assert_eq!(machine.run(), StopReason::Exited(0));
+}
+
+#[test]
+fn spike_trace() {
+    use crate::emulator::trace;
+
+    let mut machine =
+        Machine::builder().log_commits(true).load("./tests/sw").unwrap();
+    let mut lines = Vec::new();
+    loop {
+        let step = machine.step();
+        if let Some(commit) = machine.commit() {
+            lines.push(trace::spike(&step, commit));
+        }
+        if let Some(stop) = step.stop {
+            assert_eq!(stop, StopReason::Exited(0));
+            break;
+        }
+    }
+    assert_eq!(lines[0], "core   0: 3 0x80000000 (0x0480006f)");
+    assert_eq!(
+        lines[1],
+        "core   0: 3 0x80000048 (0x00000093) x1  0x00000000"
+    );
+    let line = |pc: &str| {
+        lines.iter().find(|line| line.contains(pc)).unwrap().as_str()
     };
-    cpu.load("./tests/sltu");
-    let ret = cpu.run(args);
-    assert_eq!(ret, 0);
+    assert_eq!(
+        line("0x80000184"),
+        "core   0: 0 0x80000184 (0x0020a023) mem 0x80002000 0x00aa00aa"
+    );
+    assert_eq!(
+        line("0x80000188"),
+        "core   0: 0 0x80000188 (0x0000a703) x14 0x00aa00aa mem 0x80002000"
+    );
+
+    let mut machine = Machine::builder()
+        .log_commits(true)
+        .load("./tests/csr")
+        .unwrap();
+    let step = loop {
+        let step = machine.step();
+        if step.pc == 0x800000d8 {
+            break step;
+        }
+    };
+    assert_eq!(
+        trace::spike(&step, machine.commit().unwrap()),
+        "core   0: 3 0x800000d8 (0x30529073) c773_mtvec 0x800000e0"
+    );
+
+    // nothing is recorded unless asked for
+    let mut machine = Machine::builder().load("./tests/sw").unwrap();
+    machine.step();
+    assert!(machine.commit().is_none());
 }
 
 #[test]
 fn sra() {
-    let mut cpu = Cpu::new(16);
-    let args = Args {
-        file: std::path::PathBuf::new(),
-        debug: false,
-        registers: false,
-        aliases: false,
-        interactive: false,
-        pc: None,
-        stack: false,
-        mem: None,
-    };
-    cpu.load("./tests/sra");
-    let ret = cpu.run(args);
-    assert_eq!(ret, 0);
+    let mut machine = Machine::builder().load("./tests/sra").unwrap();
+    assert_eq!(machine.run(), StopReason::Exited(0));
 }
 
 #[test]
 fn srai() {
-    let mut cpu = Cpu::new(16);
-    let args = Args {
-        file: std::path::PathBuf::new(),
-        debug: false,
-        registers: false,
-        aliases: false,
-        interactive: false,
-        pc: None,
-        stack: false,
-        mem: None,
-    };
-    cpu.load("./tests/srai");
-    let ret = cpu.run(args);
-    assert_eq!(ret, 0);
+    let mut machine = Machine::builder().load("./tests/srai").unwrap();
+    assert_eq!(machine.run(), StopReason::Exited(0));
 }
 
 #[test]
 fn srl() {
-    let mut cpu = Cpu::new(16);
-    let args = Args {
-        file: std::path::PathBuf::new(),
-        debug: false,
-        registers: false,
-        aliases: false,
-        interactive: false,
-        pc: None,
-        stack: false,
-        mem: None,
-    };
-    cpu.load("./tests/srl");
-    let ret = cpu.run(args);
-    assert_eq!(ret, 0);
+    let mut machine = Machine::builder().load("./tests/srl").unwrap();
+    assert_eq!(machine.run(), StopReason::Exited(0));
 }
 
 #[test]
 fn srli() {
-    let mut cpu = Cpu::new(16);
-    let args = Args {
-        file: std::path::PathBuf::new(),
-        debug: false,
-        registers: false,
-        aliases: false,
-        interactive: false,
-        pc: None,
-        stack: false,
-        mem: None,
+    let mut machine = Machine::builder().load("./tests/srli").unwrap();
+    assert_eq!(machine.run(), StopReason::Exited(0));
+}
+
+#[test]
+fn step_limit() {
+    let mut machine =
+        Machine::builder().step_limit(10).load("./tests/add").unwrap();
+    assert_eq!(machine.run(), StopReason::StepLimit);
+}
+
+#[test]
+fn stop_reason() {
+    // mtvec isn't set up yet at the entry point, so nothing is handled
+    let mut machine = Machine::builder().load("./tests/add").unwrap();
+    let pc = machine.cpu().pc();
+    let mut run = |inst: u32| {
+        machine.cpu_mut().write_memory(pc, &inst.to_le_bytes());
+        machine.cpu_mut().set_pc(pc);
+        machine.run()
     };
-    cpu.load("./tests/srli");
-    let ret = cpu.run(args);
-    assert_eq!(ret, 0);
+    assert_eq!(
+        run(0xc0001073),
+        StopReason::IllegalInstruction { pc, raw: 0xc0001073 }
+    );
+    assert_eq!(run(0x00100073), StopReason::Breakpoint { pc });
+    assert_eq!(
+        run(0x00002083),
+        StopReason::MemoryFault {
+            addr: 0,
+            kind: FaultKind::Load,
+        }
+    );
+    assert_eq!(run(0x00000073), StopReason::UnknownSyscall(0));
 }
 
 #[test]
 fn sub() {
-    let mut cpu = Cpu::new(16);
-    let args = Args {
-        file: std::path::PathBuf::new(),
-        debug: false,
-        registers: false,
-        aliases: false,
-        interactive: false,
-        pc: None,
-        stack: false,
-        mem: None,
-    };
-    cpu.load("./tests/sub");
-    let ret = cpu.run(args);
-    assert_eq!(ret, 0);
+    let mut machine = Machine::builder().load("./tests/sub").unwrap();
+    assert_eq!(machine.run(), StopReason::Exited(0));
 }
 
 #[test]
 fn sw() {
-    let mut cpu = Cpu::new(16);
-    let args = Args {
-        file: std::path::PathBuf::new(),
-        debug: false,
-        registers: false,
-        aliases: false,
-        interactive: false,
-        pc: None,
-        stack: false,
-        mem: None,
+    let mut machine = Machine::builder().load("./tests/sw").unwrap();
+    assert_eq!(machine.run(), StopReason::Exited(0));
+}
+
+#[test]
+fn symbols() {
+    let mut machine =
+        Machine::builder().stack(true).load("./benches/fib").unwrap();
+    let symbols = machine.cpu().symbols();
+    let fib = symbols.find("fib").unwrap();
+    assert_eq!(fib, 0x80000022);
+    assert_eq!(symbols.at(fib).unwrap().name, "fib");
+    assert!(symbols.at(fib + 2).is_none());
+    let (sym, offset) = symbols.lookup(fib + 8).unwrap();
+    assert_eq!((sym.name.as_str(), offset), ("fib", 8));
+    assert!(symbols.lookup(0x7ffffffc).is_none());
+
+    // run into the innermost call, fib(1), then finish it the way the
+    // interactive `finish` does
+    machine.add_breakpoint(fib);
+    for _ in 0..30 {
+        assert_eq!(machine.run(), StopReason::Breakpoint { pc: fib });
+    }
+    assert_eq!(machine.cpu().register(10), 1);
+    let (ra, sp) = (machine.cpu().register(1), machine.cpu().register(2));
+    machine.remove_breakpoint(fib);
+    machine.step();
+    let stop =
+        machine.run_until(|cpu| cpu.pc() == ra && cpu.register(2) >= sp);
+    assert_eq!(stop, None);
+    assert_eq!(machine.cpu().register(2), sp);
+}
+
+#[test]
+fn syscalls() {
+    // the heap and mmap need more than the default 16 KiB
+    let mut machine = Machine::builder()
+        .mem_size(64)
+        .load("./tests/syscalls")
+        .unwrap();
+    assert_eq!(machine.run(), StopReason::Exited(0));
+}
+
+#[test]
+fn trace_file() {
+    use crate::emulator::trace::{self, Format, Reader, Record, Writer};
+
+    // what the trace should hold, straight from the machine
+    let mut machine =
+        Machine::builder().log_commits(true).load("./tests/sw").unwrap();
+    let mut expected = Vec::new();
+    loop {
+        let step = machine.step();
+        if let Some(commit) = machine.commit() {
+            expected.push(Record::new(step.pc, &step.inst, commit.clone()));
+        }
+        if step.stop.is_some() {
+            break;
+        }
+    }
+    let sw = expected.iter().find(|r| r.pc == 0x80000184).unwrap();
+    assert_eq!(sw.mnemonic, "sw");
+    assert_eq!(sw.commit.mem[0].value, 0x00aa00aa);
+
+    for format in [Format::Json, Format::Binary] {
+        let path = std::env::temp_dir()
+            .join(format!("rvemu-trace-{:?}-{}", format, std::process::id()));
+        let trace = Writer::create(&path, format).unwrap();
+        let mut machine =
+            Machine::builder().trace(trace).load("./tests/sw").unwrap();
+        assert_eq!(machine.run(), StopReason::Exited(0));
+        machine.finish_trace().unwrap();
+
+        let reader = trace::open(&path).unwrap();
+        assert_eq!(reader.format(), format);
+        let records: Vec<Record> =
+            reader.collect::<Result<_, _>>().unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(records, expected);
+    }
+
+    // binary traces in any other version are rejected
+    assert!(Reader::new(&b"rvtrace\x01"[..]).is_err());
+}
+
+#[test]
+fn watchpoint() {
+    let mut machine = Machine::builder().load("./tests/sw").unwrap();
+    let write = Watchpoint {
+        addr: 0x80002000,
+        len: 4,
+        kind: WatchKind::Write,
     };
-    cpu.load("./tests/sw");
-    let ret = cpu.run(args);
-    assert_eq!(ret, 0);
+    machine.add_watchpoint(write);
+    assert_eq!(
+        machine.run(),
+        StopReason::Watchpoint {
+            addr: 0x80002000,
+            kind: WatchKind::Write,
+            pc: 0x80000184,
+            raw: 0x0020a023,
+            old: 0xdeadbeef,
+            new: 0x00aa00aa,
+        }
+    );
+    // only an access watchpoint sees the load that checks the value
+    assert!(machine.remove_watchpoint(write));
+    machine.add_watchpoint(Watchpoint {
+        kind: WatchKind::Access,
+        ..write
+    });
+    assert_eq!(
+        machine.run(),
+        StopReason::Watchpoint {
+            addr: 0x80002000,
+            kind: WatchKind::Access,
+            pc: 0x80000188,
+            raw: 0x0000a703,
+            old: 0x00aa00aa,
+            new: 0x00aa00aa,
+        }
+    );
+}
+
+#[test]
+fn xnor() {
+    let mut machine = Machine::builder().load("./tests/xnor").unwrap();
+    assert_eq!(machine.run(), StopReason::Exited(0));
 }
 
 #[test]
 fn xor() {
-    let mut cpu = Cpu::new(16);
-    let args = Args {
-        file: std::path::PathBuf::new(),
-        debug: false,
-        registers: false,
-        aliases: false,
-        interactive: false,
-        pc: None,
-        stack: false,
-        mem: None,
-    };
-    cpu.load("./tests/xor");
-    let ret = cpu.run(args);
-    assert_eq!(ret, 0);
+    let mut machine = Machine::builder().load("./tests/xor").unwrap();
+    assert_eq!(machine.run(), StopReason::Exited(0));
 }
 
 #[test]
 fn xori() {
-    let mut cpu = Cpu::new(16);
-    let args = Args {
-        file: std::path::PathBuf::new(),
-        debug: false,
-        registers: false,
-        aliases: false,
-        interactive: false,
-        pc: None,
-        stack: false,
-        mem: None,
-    };
-    cpu.load("./tests/xori");
-    let ret = cpu.run(args);
-    assert_eq!(ret, 0);
+    let mut machine = Machine::builder().load("./tests/xori").unwrap();
+    assert_eq!(machine.run(), StopReason::Exited(0));
+}
+
+#[test]
+fn zext_h() {
+    let mut machine = Machine::builder().load("./tests/zext_h").unwrap();
+    assert_eq!(machine.run(), StopReason::Exited(0));
 }
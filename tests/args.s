@@ -0,0 +1,105 @@
+# Checks the initial stack the emulator sets up like Linux does: argc,
+# argv, envp and the auxiliary vector. Run it with the arguments "one"
+# and "-two" and the environment "A=1". Each check puts its number in
+# gp, and a failed one exits with it. Built with
+#
+#   riscv64-unknown-elf-gcc -march=rv32i_zicsr -mabi=ilp32 -nostdlib \
+#       -Wl,--no-relax -T tests/link.ld -o tests/args tests/args.s
+
+        .section .text.init,"ax",@progbits
+        .globl _start
+_start:
+        # sp is 16-byte aligned and points at argc = 3
+        li gp, 2
+        andi t0, sp, 15
+        bnez t0, fail
+        lw s0, 0(sp)
+        li t0, 3
+        bne s0, t0, fail
+        # argv[1] = "one", argv[2] = "-two", argv[3] = NULL
+        li gp, 3
+        lw a0, 8(sp)
+        la a1, one
+        call strcmp
+        bnez a0, fail
+        li gp, 4
+        lw a0, 12(sp)
+        la a1, two
+        call strcmp
+        bnez a0, fail
+        lw t0, 16(sp)
+        bnez t0, fail
+        # envp[0] = "A=1", envp[1] = NULL
+        li gp, 5
+        lw a0, 20(sp)
+        la a1, env
+        call strcmp
+        bnez a0, fail
+        lw t0, 24(sp)
+        bnez t0, fail
+        # scan the auxiliary vector
+        addi s1, sp, 28
+        li s2, 0
+1:      lw t0, 0(s1)
+        lw t1, 4(s1)
+        addi s1, s1, 8
+        beqz t0, 2f
+        li t2, 6
+        bne t0, t2, 3f
+        # AT_PAGESZ
+        li gp, 6
+        li t2, 4096
+        bne t1, t2, fail
+        ori s2, s2, 1
+3:      li t2, 9
+        bne t0, t2, 3f
+        # AT_ENTRY
+        li gp, 7
+        la t2, _start
+        bne t1, t2, fail
+        ori s2, s2, 2
+3:      li t2, 3
+        bne t0, t2, 3f
+        # AT_PHDR: the first program header is the PT_LOAD of _start
+        li gp, 8
+        lw t2, 0(t1)
+        li t3, 1
+        bne t2, t3, fail
+        lw t2, 8(t1)
+        la t3, _start
+        bne t2, t3, fail
+        ori s2, s2, 4
+3:      li t2, 25
+        bne t0, t2, 1b
+        # AT_RANDOM points at 16 bytes above the strings
+        li gp, 9
+        lw t2, 4(sp)
+        bleu t1, t2, fail
+        ori s2, s2, 8
+        j 1b
+2:      li gp, 10
+        li t0, 15
+        bne s2, t0, fail
+
+        li a0, 0
+        li a7, 93
+        ecall
+fail:
+        mv a0, gp
+        li a7, 93
+        ecall
+
+strcmp:
+        lbu t0, 0(a0)
+        lbu t1, 0(a1)
+        bne t0, t1, 1f
+        addi a0, a0, 1
+        addi a1, a1, 1
+        bnez t0, strcmp
+1:      sub a0, t0, t1
+        ret
+
+        .data
+one:    .asciz "one"
+two:    .asciz "-two"
+env:    .asciz "A=1"
@@ -0,0 +1,633 @@
+# Checks physical memory protection: OFF, TOR, NA4 and NAPOT matching,
+# partial matches, MPRV and lock bits. Entry 15 allows everything, so
+# that lower entries can carve regions out of `tdat`. The accesses PMP
+# checks are made from user mode; the handler at `mhandler` records
+# mcause, mepc, mtval and mstatus in s1, s2, s3 and s7 and returns to
+# machine mode at s4. Each test_<n> fails with exit code n. Built with
+#
+#   riscv64-unknown-elf-gcc -march=rv32imafdc_zicsr -mabi=ilp32 -nostdlib \
+#       -Wl,--no-relax -T tests/link.ld -o tests/pmp tests/pmp.s
+
+
+        .section .text.init,"ax",@progbits
+        .align 6
+        .globl _start
+_start:
+        j reset_vector
+        .align 2
+trap_vector:
+        csrr t5, mcause
+        li t6, 8
+        beq t5, t6, write_tohost
+        li t6, 9
+        beq t5, t6, write_tohost
+        li t6, 11
+        beq t5, t6, write_tohost
+        li t5, 0
+        beqz t5, 1f
+        jr t5
+1:      csrr t5, mcause
+        bgez t5, handle_exception
+        j other_exception
+handle_exception:
+other_exception:
+1:      ori gp, gp, 1337
+write_tohost:
+        sw gp, tohost, t5
+        j write_tohost
+reset_vector:
+        li x1, 0
+        li x2, 0
+        li x3, 0
+        li x4, 0
+        li x5, 0
+        li x6, 0
+        li x7, 0
+        li x8, 0
+        li x9, 0
+        li x10, 0
+        li x11, 0
+        li x12, 0
+        li x13, 0
+        li x14, 0
+        li x15, 0
+        li x16, 0
+        li x17, 0
+        li x18, 0
+        li x19, 0
+        li x20, 0
+        li x21, 0
+        li x22, 0
+        li x23, 0
+        li x24, 0
+        li x25, 0
+        li x26, 0
+        li x27, 0
+        li x28, 0
+        li x29, 0
+        li x30, 0
+        li x31, 0
+
+        csrr a0, mhartid
+1:      bnez a0, 1b
+        la t0, 1f
+        csrw mtvec, t0
+        csrwi satp, 0
+        .align 2
+1:      la t0, 1f
+        csrw mtvec, t0
+        li t0, 0x7fffffff
+        csrw pmpaddr0, t0
+        li t0, 31
+        csrw pmpcfg0, t0
+        .align 2
+1:      csrwi mie, 0
+        la t0, 1f
+        csrw mtvec, t0
+        csrwi medeleg, 0
+        csrwi mideleg, 0
+        .align 2
+1:      li gp, 0
+        la t0, trap_vector
+        csrw mtvec, t0
+        li a0, 1
+        slli a0, a0, 31
+        bltz a0, 1f
+        fence
+        li gp, 1
+        li a7, 93
+        li a0, 0
+        ecall
+1:      li t0, 0
+        beqz t0, 1f
+        csrw stvec, t0
+        li t0, 45321
+        csrw medeleg, t0
+1:      csrwi mstatus, 0
+        la t0, mhandler
+        csrw mtvec, t0
+        li t0, 0x3800
+        csrs mstatus, t0
+        li t0, -1
+        csrw pmpaddr15, t0
+        li t0, 0x1f000000
+        csrw pmpcfg3, t0
+        csrw pmpcfg0, x0
+        la t0, test_2
+        csrw mepc, t0
+        csrr a0, mhartid
+        mret
+
+        # entry 1: tdat, NA4, read-only
+test_2:
+        la a1, tdat
+        srli t0, a1, 2
+        csrw pmpaddr1, t0
+        li t0, 0x1100
+        csrs pmpcfg0, t0
+        la s4, 9f
+        li s5, 0x1801
+        la t0, 7f
+        csrw mepc, t0
+        li t0, 0x1800
+        csrc mstatus, t0
+        mret
+        7:
+        lw a2, 0(a1)
+        li a7, 0
+        ecall
+        9:
+        mv a0, s1
+        li x7, 0x8
+        li gp, 2
+        bne a0, x7, fail
+
+test_3:
+        la s4, 9f
+        li s5, 0x1801
+        la t0, 7f
+        csrw mepc, t0
+        li t0, 0x1800
+        csrc mstatus, t0
+        mret
+        7:
+        la a1, tdat
+        sw a1, 0(a1)
+        li a7, 0
+        ecall
+        9:
+        mv a0, s1
+        li x7, 0x7
+        li gp, 3
+        bne a0, x7, fail
+
+test_4:
+        la s4, 9f
+        li s5, 0x1801
+        la t0, 7f
+        csrw mepc, t0
+        li t0, 0x1800
+        csrc mstatus, t0
+        mret
+        7:
+        la a1, tdat
+        sw a1, 0(a1)
+        li a7, 0
+        ecall
+        9:
+        sub a0, s3, a1
+        li x7, 0x0
+        li gp, 4
+        bne a0, x7, fail
+
+test_5:
+        la s4, 9f
+        li s5, 0x1801
+        la t0, 7f
+        csrw mepc, t0
+        li t0, 0x1800
+        csrc mstatus, t0
+        mret
+        7:
+        la a1, tdat
+        sw a1, 4(a1)
+        lw a2, 4(a1)
+        li a7, 0
+        ecall
+        9:
+        mv a0, s1
+        li x7, 0x8
+        li gp, 5
+        bne a0, x7, fail
+
+        # machine mode isn't checked while nothing is locked
+test_6:
+        la a1, tdat
+        li a2, 0x55
+        sw a2, 0(a1)
+        lw a0, 0(a1)
+        li x7, 0x55
+        li gp, 6
+        bne a0, x7, fail
+
+        # entry 2: tdat+16, 16 bytes of NAPOT with no permissions
+test_7:
+        la a1, tdat
+        addi t0, a1, 16
+        srli t0, t0, 2
+        ori t0, t0, 1
+        csrw pmpaddr2, t0
+        li t0, 0x180000
+        csrs pmpcfg0, t0
+        la s4, 9f
+        li s5, 0x1801
+        la t0, 7f
+        csrw mepc, t0
+        li t0, 0x1800
+        csrc mstatus, t0
+        mret
+        7:
+        la a1, tdat
+        lw a2, 28(a1)
+        li a7, 0
+        ecall
+        9:
+        mv a0, s1
+        li x7, 0x5
+        li gp, 7
+        bne a0, x7, fail
+
+test_8:
+        la s4, 9f
+        li s5, 0x1801
+        la t0, 7f
+        csrw mepc, t0
+        li t0, 0x1800
+        csrc mstatus, t0
+        mret
+        7:
+        la a1, tdat
+        lw a2, 32(a1)
+        li a7, 0
+        ecall
+        9:
+        mv a0, s1
+        li x7, 0x8
+        li gp, 8
+        bne a0, x7, fail
+
+test_9:
+        la s4, 9f
+        li s5, 0x1801
+        la t0, 7f
+        csrw mepc, t0
+        li t0, 0x1800
+        csrc mstatus, t0
+        mret
+        7:
+        la a1, tdat
+        addi a1, a1, 16
+        jalr a1
+        li a7, 0
+        ecall
+        9:
+        mv a0, s1
+        li x7, 0x1
+        li gp, 9
+        bne a0, x7, fail
+
+test_10:
+        la s4, 9f
+        li s5, 0x1801
+        la t0, 7f
+        csrw mepc, t0
+        li t0, 0x1800
+        csrc mstatus, t0
+        mret
+        7:
+        la a1, tdat
+        addi a1, a1, 16
+        jalr a1
+        li a7, 0
+        ecall
+        9:
+        la a1, tdat
+        sub a0, s3, a1
+        li x7, 0x10
+        li gp, 10
+        bne a0, x7, fail
+
+        # entry 3: tdat+36, NA4, read-only; an access it only partly matches fails
+test_11:
+        la a1, tdat
+        addi t0, a1, 36
+        srli t0, t0, 2
+        csrw pmpaddr3, t0
+        li t0, 0x11000000
+        csrs pmpcfg0, t0
+        la s4, 9f
+        li s5, 0x1801
+        la t0, 7f
+        csrw mepc, t0
+        li t0, 0x1800
+        csrc mstatus, t0
+        mret
+        7:
+        la a1, tdat
+        fld fa0, 32(a1)
+        li a7, 0
+        ecall
+        9:
+        mv a0, s1
+        li x7, 0x5
+        li gp, 11
+        bne a0, x7, fail
+
+test_12:
+        la s4, 9f
+        li s5, 0x1801
+        la t0, 7f
+        csrw mepc, t0
+        li t0, 0x1800
+        csrc mstatus, t0
+        mret
+        7:
+        la a1, tdat
+        lw a2, 36(a1)
+        li a7, 0
+        ecall
+        9:
+        mv a0, s1
+        li x7, 0x8
+        li gp, 12
+        bne a0, x7, fail
+
+        # entry 5: TOR from entry 4's address, tdat+48, up to tdat+56
+test_13:
+        la a1, tdat
+        addi t0, a1, 48
+        srli t0, t0, 2
+        csrw pmpaddr4, t0
+        addi t0, a1, 56
+        srli t0, t0, 2
+        csrw pmpaddr5, t0
+        li t0, 0x800
+        csrs pmpcfg1, t0
+        la s4, 9f
+        li s5, 0x1801
+        la t0, 7f
+        csrw mepc, t0
+        li t0, 0x1800
+        csrc mstatus, t0
+        mret
+        7:
+        la a1, tdat
+        lw a2, 52(a1)
+        li a7, 0
+        ecall
+        9:
+        mv a0, s1
+        li x7, 0x5
+        li gp, 13
+        bne a0, x7, fail
+
+test_14:
+        la s4, 9f
+        li s5, 0x1801
+        la t0, 7f
+        csrw mepc, t0
+        li t0, 0x1800
+        csrc mstatus, t0
+        mret
+        7:
+        la a1, tdat
+        lw a2, 48(a1)
+        li a7, 0
+        ecall
+        9:
+        mv a0, s1
+        li x7, 0x5
+        li gp, 14
+        bne a0, x7, fail
+
+test_15:
+        la s4, 9f
+        li s5, 0x1801
+        la t0, 7f
+        csrw mepc, t0
+        li t0, 0x1800
+        csrc mstatus, t0
+        mret
+        7:
+        la a1, tdat
+        lw a2, 44(a1)
+        lw a2, 56(a1)
+        li a7, 0
+        ecall
+        9:
+        mv a0, s1
+        li x7, 0x8
+        li gp, 15
+        bne a0, x7, fail
+
+        # with entry 15 off no entry matches: user mode can't even fetch, not
+        # even what machine mode just ran
+test_16:
+        li t0, 0x1f000000
+        csrc pmpcfg3, t0
+        li a1, 0
+        7:  bnez a1, 8f
+        li a1, 1
+        la s4, 9f
+        li s5, 0x1801
+        la t0, 7b
+        csrw mepc, t0
+        li t0, 0x1800
+        csrc mstatus, t0
+        mret
+        8:  li a7, 0
+        ecall
+        9:  li t0, 0x1f000000
+        csrs pmpcfg3, t0
+        la t0, 7b
+        sub a0, s3, t0
+        add a0, a0, s1
+        li x7, 0x1
+        li gp, 16
+        bne a0, x7, fail
+
+test_17:
+        la a1, tdat
+        lw a0, 0(a1)
+        li x7, 0x55
+        li gp, 17
+        bne a0, x7, fail
+
+        # W without R is reserved, and so are bits 5 and 6
+test_18:
+        li t0, 0x660000
+        csrs pmpcfg1, t0
+        csrr a0, pmpcfg1
+        li t0, 0xff0000
+        and a0, a0, t0
+        li x7, 0x40000
+        li gp, 18
+        bne a0, x7, fail
+
+        # MPRV makes machine-mode loads and stores use MPP's checks
+test_19:
+        li t0, 0x20000
+        csrs mstatus, t0
+        la s4, 9f
+        la a1, tdat
+        lw a2, 16(a1)
+        9:
+        mv a0, s1
+        li t0, 0x20000
+        csrc mstatus, t0
+        li x7, 0x5
+        li gp, 19
+        bne a0, x7, fail
+
+test_20:
+        li t0, 0x20000
+        csrs mstatus, t0
+        la s4, 9f
+        la a1, tdat
+        sw a2, 0(a1)
+        9:
+        mv a0, s1
+        li t0, 0x20000
+        csrc mstatus, t0
+        li x7, 0x7
+        li gp, 20
+        bne a0, x7, fail
+
+        # a locked entry applies to machine mode too, and can't be changed
+test_21:
+        li t0, 0x8000
+        csrs pmpcfg0, t0
+        la s4, 9f
+        la a1, tdat
+        sw a2, 0(a1)
+        9:
+        mv a0, s1
+        li x7, 0x7
+        li gp, 21
+        bne a0, x7, fail
+
+test_22:
+        la a1, tdat
+        lw a0, 0(a1)
+        li x7, 0x55
+        li gp, 22
+        bne a0, x7, fail
+
+test_23:
+        li t0, 0xff00
+        csrc pmpcfg0, t0
+        csrr a0, pmpcfg0
+        srli a0, a0, 8
+        andi a0, a0, 0xff
+        li x7, 0x91
+        li gp, 23
+        bne a0, x7, fail
+
+test_24:
+        csrr a2, pmpaddr1
+        csrw pmpaddr1, x0
+        csrr a0, pmpaddr1
+        sub a0, a0, a2
+        li x7, 0x0
+        li gp, 24
+        bne a0, x7, fail
+
+        # locking a TOR entry locks the address below it as well
+test_25:
+        li t0, 0x8000
+        csrs pmpcfg1, t0
+        la s4, 9f
+        la a1, tdat
+        lw a2, 48(a1)
+        9:
+        mv a0, s1
+        li x7, 0x5
+        li gp, 25
+        bne a0, x7, fail
+
+test_26:
+        csrr a2, pmpaddr4
+        csrw pmpaddr4, x0
+        csrr a0, pmpaddr4
+        sub a0, a0, a2
+        li x7, 0x0
+        li gp, 26
+        bne a0, x7, fail
+
+test_27:
+        csrr a2, pmpaddr6
+        addi a2, a2, 1
+        csrw pmpaddr6, a2
+        csrr a0, pmpaddr6
+        sub a0, a0, a2
+        la s4, 9f
+        li s5, 0x1801
+        la t0, 7f
+        csrw mepc, t0
+        li t0, 0x1800
+        csrc mstatus, t0
+        mret
+        7:
+        la a1, tdat
+        lw a2, 60(a1)
+        li a7, 0
+        ecall
+        9:
+        add a0, a0, s1
+        li x7, 0x8
+        li gp, 27
+        bne a0, x7, fail
+
+
+        bne x0, gp, pass
+fail:
+        fence
+1:      beqz gp, 1b
+        sll gp, gp, 1
+        or gp, gp, 1
+        li a7, 93
+        addi a0, gp, 0
+        ecall
+pass:
+        fence
+        li gp, 1
+        li a7, 93
+        li a0, 0
+        ecall
+        unimp
+
+        .pushsection .tohost,"aw",@progbits
+        .align 6
+        .globl tohost
+tohost: .dword 0
+        .align 6
+        .globl fromhost
+fromhost: .dword 0
+        .popsection
+
+        .data
+        .align 4
+        .globl begin_signature
+begin_signature:
+        .align 6
+tdat:   .fill 16, 4, 0
+        .align 4
+        .globl end_signature
+end_signature:
+
+        .section .text.init,"ax",@progbits
+        .align 2
+mhandler:
+        csrr s1, mcause
+        csrr s2, mepc
+        csrr s3, mtval
+        csrr s7, mstatus
+        li t6, 93
+        bne a7, t6, 2f
+        li t6, 8
+        beq s1, t6, write_tohost
+        li t6, 9
+        beq s1, t6, write_tohost
+        li t6, 11
+        beq s1, t6, write_tohost
+2:      beqz s4, fail
+        csrw mepc, s4
+        li s4, 0
+        beqz s5, 3f
+        li t6, 0x1800
+        csrc mstatus, t6
+        and t5, s5, t6
+        csrs mstatus, t5
+        li s5, 0
+3:      mret
+
@@ -0,0 +1,246 @@
+# Exercises the Linux system calls the emulator provides to programs that
+# don't handle `ecall` themselves. Each check puts its number in gp, and
+# a failed one exits with it. Needs more than the default 16 KiB of
+# memory. Built with
+#
+#   riscv64-unknown-elf-gcc -march=rv32i_zicsr -mabi=ilp32 -nostdlib \
+#       -Wl,--no-relax -T tests/link.ld -o tests/syscalls tests/syscalls.s
+
+        .section .text.init,"ax",@progbits
+        .globl _start
+_start:
+        # write(1, msg, len)
+        li gp, 2
+        li a0, 1
+        la a1, msg
+        li a2, 14
+        li a7, 64
+        ecall
+        li t0, 14
+        bne a0, t0, fail
+
+        # writev to stdout with two buffers
+        li gp, 3
+        li a0, 1
+        la a1, iov
+        li a2, 2
+        li a7, 66
+        ecall
+        li t0, 9
+        bne a0, t0, fail
+
+        # brk(0) returns the break, which can then grow
+        li gp, 4
+        li a0, 0
+        li a7, 214
+        ecall
+        mv s0, a0
+        beqz s0, fail
+        slli t0, s0, 20
+        bnez t0, fail
+        addi a0, s0, 256
+        li a7, 214
+        ecall
+        addi t0, s0, 256
+        bne a0, t0, fail
+        li t1, 0x1234
+        sw t1, 252(s0)
+        lw t2, 252(s0)
+        bne t1, t2, fail
+        # growing it into the stack fails and leaves it alone
+        li a0, -4096
+        li a7, 214
+        ecall
+        addi t0, s0, 256
+        bne a0, t0, fail
+
+        # anonymous mmap hands out zeroed pages above the heap
+        li gp, 5
+        li a0, 0
+        li a1, 100
+        li a2, 3
+        li a3, 0x22
+        li a4, -1
+        li a5, 0
+        li a7, 222
+        ecall
+        mv s1, a0
+        slli t0, s1, 20
+        bnez t0, fail
+        bleu s1, s0, fail
+        lw t0, 96(s1)
+        bnez t0, fail
+        sw t1, 96(s1)
+        # munmap
+        mv a0, s1
+        li a1, 100
+        li a7, 215
+        ecall
+        bnez a0, fail
+        # file mappings aren't supported
+        li gp, 6
+        li a0, 0
+        li a1, 100
+        li a2, 1
+        li a3, 2
+        li a4, 0
+        li a7, 222
+        ecall
+        li t0, -19
+        bne a0, t0, fail
+
+        # openat(AT_FDCWD, "./tests/syscalls", O_RDONLY)
+        li gp, 7
+        li a0, -100
+        la a1, path
+        li a2, 0
+        li a7, 56
+        ecall
+        mv s2, a0
+        li t0, 3
+        bne s2, t0, fail
+        # read(fd, buf, 4) gets the ELF magic
+        li gp, 8
+        mv a0, s2
+        la a1, buf
+        li a2, 4
+        li a7, 63
+        ecall
+        li t0, 4
+        bne a0, t0, fail
+        la a1, buf
+        lw t0, 0(a1)
+        li t1, 0x464c457f
+        bne t0, t1, fail
+        # newlib-style lseek(fd, 1, SEEK_SET)
+        li gp, 9
+        mv a0, s2
+        li a1, 1
+        li a2, 0
+        li a3, 0
+        li a7, 62
+        ecall
+        li t0, 1
+        bne a0, t0, fail
+        # _llseek(fd, 0, 2, &result, SEEK_CUR)
+        li gp, 10
+        mv a0, s2
+        li a1, 0
+        li a2, 2
+        la a3, buf
+        li a4, 1
+        li a7, 62
+        ecall
+        bnez a0, fail
+        la a3, buf
+        lw t0, 0(a3)
+        li t1, 3
+        bne t0, t1, fail
+        lw t0, 4(a3)
+        bnez t0, fail
+        # fstat(fd, buf) says it's a regular file
+        li gp, 11
+        mv a0, s2
+        la a1, buf
+        li a7, 80
+        ecall
+        bnez a0, fail
+        la a1, buf
+        lw t0, 16(a1)
+        li t1, 0xf000
+        and t0, t0, t1
+        li t1, 0x8000
+        bne t0, t1, fail
+        lw t0, 48(a1)
+        beqz t0, fail
+        # close, twice
+        li gp, 12
+        mv a0, s2
+        li a7, 57
+        ecall
+        bnez a0, fail
+        mv a0, s2
+        li a7, 57
+        ecall
+        li t0, -9
+        bne a0, t0, fail
+        # a missing file
+        li gp, 13
+        li a0, -100
+        la a1, missing
+        li a2, 0
+        li a7, 56
+        ecall
+        li t0, -2
+        bne a0, t0, fail
+        # stdout isn't seekable
+        li gp, 14
+        li a0, 1
+        li a1, 0
+        li a2, 0
+        li a3, 0
+        li a7, 62
+        ecall
+        li t0, -29
+        bne a0, t0, fail
+
+        # uname
+        li gp, 15
+        la a0, buf
+        li a7, 160
+        ecall
+        bnez a0, fail
+        la a0, buf
+        lbu t0, 0(a0)
+        li t1, 'L'
+        bne t0, t1, fail
+        lbu t0, 260(a0)
+        li t1, 'r'
+        bne t0, t1, fail
+        # clock_gettime64(CLOCK_REALTIME) is well after 2020
+        li gp, 16
+        li a0, 0
+        la a1, buf
+        li a7, 403
+        ecall
+        bnez a0, fail
+        la a1, buf
+        lw t0, 0(a1)
+        li t1, 1577836800
+        bltu t0, t1, fail
+        # gettimeofday
+        li gp, 17
+        la a0, buf
+        li a1, 0
+        li a7, 169
+        ecall
+        bnez a0, fail
+        la a0, buf
+        lw t0, 0(a0)
+        bltu t0, t1, fail
+        # getpid
+        li gp, 18
+        li a7, 172
+        ecall
+        beqz a0, fail
+
+        # exit_group(0)
+        li a0, 0
+        li a7, 94
+        ecall
+fail:
+        mv a0, gp
+        li a7, 94
+        ecall
+
+        .data
+        .align 2
+iov:    .word iov1, 4, iov2, 5
+msg:    .ascii "Hello, world!\n"
+msg_end:
+iov1:   .ascii "from"
+iov2:   .ascii " iov\n"
+path:   .asciz "./tests/syscalls"
+missing: .asciz "./tests/missing"
+        .align 2
+buf:    .space 512